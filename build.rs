@@ -5,6 +5,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .build_server(true)
         .build_client(true)
         .type_attribute(".", SERIAL_DESERIAL_ATTR)
+        // `prost_types::FieldMask` doesn't implement serde, masks are never stored anyway
+        .field_attribute("update_mask", "#[serde(skip)]")
         .include_file("proto.rs")
         .compile(
             &[
@@ -13,6 +15,19 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 "proto/collection/service-rating.proto",
                 "proto/collection/service-request.proto",
                 "proto/collection/service-request-bid.proto",
+                "proto/collection/service-request-status.proto",
+                "proto/admin.proto",
+                "proto/ledger.proto",
+                "proto/location.proto",
+                "proto/messaging.proto",
+                "proto/moderation.proto",
+                "proto/query.proto",
+                "proto/ranking.proto",
+                "proto/reputation.proto",
+                "proto/role.proto",
+                "proto/taxonomy.proto",
+                "proto/verification.proto",
+                "proto/watch.proto",
             ],
             &["proto"],
         )?;
@@ -0,0 +1,21 @@
+///
+/// Storage for binary content such as identity documents, kept out of the database.
+///
+/// `local::LocalBlobStore` writes to a directory on the server, other backends only have
+/// to implement `BlobStore`.
+///
+pub mod local;
+
+use crate::error::Result;
+
+#[tonic::async_trait]
+pub trait BlobStore: Send + Sync {
+    /// Stores the content under the key, replacing whatever was stored there.
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<()>;
+
+    /// Fails with `Error::NotFound` if nothing is stored under the key.
+    async fn get(&self, key: &str) -> Result<Vec<u8>>;
+
+    /// Deleting a missing key is not an error.
+    async fn delete(&self, key: &str) -> Result<()>;
+}
@@ -0,0 +1,73 @@
+// Blob store backed by a directory on the local filesystem, one file per key
+
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use super::BlobStore;
+use crate::error::{Error, Result};
+
+pub struct LocalBlobStore {
+    root: PathBuf,
+}
+
+impl LocalBlobStore {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self { root: root.into() }
+    }
+
+    // keys are `/` separated, anything that could escape the root is rejected
+    fn path(&self, key: &str) -> Result<PathBuf> {
+        let relative = Path::new(key);
+
+        let is_safe = !key.is_empty()
+            && relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)));
+
+        if !is_safe {
+            return Err(Error::Internal(format!("INVALID BLOB KEY {key:?}")));
+        }
+
+        Ok(self.root.join(relative))
+    }
+}
+
+fn io_error(error: std::io::Error) -> Error {
+    Error::Internal(error.to_string())
+}
+
+#[tonic::async_trait]
+impl BlobStore for LocalBlobStore {
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<()> {
+        let path = self.path(key)?;
+
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await.map_err(io_error)?;
+        }
+
+        // written next to the final path first so readers never see a partial file
+        let partial = path.with_extension("partial");
+
+        tokio::fs::write(&partial, content)
+            .await
+            .map_err(io_error)?;
+
+        tokio::fs::rename(&partial, &path).await.map_err(io_error)
+    }
+
+    async fn get(&self, key: &str) -> Result<Vec<u8>> {
+        match tokio::fs::read(self.path(key)?).await {
+            Ok(content) => Ok(content),
+            Err(e) if e.kind() == ErrorKind::NotFound => Err(Error::NotFound),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.path(key)?).await {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(io_error(e)),
+        }
+    }
+}
@@ -0,0 +1,695 @@
+///
+/// Server configuration, loaded once at startup from a TOML file and then
+/// overridden by the environment (including a `.env` file).
+///
+/// The file is read from `TIMEBANK_CONFIG`, or `config.toml` if that file exists.
+/// See `config.example.toml` for every setting.
+///
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+const CONFIG_PATH_VAR: &str = "TIMEBANK_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub supabase: SupabaseConfig,
+    pub timeouts: TimeoutConfig,
+    pub retry: RetryConfig,
+    pub circuit_breaker: CircuitBreakerConfig,
+    pub blob_store: BlobStoreConfig,
+    pub ranking: RankingConfig,
+    pub requests: RequestConfig,
+    pub ratings: RatingConfig,
+    pub features: FeatureConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen_address: SocketAddr,
+    /// Serves plaintext gRPC when not set.
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: SocketAddr::from(([0, 0, 0, 0], 50051)),
+            tls: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM encoded certificate chain.
+    pub cert_path: PathBuf,
+    /// PEM encoded private key.
+    pub key_path: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SupabaseConfig {
+    /// GoTrue endpoint, eg `https://<project>.supabase.co/auth/v1`. Derived from
+    /// `database_endpoint` when not set.
+    pub auth_endpoint: String,
+    /// PostgREST endpoint, eg `https://<project>.supabase.co/rest/v1`.
+    pub database_endpoint: String,
+    /// The service role key, the server authorizes calls itself and some of the database
+    /// functions are only granted to that role.
+    pub api_key: String,
+    /// Secret the access tokens are signed with.
+    pub jwt_secret: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    /// Time allowed to connect to Supabase.
+    pub connect_ms: u64,
+    /// Time allowed for a whole call to Supabase.
+    pub request_ms: u64,
+}
+
+impl TimeoutConfig {
+    pub fn connect(&self) -> Duration {
+        Duration::from_millis(self.connect_ms)
+    }
+
+    pub fn request(&self) -> Duration {
+        Duration::from_millis(self.request_ms)
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect_ms: 5_000,
+            request_ms: 10_000,
+        }
+    }
+}
+
+/// Retries of idempotent reads that failed with a 5xx or connection error.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Retries after the first attempt, 0 disables retrying.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every retry after it.
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl RetryConfig {
+    /// Delay before the retry following the `attempt`th failed attempt, counted from 0.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(1 << attempt.min(16))
+            .min(self.max_delay_ms);
+
+        Duration::from_millis(delay)
+    }
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay_ms: 100,
+            max_delay_ms: 2_000,
+        }
+    }
+}
+
+/// Stops calling Supabase for a while once it keeps failing.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Consecutive failed calls that open the circuit.
+    pub failure_threshold: u32,
+    /// Time calls fail fast for before one is let through to probe Supabase.
+    pub open_ms: u64,
+}
+
+impl CircuitBreakerConfig {
+    pub fn open(&self) -> Duration {
+        Duration::from_millis(self.open_ms)
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobBackend {
+    /// Files in a directory on the server.
+    Local,
+}
+
+/// Where uploaded documents are stored.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BlobStoreConfig {
+    pub backend: BlobBackend,
+    /// Directory of the `local` backend, created if missing.
+    pub path: PathBuf,
+}
+
+impl Default for BlobStoreConfig {
+    fn default() -> Self {
+        Self {
+            backend: BlobBackend::Local,
+            path: PathBuf::from("data/blobs"),
+        }
+    }
+}
+
+/// How much each signal weighs in the score of bids and recommended providers, relative to the
+/// others. 0 leaves the signal out.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RankingWeights {
+    pub amount: f64,
+    pub rating: f64,
+    pub completion_rate: f64,
+    pub response_time: f64,
+    pub distance: f64,
+    pub relevance: f64,
+    pub skill: f64,
+}
+
+impl Default for RankingWeights {
+    fn default() -> Self {
+        Self {
+            amount: 1.0,
+            rating: 1.0,
+            completion_rate: 1.0,
+            response_time: 0.5,
+            distance: 0.5,
+            relevance: 1.0,
+            skill: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RankingConfig {
+    pub weights: RankingWeights,
+    /// Distance at which the distance signal is halved.
+    pub distance_halving_km: f64,
+    /// Usual response time at which the response time signal is halved.
+    pub response_halving_hours: f64,
+    /// Providers further than this from the request are only recommended if their past
+    /// requests or their skills are relevant.
+    pub max_distance_km: f64,
+    /// Most providers `RecommendProviders` returns.
+    pub recommendations: u32,
+}
+
+impl Default for RankingConfig {
+    fn default() -> Self {
+        Self {
+            weights: RankingWeights::default(),
+            distance_halving_km: 5.0,
+            response_halving_hours: 24.0,
+            max_distance_km: 20.0,
+            recommendations: 10,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RequestConfig {
+    /// Time after its creation a request expires if no bid has been selected.
+    pub expire_after_hours: u64,
+}
+
+impl RequestConfig {
+    pub fn expire_after(&self) -> Duration {
+        Duration::from_secs(self.expire_after_hours * 3600)
+    }
+}
+
+impl Default for RequestConfig {
+    fn default() -> Self {
+        Self {
+            expire_after_hours: 30 * 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RatingConfig {
+    /// Time a rating stays hidden if the other side of the exchange doesn't rate back.
+    pub reveal_window_hours: u64,
+    /// Time after its creation during which the author can edit or delete a rating.
+    pub edit_window_hours: u64,
+}
+
+impl RatingConfig {
+    pub fn reveal_window(&self) -> Duration {
+        Duration::from_secs(self.reveal_window_hours * 3600)
+    }
+
+    pub fn edit_window(&self) -> Duration {
+        Duration::from_secs(self.edit_window_hours * 3600)
+    }
+}
+
+impl Default for RatingConfig {
+    fn default() -> Self {
+        Self {
+            reveal_window_hours: 14 * 24,
+            edit_window_hours: 48,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Database,
+    /// Keeps everything in process, eg for local demos without a Supabase project.
+    Memory,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeatureConfig {
+    pub storage: StorageBackend,
+    /// Serves the `Watch` streams.
+    pub watch: bool,
+    /// Serves the `Messaging` service.
+    pub messaging: bool,
+}
+
+impl Default for FeatureConfig {
+    fn default() -> Self {
+        Self {
+            storage: StorageBackend::Database,
+            watch: true,
+            messaging: true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Read(PathBuf, std::io::Error),
+    Parse(PathBuf, toml::de::Error),
+    /// The setting, and why its value is invalid.
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read(path, e) => {
+                write!(f, "UNABLE TO READ CONFIG FILE {}: {e}", path.display())
+            }
+            ConfigError::Parse(path, e) => {
+                write!(f, "UNABLE TO PARSE CONFIG FILE {}: {e}", path.display())
+            }
+            ConfigError::Invalid(setting, reason) => {
+                write!(f, "INVALID CONFIG `{setting}`: {reason}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var(CONFIG_PATH_VAR) {
+            Ok(path) => Self::from_file(Path::new(&path))?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            Err(_) => Self::default(),
+        };
+
+        config.apply_env(|name| dotenv::var(name).ok())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ConfigError> {
+        let content =
+            std::fs::read_to_string(path).map_err(|e| ConfigError::Read(path.to_owned(), e))?;
+
+        toml::from_str(&content).map_err(|e| ConfigError::Parse(path.to_owned(), e))
+    }
+
+    // the variable names predate the config file and are kept for existing deployments
+    fn apply_env(&mut self, lookup: impl Fn(&str) -> Option<String>) -> Result<(), ConfigError> {
+        let var = |name: &str| lookup(name).filter(|v| !v.is_empty());
+
+        if let Some(address) = var("SOCKET_ADDRESS") {
+            self.server.listen_address = address.parse().map_err(|_| {
+                ConfigError::Invalid(
+                    "server.listen_address",
+                    format!("{address:?} IS NOT A SOCKET ADDRESS"),
+                )
+            })?;
+        }
+
+        if let (Some(cert_path), Some(key_path)) = (var("TLS_CERT_PATH"), var("TLS_KEY_PATH")) {
+            self.server.tls = Some(TlsConfig {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+            });
+        }
+
+        let supabase = &mut self.supabase;
+
+        for (name, setting) in [
+            ("SUPABASE_AUTH_ENDPOINT", &mut supabase.auth_endpoint),
+            ("SUPABASE_ENDPOINT", &mut supabase.database_endpoint),
+            ("SUPABASE_API_KEY", &mut supabase.api_key),
+            ("SUPABASE_JWT_SECRET", &mut supabase.jwt_secret),
+        ] {
+            if let Some(value) = var(name) {
+                *setting = value;
+            }
+        }
+
+        // deployments from before the auth endpoint was configurable only set the database one,
+        // GoTrue is served next to PostgREST on the same host
+        if supabase.auth_endpoint.is_empty() && !supabase.database_endpoint.is_empty() {
+            let base = supabase.database_endpoint.trim_end_matches('/');
+            let base = base.strip_suffix("/rest/v1").unwrap_or(base);
+
+            supabase.auth_endpoint = format!("{base}/auth/v1");
+        }
+
+        for (name, setting, field) in [
+            (
+                "CONNECT_TIMEOUT_MS",
+                &mut self.timeouts.connect_ms,
+                "timeouts.connect_ms",
+            ),
+            (
+                "REQUEST_TIMEOUT_MS",
+                &mut self.timeouts.request_ms,
+                "timeouts.request_ms",
+            ),
+        ] {
+            if let Some(value) = var(name) {
+                *setting = value.parse().map_err(|_| {
+                    ConfigError::Invalid(field, format!("{value:?} IS NOT A NUMBER"))
+                })?;
+            }
+        }
+
+        if let Some(path) = var("BLOB_STORE_PATH") {
+            self.blob_store.path = path.into();
+        }
+
+        if let Some(storage) = var("STORAGE_BACKEND") {
+            self.features.storage = match storage.as_str() {
+                "database" => StorageBackend::Database,
+                "memory" => StorageBackend::Memory,
+                _ => {
+                    return Err(ConfigError::Invalid(
+                        "features.storage",
+                        format!("{storage:?} IS NEITHER \"database\" NOR \"memory\""),
+                    ))
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let supabase = &self.supabase;
+
+        validate_url("supabase.auth_endpoint", &supabase.auth_endpoint)?;
+
+        if self.features.storage == StorageBackend::Database {
+            validate_url("supabase.database_endpoint", &supabase.database_endpoint)?;
+        }
+
+        if supabase.api_key.is_empty() {
+            return Err(ConfigError::Invalid("supabase.api_key", "MISSING".into()));
+        }
+
+        if supabase.jwt_secret.is_empty() {
+            return Err(ConfigError::Invalid(
+                "supabase.jwt_secret",
+                "MISSING".into(),
+            ));
+        }
+
+        if let Some(tls) = &self.server.tls {
+            for (setting, path) in [
+                ("server.tls.cert_path", &tls.cert_path),
+                ("server.tls.key_path", &tls.key_path),
+            ] {
+                if !path.is_file() {
+                    return Err(ConfigError::Invalid(
+                        setting,
+                        format!("{} DOES NOT EXIST", path.display()),
+                    ));
+                }
+            }
+        }
+
+        for (setting, value) in [
+            ("timeouts.connect_ms", self.timeouts.connect_ms),
+            ("timeouts.request_ms", self.timeouts.request_ms),
+            (
+                "circuit_breaker.failure_threshold",
+                self.circuit_breaker.failure_threshold as u64,
+            ),
+            ("circuit_breaker.open_ms", self.circuit_breaker.open_ms),
+            (
+                "requests.expire_after_hours",
+                self.requests.expire_after_hours,
+            ),
+            (
+                "ratings.reveal_window_hours",
+                self.ratings.reveal_window_hours,
+            ),
+            ("ratings.edit_window_hours", self.ratings.edit_window_hours),
+        ] {
+            if value == 0 {
+                return Err(ConfigError::Invalid(
+                    setting,
+                    "MUST BE GREATER THAN 0".into(),
+                ));
+            }
+        }
+
+        let weights = &self.ranking.weights;
+
+        for (setting, value) in [
+            ("ranking.weights.amount", weights.amount),
+            ("ranking.weights.rating", weights.rating),
+            ("ranking.weights.completion_rate", weights.completion_rate),
+            ("ranking.weights.response_time", weights.response_time),
+            ("ranking.weights.distance", weights.distance),
+            ("ranking.weights.relevance", weights.relevance),
+            ("ranking.weights.skill", weights.skill),
+        ] {
+            if !(value.is_finite() && value >= 0.0) {
+                return Err(ConfigError::Invalid(
+                    setting,
+                    "MUST BE A NUMBER NOT LESS THAN 0".into(),
+                ));
+            }
+        }
+
+        for (setting, value) in [
+            (
+                "ranking.distance_halving_km",
+                self.ranking.distance_halving_km,
+            ),
+            (
+                "ranking.response_halving_hours",
+                self.ranking.response_halving_hours,
+            ),
+            ("ranking.max_distance_km", self.ranking.max_distance_km),
+            (
+                "ranking.recommendations",
+                self.ranking.recommendations as f64,
+            ),
+        ] {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(ConfigError::Invalid(
+                    setting,
+                    "MUST BE GREATER THAN 0".into(),
+                ));
+            }
+        }
+
+        if self.retry.base_delay_ms > self.retry.max_delay_ms {
+            return Err(ConfigError::Invalid(
+                "retry.base_delay_ms",
+                "MUST NOT BE GREATER THAN retry.max_delay_ms".into(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+fn validate_url(setting: &'static str, url: &str) -> Result<(), ConfigError> {
+    if url.is_empty() {
+        return Err(ConfigError::Invalid(setting, "MISSING".into()));
+    }
+
+    match reqwest::Url::parse(url) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") => Ok(()),
+        _ => Err(ConfigError::Invalid(
+            setting,
+            format!("{url:?} IS NOT AN HTTP URL"),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const FILE: &str = r#"
+        [supabase]
+        auth_endpoint = "https://file.supabase.co/auth/v1"
+        database_endpoint = "https://file.supabase.co/rest/v1"
+        api_key = "file-key"
+        jwt_secret = "file-secret"
+
+        [timeouts]
+        request_ms = 2000
+    "#;
+
+    fn load(file: &str, env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let env: HashMap<String, String> = env
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+
+        let mut config: Config = toml::from_str(file).expect("VALID TOML");
+        config.apply_env(|name| env.get(name).cloned())?;
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    fn invalid_setting(result: Result<Config, ConfigError>) -> &'static str {
+        match result {
+            Err(ConfigError::Invalid(setting, _)) => setting,
+            Err(e) => panic!("UNEXPECTED ERROR {e}"),
+            Ok(_) => panic!("CONFIG ACCEPTED"),
+        }
+    }
+
+    #[test]
+    fn the_file_is_used_where_the_environment_is_silent() {
+        let config = load(FILE, &[]).unwrap();
+
+        assert_eq!(config.supabase.api_key, "file-key");
+        assert_eq!(config.timeouts.request_ms, 2000);
+        // settings missing from both keep their default
+        assert_eq!(config.timeouts.connect_ms, 5000);
+    }
+
+    #[test]
+    fn the_environment_overrides_the_file() {
+        let config = load(
+            FILE,
+            &[
+                ("SUPABASE_API_KEY", "env-key"),
+                ("REQUEST_TIMEOUT_MS", "3000"),
+                // empty variables count as unset
+                ("SUPABASE_JWT_SECRET", ""),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.supabase.api_key, "env-key");
+        assert_eq!(config.timeouts.request_ms, 3000);
+        assert_eq!(config.supabase.jwt_secret, "file-secret");
+    }
+
+    #[test]
+    fn the_auth_endpoint_defaults_to_the_one_next_to_the_database() {
+        let env = [
+            ("SUPABASE_API_KEY", "key"),
+            ("SUPABASE_JWT_SECRET", "secret"),
+        ];
+
+        for endpoint in [
+            "https://env.supabase.co",
+            "https://env.supabase.co/",
+            "https://env.supabase.co/rest/v1",
+        ] {
+            let env = [env.as_slice(), &[("SUPABASE_ENDPOINT", endpoint)]].concat();
+            let config = load("", &env).unwrap();
+
+            assert_eq!(
+                config.supabase.auth_endpoint, "https://env.supabase.co/auth/v1",
+                "{endpoint}"
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_settings_are_named() {
+        let result = load(FILE, &[("REQUEST_TIMEOUT_MS", "soon")]);
+        assert_eq!(invalid_setting(result), "timeouts.request_ms");
+
+        let result = load(FILE, &[("STORAGE_BACKEND", "disk")]);
+        assert_eq!(invalid_setting(result), "features.storage");
+
+        let result = load(FILE, &[("SUPABASE_ENDPOINT", "file.supabase.co")]);
+        assert_eq!(invalid_setting(result), "supabase.database_endpoint");
+
+        let result = load(&FILE.replace("file-key", ""), &[]);
+        assert_eq!(invalid_setting(result), "supabase.api_key");
+
+        let result = load(&format!("{FILE}\n[retry]\nbase_delay_ms = 5000"), &[]);
+        assert_eq!(invalid_setting(result), "retry.base_delay_ms");
+    }
+
+    #[test]
+    fn nothing_is_required_of_the_database_when_storing_in_memory() {
+        let config = load(
+            "",
+            &[
+                ("SUPABASE_AUTH_ENDPOINT", "http://127.0.0.1:9999"),
+                ("SUPABASE_API_KEY", "key"),
+                ("SUPABASE_JWT_SECRET", "secret"),
+                ("STORAGE_BACKEND", "memory"),
+            ],
+        )
+        .unwrap();
+
+        assert!(config.supabase.database_endpoint.is_empty());
+    }
+}
@@ -0,0 +1,296 @@
+///
+/// Error type shared by the services and repositories, and its mapping to gRPC
+/// status codes.
+///
+use std::fmt;
+
+use reqwest::StatusCode;
+use tonic::{Code, Status};
+
+use crate::proto::timebank::servicerequeststatus::RequestStatus;
+use crate::services::util::DatabaseErrorResponse;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidPayload,
+    MissingArgument,
+    MissingAccessToken,
+    InvalidAccessToken,
+    TooManyRequests,
+    NotFound,
+    AlreadyExists,
+    /// Why the caller isn't allowed to do what they asked.
+    PermissionDenied(String),
+    /// The account is suspended until the given time.
+    Suspended(String),
+    Banned,
+    OwnRequest,
+    BidAlreadySelected,
+    NoBidSelected,
+    BidWithdrawn,
+    /// The counter-offer was accepted or superseded already.
+    CounterOfferNotPending,
+    ServiceNotCompleted,
+    /// The rating value is outside `RATING_SCALE`.
+    RatingOutOfScale(i32),
+    /// The rating can no longer be edited nor deleted.
+    RatingLocked,
+    RatingAlreadyHidden,
+    RatingNotHidden,
+    /// No category has this id.
+    UnknownCategory(String),
+    /// A category can't be moved under itself or one of its subcategories.
+    CategoryCycle,
+    /// The category has subcategories, or requests or skills refer to it.
+    CategoryInUse,
+    /// The moderation case was resolved already.
+    CaseNotPending,
+    UnbalancedTransaction,
+    InsufficientBalance,
+    /// The user's identity hasn't been verified yet.
+    NotVerified,
+    AlreadyVerified,
+    /// The verification has no submission waiting for review.
+    VerificationNotPending,
+    /// The uploaded document is rejected, and why.
+    InvalidDocument(String),
+    /// The field is not part of the entity's mutable fields.
+    ImmutableField(String),
+    /// The column can't be filtered or ordered on.
+    InvalidColumn(String),
+    InvalidTransition(RequestStatus, RequestStatus),
+    /// The operation is not allowed while the request has this status.
+    InvalidStatus(RequestStatus),
+    /// Error reported by the database or auth server, mapped to the closest status code.
+    Upstream {
+        code: Code,
+        message: String,
+    },
+    /// The database or auth server could not be reached.
+    Unavailable(String),
+    /// The database or auth server did not answer before the call's deadline.
+    DeadlineExceeded,
+    Internal(String),
+    Unknown,
+}
+
+impl Error {
+    pub fn code(&self) -> Code {
+        match self {
+            Error::InvalidPayload
+            | Error::MissingArgument
+            | Error::UnbalancedTransaction
+            | Error::ImmutableField(_)
+            | Error::InvalidColumn(_)
+            | Error::InvalidDocument(_)
+            | Error::RatingOutOfScale(_)
+            | Error::UnknownCategory(_)
+            | Error::CategoryCycle => Code::InvalidArgument,
+            Error::MissingAccessToken | Error::InvalidAccessToken => Code::Unauthenticated,
+            Error::TooManyRequests => Code::ResourceExhausted,
+            Error::NotFound => Code::NotFound,
+            Error::AlreadyExists => Code::AlreadyExists,
+            Error::PermissionDenied(_) | Error::Suspended(_) | Error::Banned => {
+                Code::PermissionDenied
+            }
+            Error::OwnRequest
+            | Error::BidAlreadySelected
+            | Error::NoBidSelected
+            | Error::BidWithdrawn
+            | Error::CounterOfferNotPending
+            | Error::ServiceNotCompleted
+            | Error::RatingLocked
+            | Error::RatingAlreadyHidden
+            | Error::RatingNotHidden
+            | Error::CaseNotPending
+            | Error::CategoryInUse
+            | Error::InsufficientBalance
+            | Error::NotVerified
+            | Error::AlreadyVerified
+            | Error::VerificationNotPending
+            | Error::InvalidTransition(..)
+            | Error::InvalidStatus(_) => Code::FailedPrecondition,
+            Error::Upstream { code, .. } => *code,
+            Error::Unavailable(_) => Code::Unavailable,
+            Error::DeadlineExceeded => Code::DeadlineExceeded,
+            Error::Internal(_) => Code::Internal,
+            Error::Unknown => Code::Unknown,
+        }
+    }
+
+    /// Maps an error returned by the GoTrue auth server.
+    pub fn from_auth_response(status: StatusCode, body: &serde_json::Value) -> Self {
+        // GoTrue reports errors as either `error_description` or `msg` depending on the endpoint
+        let message = body["error_description"]
+            .as_str()
+            .or_else(|| body["msg"].as_str())
+            .unwrap_or_default()
+            .to_uppercase();
+
+        let code = match status {
+            StatusCode::TOO_MANY_REQUESTS => return Error::TooManyRequests,
+            StatusCode::BAD_REQUEST => Code::InvalidArgument,
+            StatusCode::UNPROCESSABLE_ENTITY if message.contains("ALREADY REGISTERED") => {
+                Code::AlreadyExists
+            }
+            StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+            status => code_from_http_status(status),
+        };
+
+        Error::Upstream { code, message }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            Error::InvalidPayload => "INVALID PAYLOAD",
+            Error::MissingArgument => "EXPECTED ARGUMENT MISSING",
+            Error::MissingAccessToken => "MISSING ACCESS TOKEN",
+            Error::InvalidAccessToken => "INVALID ACCESS TOKEN",
+            Error::TooManyRequests => "TOO MANY REQUESTS",
+            Error::NotFound => "ITEM NOT FOUND",
+            Error::AlreadyExists => "ITEM ALREADY EXISTS",
+            Error::PermissionDenied(reason) => return write!(f, "PERMISSION DENIED: {reason}"),
+            Error::Suspended(until) => return write!(f, "ACCOUNT SUSPENDED UNTIL {until}"),
+            Error::Banned => "ACCOUNT BANNED",
+            Error::OwnRequest => "CANNOT BID ON OWN REQUEST",
+            Error::BidAlreadySelected => "A BID HAS ALREADY BEEN SELECTED",
+            Error::NoBidSelected => "NO BID HAS BEEN SELECTED",
+            Error::BidWithdrawn => "BID HAS BEEN WITHDRAWN",
+            Error::CounterOfferNotPending => "COUNTER-OFFER IS NO LONGER PENDING",
+            Error::ServiceNotCompleted => "SERVICE HAS NOT BEEN COMPLETED",
+            Error::RatingOutOfScale(value) => {
+                return write!(f, "RATING {value} IS OUTSIDE THE SCALE")
+            }
+            Error::RatingLocked => "RATING CAN NO LONGER BE CHANGED",
+            Error::RatingAlreadyHidden => "RATING IS ALREADY HIDDEN",
+            Error::RatingNotHidden => "RATING IS NOT HIDDEN",
+            Error::CaseNotPending => "MODERATION CASE IS NOT PENDING",
+            Error::UnknownCategory(id) => return write!(f, "CATEGORY {id} DOES NOT EXIST"),
+            Error::CategoryCycle => "CATEGORY CANNOT BE MOVED UNDER ITSELF",
+            Error::CategoryInUse => "CATEGORY IS IN USE",
+            Error::UnbalancedTransaction => "TRANSACTION ENTRIES DO NOT BALANCE",
+            Error::InsufficientBalance => "INSUFFICIENT BALANCE",
+            Error::NotVerified => "ACCOUNT HAS NOT BEEN VERIFIED",
+            Error::AlreadyVerified => "ACCOUNT HAS ALREADY BEEN VERIFIED",
+            Error::VerificationNotPending => "VERIFICATION IS NOT PENDING REVIEW",
+            Error::InvalidDocument(reason) => return write!(f, "INVALID DOCUMENT: {reason}"),
+            Error::ImmutableField(field) => return write!(f, "FIELD {field} CANNOT BE UPDATED"),
+            Error::InvalidColumn(column) => return write!(f, "COLUMN {column} CANNOT BE QUERIED"),
+            Error::InvalidTransition(from, to) => {
+                return write!(f, "CANNOT MOVE REQUEST FROM {from} TO {to}")
+            }
+            Error::InvalidStatus(status) => {
+                return write!(f, "NOT ALLOWED WHILE REQUEST IS {status}")
+            }
+            Error::Upstream { message, .. } => message,
+            Error::Unavailable(message) => return write!(f, "SERVICE UNAVAILABLE: {message}"),
+            Error::DeadlineExceeded => "DEADLINE EXCEEDED",
+            Error::Internal(message) => return write!(f, "INTERNAL ERROR: {message}"),
+            Error::Unknown => "AN ERROR HAS OCCURED",
+        };
+
+        f.write_str(message)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<Error> for Status {
+    fn from(error: Error) -> Self {
+        Status::new(error.code(), error.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(error: reqwest::Error) -> Self {
+        if error.is_decode() {
+            Error::Internal(error.to_string())
+        } else if error.is_timeout() {
+            Error::DeadlineExceeded
+        } else {
+            Error::Unavailable(error.to_string())
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Internal(error.to_string())
+    }
+}
+
+// see https://www.postgresql.org/docs/current/errcodes-appendix.html
+// and https://postgrest.org/en/stable/errors.html
+impl From<DatabaseErrorResponse> for Error {
+    fn from(res: DatabaseErrorResponse) -> Self {
+        let DatabaseErrorResponse {
+            response_status,
+            error,
+        } = res;
+
+        // raised by `ledger_record_transaction` when a debit would overdraw an account
+        if error.code == "P0001" && error.message == Error::InsufficientBalance.to_string() {
+            return Error::InsufficientBalance;
+        }
+
+        let code = match error.code.as_str() {
+            // unique_violation
+            "23505" => Code::AlreadyExists,
+            // foreign_key_violation, either the referenced row is missing or is still referenced
+            "23503"
+                if error
+                    .details
+                    .as_deref()
+                    .unwrap_or_default()
+                    .contains("still referenced") =>
+            {
+                Code::FailedPrecondition
+            }
+            "23503" => Code::NotFound,
+            // check_violation, not_null_violation, invalid_text_representation (eg malformed uuid)
+            "23514" | "23502" | "22P02" => Code::InvalidArgument,
+            // insufficient_privilege, raised when a row level security policy rejects the query
+            "42501" => Code::PermissionDenied,
+            // undefined_column, unknown column used in a filter
+            "42703" => Code::InvalidArgument,
+            // raise_exception, used by the database functions to reject the operation
+            "P0001" => Code::FailedPrecondition,
+            // no_data_found, raised by the database functions when the row doesn't exist
+            "P0002" => Code::NotFound,
+            // no rows returned when exactly one was expected
+            "PGRST116" => Code::NotFound,
+            // JWT errors
+            "PGRST301" | "PGRST302" => Code::Unauthenticated,
+            _ => StatusCode::from_u16(response_status)
+                .map(code_from_http_status)
+                .unwrap_or(Code::Unknown),
+        };
+
+        let message = if error.message.is_empty() {
+            Error::Unknown.to_string()
+        } else {
+            error.message
+        };
+
+        Error::Upstream { code, message }
+    }
+}
+
+fn code_from_http_status(status: StatusCode) -> Code {
+    match status {
+        StatusCode::BAD_REQUEST | StatusCode::UNPROCESSABLE_ENTITY => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::CONFLICT => Code::AlreadyExists,
+        StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+            Code::Unavailable
+        }
+        _ => Code::Unknown,
+    }
+}
@@ -0,0 +1,64 @@
+///
+/// In-process event bus the handlers publish to, feeding the `Watch` and `Messaging.Chat`
+/// streams.
+///
+/// Subscribers that fall too far behind miss the oldest events rather than slowing the
+/// publishers down.
+///
+use tokio::sync::broadcast;
+
+use crate::proto::timebank::messaging::{TMessage, TReadReceipt};
+use crate::proto::timebank::servicerequest::TServiceRequest;
+use crate::proto::timebank::servicerequestbid::TServiceRequestBid;
+use crate::proto::timebank::watch::{bid_event, request_event, BidEvent, RequestEvent};
+
+const CAPACITY: usize = 1024;
+
+#[derive(Debug, Clone)]
+pub enum Event {
+    Request(RequestEvent),
+    Bid(BidEvent),
+    Message(TMessage),
+    Receipt(TReadReceipt),
+}
+
+#[derive(Clone)]
+pub struct EventBus {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+
+    pub fn publish(&self, event: Event) {
+        // fails only when nobody is subscribed, which is fine
+        let _ = self.sender.send(event);
+    }
+
+    pub fn publish_request(&self, kind: request_event::Kind, request: &TServiceRequest) {
+        self.publish(Event::Request(RequestEvent {
+            kind: kind as i32,
+            request: Some(request.clone()),
+        }));
+    }
+
+    pub fn publish_bid(&self, kind: bid_event::Kind, bid: &TServiceRequestBid) {
+        self.publish(Event::Bid(BidEvent {
+            kind: kind as i32,
+            bid: Some(bid.clone()),
+        }));
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,437 @@
+// In-memory stand-in for the GoTrue endpoints the Auth service uses, for local testing
+// without a Supabase project.
+//
+// Point `supabase.auth_endpoint` at it, eg `http://127.0.0.1:9999`, and give both the same
+// `SUPABASE_JWT_SECRET`. Emails aren't sent, the verification and recovery codes are printed
+// instead. Everything is lost on restart.
+//
+// GOTRUE_STUB_ADDRESS      listen address, 127.0.0.1:9999 by default
+// GOTRUE_STUB_AUTOCONFIRM  confirm emails on sign up, off by default
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use chrono::Utc;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:9999";
+const AUDIENCE: &str = "authenticated";
+// seconds, same as GoTrue's default
+const TOKEN_LIFETIME: i64 = 3600;
+
+#[derive(Serialize, Deserialize)]
+struct Claims {
+    sub: String,
+    aud: String,
+    exp: i64,
+    email: String,
+    role: String,
+    session_id: String,
+}
+
+struct User {
+    id: String,
+    email: String,
+    password: String,
+    created_at: String,
+    confirmation_sent_at: Option<String>,
+    email_confirmed_at: Option<String>,
+}
+
+impl User {
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "aud": AUDIENCE,
+            "email": self.email,
+            "created_at": self.created_at,
+            "confirmation_sent_at": self.confirmation_sent_at,
+            "email_confirmed_at": self.email_confirmed_at,
+        })
+    }
+}
+
+// a code that would have been emailed
+struct Otp {
+    kind: String,
+    email: String,
+    code: String,
+}
+
+struct RefreshToken {
+    user_id: String,
+    session_id: String,
+}
+
+#[derive(Default)]
+struct State {
+    users: Vec<User>,
+    otps: Vec<Otp>,
+    refresh_tokens: HashMap<String, RefreshToken>,
+}
+
+struct Stub {
+    state: Mutex<State>,
+    encoding_key: EncodingKey,
+    decoding_key: DecodingKey,
+    autoconfirm: bool,
+}
+
+fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn reply(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .expect("VALID RESPONSE")
+}
+
+fn error(status: StatusCode, message: &str) -> Response<Body> {
+    reply(
+        status,
+        json!({ "error": "stub_error", "error_description": message, "msg": message }),
+    )
+}
+
+impl Stub {
+    fn new(secret: &str, autoconfirm: bool) -> Self {
+        Self {
+            state: Mutex::new(State::default()),
+            encoding_key: EncodingKey::from_secret(secret.as_bytes()),
+            decoding_key: DecodingKey::from_secret(secret.as_bytes()),
+            autoconfirm,
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("STUB LOCK POISONED")
+    }
+
+    // issues a new access and refresh token for the session
+    fn session(&self, state: &mut State, user_id: &str, session_id: &str) -> Value {
+        let user = state
+            .users
+            .iter()
+            .find(|u| u.id == user_id)
+            .expect("SESSION OF UNKNOWN USER");
+
+        let expires_at = Utc::now().timestamp() + TOKEN_LIFETIME;
+
+        let claims = Claims {
+            sub: user.id.clone(),
+            aud: AUDIENCE.to_string(),
+            exp: expires_at,
+            email: user.email.clone(),
+            role: AUDIENCE.to_string(),
+            session_id: session_id.to_string(),
+        };
+
+        let access_token = encode(&Header::default(), &claims, &self.encoding_key)
+            .expect("UNABLE TO SIGN ACCESS TOKEN");
+
+        let user = user.to_json();
+        let refresh_token = new_id();
+
+        state.refresh_tokens.insert(
+            refresh_token.clone(),
+            RefreshToken {
+                user_id: user_id.to_string(),
+                session_id: session_id.to_string(),
+            },
+        );
+
+        json!({
+            "access_token": access_token,
+            "token_type": "bearer",
+            "expires_in": TOKEN_LIFETIME,
+            "expires_at": expires_at,
+            "refresh_token": refresh_token,
+            "user": user,
+        })
+    }
+
+    // prints the code instead of emailing it
+    fn send_otp(&self, state: &mut State, kind: &str, email: &str) {
+        let code = format!("{:06}", uuid::Uuid::new_v4().as_u128() % 1_000_000);
+
+        println!("[mail] {kind} code for {email}: {code}");
+
+        state.otps.retain(|o| !(o.kind == kind && o.email == email));
+        state.otps.push(Otp {
+            kind: kind.to_string(),
+            email: email.to_string(),
+            code,
+        });
+    }
+
+    fn claims(&self, request: &Request<Body>) -> Option<Claims> {
+        let token = request
+            .headers()
+            .get("authorization")?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.set_audience(&[AUDIENCE]);
+
+        decode::<Claims>(token, &self.decoding_key, &validation)
+            .ok()
+            .map(|data| data.claims)
+    }
+
+    fn sign_up(&self, body: Value) -> Response<Body> {
+        let email = body["email"].as_str().unwrap_or_default().to_lowercase();
+        let password = body["password"].as_str().unwrap_or_default();
+
+        if email.is_empty() || password.len() < 6 {
+            return error(
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Signup requires a valid email and a password of at least 6 characters",
+            );
+        }
+
+        let mut state = self.state();
+
+        if state.users.iter().any(|u| u.email == email) {
+            return error(StatusCode::UNPROCESSABLE_ENTITY, "User already registered");
+        }
+
+        let now = Utc::now().to_rfc3339();
+        let id = new_id();
+
+        state.users.push(User {
+            id: id.clone(),
+            email: email.clone(),
+            password: password.to_string(),
+            created_at: now.clone(),
+            confirmation_sent_at: (!self.autoconfirm).then(|| now.clone()),
+            email_confirmed_at: self.autoconfirm.then(|| now.clone()),
+        });
+
+        if self.autoconfirm {
+            let session = self.session(&mut state, &id, &new_id());
+            return reply(StatusCode::OK, session);
+        }
+
+        self.send_otp(&mut state, "signup", &email);
+
+        let user = state.users.last().expect("USER JUST CREATED").to_json();
+        reply(StatusCode::OK, user)
+    }
+
+    fn token(&self, grant_type: &str, body: Value) -> Response<Body> {
+        let mut state = self.state();
+
+        match grant_type {
+            "password" => {
+                let email = body["email"].as_str().unwrap_or_default().to_lowercase();
+                let password = body["password"].as_str().unwrap_or_default();
+
+                let user = match state
+                    .users
+                    .iter()
+                    .find(|u| u.email == email && u.password == password)
+                {
+                    Some(user) => user,
+                    None => return error(StatusCode::BAD_REQUEST, "Invalid login credentials"),
+                };
+
+                if user.email_confirmed_at.is_none() {
+                    return error(StatusCode::BAD_REQUEST, "Email not confirmed");
+                }
+
+                let user_id = user.id.clone();
+                let session = self.session(&mut state, &user_id, &new_id());
+                reply(StatusCode::OK, session)
+            }
+
+            "refresh_token" => {
+                let token = body["refresh_token"].as_str().unwrap_or_default();
+
+                // refresh tokens are single use
+                match state.refresh_tokens.remove(token) {
+                    Some(RefreshToken {
+                        user_id,
+                        session_id,
+                    }) => {
+                        let session = self.session(&mut state, &user_id, &session_id);
+                        reply(StatusCode::OK, session)
+                    }
+
+                    None => error(StatusCode::BAD_REQUEST, "Invalid Refresh Token"),
+                }
+            }
+
+            _ => error(StatusCode::BAD_REQUEST, "Unsupported grant type"),
+        }
+    }
+
+    fn logout(&self, claims: Claims, scope: &str) -> Response<Body> {
+        let mut state = self.state();
+
+        state.refresh_tokens.retain(|_, t| match scope {
+            "global" => t.user_id != claims.sub,
+            _ => t.session_id != claims.session_id,
+        });
+
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .expect("VALID RESPONSE")
+    }
+
+    fn send_code(&self, kind: &str, body: Value) -> Response<Body> {
+        let email = body["email"].as_str().unwrap_or_default().to_lowercase();
+        let mut state = self.state();
+
+        let known = state
+            .users
+            .iter()
+            .any(|u| u.email == email && (kind == "recovery" || u.email_confirmed_at.is_none()));
+
+        // answers the same either way so emails can't be probed
+        if known {
+            self.send_otp(&mut state, kind, &email);
+        }
+
+        reply(StatusCode::OK, json!({}))
+    }
+
+    fn verify(&self, body: Value) -> Response<Body> {
+        let kind = body["type"].as_str().unwrap_or_default();
+        let code = body["token"].as_str().unwrap_or_default();
+        let email = body["email"].as_str().map(str::to_lowercase);
+
+        let mut state = self.state();
+
+        let position = state.otps.iter().position(|o| {
+            o.kind == kind && o.code == code && email.as_ref().is_none_or(|e| *e == o.email)
+        });
+
+        let otp = match position {
+            Some(position) => state.otps.remove(position),
+            None => return error(StatusCode::FORBIDDEN, "Token has expired or is invalid"),
+        };
+
+        let user = match state.users.iter_mut().find(|u| u.email == otp.email) {
+            Some(user) => user,
+            None => return error(StatusCode::NOT_FOUND, "User not found"),
+        };
+
+        if user.email_confirmed_at.is_none() {
+            user.email_confirmed_at = Some(Utc::now().to_rfc3339());
+        }
+
+        let user_id = user.id.clone();
+        let session = self.session(&mut state, &user_id, &new_id());
+        reply(StatusCode::OK, session)
+    }
+
+    fn update_user(&self, claims: Claims, body: Value) -> Response<Body> {
+        let mut state = self.state();
+
+        let user = match state.users.iter_mut().find(|u| u.id == claims.sub) {
+            Some(user) => user,
+            None => return error(StatusCode::NOT_FOUND, "User not found"),
+        };
+
+        if let Some(password) = body["password"].as_str() {
+            if password.len() < 6 {
+                return error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Password should be at least 6 characters",
+                );
+            }
+
+            user.password = password.to_string();
+        }
+
+        reply(StatusCode::OK, user.to_json())
+    }
+
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        let method = request.method().clone();
+        let path = request.uri().path().trim_end_matches('/').to_string();
+
+        let params: HashMap<String, String> = request
+            .uri()
+            .query()
+            .unwrap_or_default()
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect();
+
+        let claims = self.claims(&request);
+
+        let body = match hyper::body::to_bytes(request.into_body()).await {
+            Ok(bytes) if bytes.is_empty() => Value::Null,
+            Ok(bytes) => match serde_json::from_slice(&bytes) {
+                Ok(body) => body,
+                Err(_) => return error(StatusCode::BAD_REQUEST, "Could not parse request body"),
+            },
+            Err(_) => return error(StatusCode::BAD_REQUEST, "Could not read request body"),
+        };
+
+        let param = |key: &str| params.get(key).map(String::as_str).unwrap_or_default();
+
+        match (method, path.as_str(), claims) {
+            (Method::POST, "/signup", _) => self.sign_up(body),
+            (Method::POST, "/token", _) => self.token(param("grant_type"), body),
+            (Method::POST, "/logout", Some(claims)) => self.logout(claims, param("scope")),
+            (Method::POST, "/recover", _) => self.send_code("recovery", body),
+            (Method::POST, "/resend", _) => self.send_code("signup", body),
+            (Method::POST, "/verify", _) => self.verify(body),
+            (Method::PUT, "/user", Some(claims)) => self.update_user(claims, body),
+            (_, "/logout" | "/user", None) => {
+                error(StatusCode::UNAUTHORIZED, "Invalid or missing token")
+            }
+            _ => error(StatusCode::NOT_FOUND, "Not found"),
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv::dotenv().ok();
+
+    let address: SocketAddr = dotenv::var("GOTRUE_STUB_ADDRESS")
+        .unwrap_or_else(|_| DEFAULT_ADDRESS.to_string())
+        .parse()?;
+
+    let secret = dotenv::var("SUPABASE_JWT_SECRET").unwrap_or_else(|_| {
+        eprintln!("SUPABASE_JWT_SECRET IS NOT SET");
+        std::process::exit(1);
+    });
+
+    let autoconfirm = dotenv::var("GOTRUE_STUB_AUTOCONFIRM").is_ok_and(|v| v == "true");
+
+    let stub = Arc::new(Stub::new(&secret, autoconfirm));
+
+    let make_service = make_service_fn(move |_| {
+        let stub = stub.clone();
+
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let stub = stub.clone();
+                async move { Ok::<_, Infallible>(stub.handle(request).await) }
+            }))
+        }
+    });
+
+    println!("GoTrue stub listening on {address}");
+
+    Server::bind(&address).serve(make_service).await?;
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+///
+/// Records every call that changes something in the audit log, see `AuditRepository`.
+///
+/// The layer knows the method and the caller, the handlers tell it what the call acted on
+/// with `target`, `before` and `after` while it is being handled. Only calls that succeed are
+/// recorded, and they only answer once their event is written. A call whose event can't be
+/// written fails, even though its change has been made, so no change goes unnoticed.
+///
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use serde::Serialize;
+use tonic::codegen::http;
+use tonic::metadata::MetadataMap;
+use tonic::Status;
+use tower::{Layer, Service};
+
+use crate::middleware::auth::AuthInterceptor;
+use crate::proto::timebank::admin::TAuditEvent;
+use crate::repository::Repository;
+
+// rpcs whose name starts with one of these only read, ranking and recommending included
+const READ_PREFIXES: &[&str] = &["Get", "List", "Search", "Watch", "Rank", "Recommend"];
+
+// streams carry many changes, which are not recorded one by one
+const STREAMS: &[&str] = &["Chat"];
+
+tokio::task_local! {
+    static EVENT: RefCell<TAuditEvent>;
+}
+
+fn update(f: impl FnOnce(&mut TAuditEvent)) {
+    // calls that aren't recorded have nothing to update
+    let _ = EVENT.try_with(|event| f(&mut event.borrow_mut()));
+}
+
+/// Sets the caller, for calls made before the caller has an access token, eg signing in.
+pub fn user(user_id: &str) {
+    update(|event| event.user_id = user_id.to_string());
+}
+
+/// Sets what the call acted on, eg `target("service_request", &request.id)`.
+pub fn target(entity: &str, id: &str) {
+    update(|event| {
+        event.entity = entity.to_string();
+        event.target_id = id.to_string();
+    });
+}
+
+/// Snapshot of the target before the call changed it.
+pub fn before(value: &impl Serialize) {
+    let value = serde_json::to_string(value).unwrap_or_default();
+    update(|event| event.before = value);
+}
+
+/// Snapshot of the target after the call changed it.
+pub fn after(value: &impl Serialize) {
+    let value = serde_json::to_string(value).unwrap_or_default();
+    update(|event| event.after = value);
+}
+
+pub fn reason(reason: &str) {
+    update(|event| event.reason = reason.to_string());
+}
+
+// `/timebank.servicerequest.ServiceRequest/Update` is recorded as
+// `timebank.servicerequest.ServiceRequest/Update`, reads and streams are skipped
+fn recorded_method(path: &str) -> Option<&str> {
+    let method = path.strip_prefix('/')?;
+    let (_, name) = method.split_once('/')?;
+
+    let is_read = READ_PREFIXES.iter().any(|prefix| name.starts_with(prefix));
+
+    (!is_read && !STREAMS.contains(&name)).then_some(method)
+}
+
+// failed unary calls answer with the status in the headers, successful ones in the trailers
+fn succeeded(headers: &http::HeaderMap) -> bool {
+    headers
+        .get("grpc-status")
+        .is_none_or(|status| status.as_bytes() == b"0")
+}
+
+#[derive(Clone)]
+pub struct AuditLayer {
+    repository: Arc<dyn Repository>,
+    authenticator: AuthInterceptor,
+}
+
+impl AuditLayer {
+    pub fn new(repository: Arc<dyn Repository>, authenticator: AuthInterceptor) -> Self {
+        Self {
+            repository,
+            authenticator,
+        }
+    }
+}
+
+impl<S> Layer<S> for AuditLayer {
+    type Service = Audit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Audit {
+            inner,
+            layer: self.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Audit<S> {
+    inner: S,
+    layer: AuditLayer,
+}
+
+impl<S, B, R> Service<http::Request<B>> for Audit<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<R>>,
+    S::Future: Send + 'static,
+    S::Error: Send,
+    R: Default + Send,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<S::Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let method = match recorded_method(request.uri().path()) {
+            Some(method) => method.to_string(),
+            None => return Box::pin(self.inner.call(request)),
+        };
+
+        // unauthenticated calls are recorded without a caller unless the handler sets one
+        let user_id = self
+            .layer
+            .authenticator
+            .authenticate(&MetadataMap::from_headers(request.headers().clone()))
+            .map(|user| user.id)
+            .unwrap_or_default();
+
+        let event = TAuditEvent {
+            user_id,
+            method,
+            ..Default::default()
+        };
+
+        let future = self.inner.call(request);
+        let repository = self.layer.repository.clone();
+
+        Box::pin(async move {
+            let (response, event) = EVENT
+                .scope(RefCell::new(event), async move {
+                    let response = future.await;
+                    (response, EVENT.with(|event| event.take()))
+                })
+                .await;
+
+            if !matches!(&response, Ok(response) if succeeded(response.headers())) {
+                return response;
+            }
+
+            if let Err(e) = repository.record_audit_event(event).await {
+                log::error!("UNABLE TO RECORD AUDIT EVENT: {e}");
+
+                // the change has been made, but the caller has to know it went unrecorded
+                let (parts, _) = Status::internal("UNABLE TO RECORD AUDIT EVENT")
+                    .to_http()
+                    .into_parts();
+
+                return Ok(http::Response::from_parts(parts, R::default()));
+            }
+
+            response
+        })
+    }
+}
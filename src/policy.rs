@@ -0,0 +1,516 @@
+///
+/// Decides whether a user may perform an action, from their role and their relation to the
+/// resource only.
+///
+/// Handlers load what the decision needs (eg the requestor of a request) and ask `authorize`,
+/// which never touches storage so every rule can be checked in isolation.
+///
+use crate::error::Error;
+use crate::proto::timebank::role::Role;
+use crate::proto::timebank::servicerequeststatus::RequestStatus;
+
+/// The user making the call.
+#[derive(Debug, Clone)]
+pub struct Actor {
+    pub id: String,
+    pub role: Role,
+}
+
+impl Actor {
+    pub fn new(id: impl Into<String>, role: Role) -> Self {
+        Self {
+            id: id.into(),
+            role,
+        }
+    }
+
+    /// Members, and users whose role isn't known, rank lowest.
+    pub fn has_role(&self, role: Role) -> bool {
+        rank(self.role) >= rank(role)
+    }
+
+    fn is(&self, user_id: &str) -> bool {
+        self.id == user_id
+    }
+}
+
+fn rank(role: Role) -> u8 {
+    match role {
+        Role::Unspecified | Role::Member => 0,
+        Role::Moderator => 1,
+        Role::Admin => 2,
+    }
+}
+
+/// What the actor wants to do, along with the users the decision depends on.
+#[derive(Debug, Clone, Copy)]
+pub enum Action<'a> {
+    UpdateRequest {
+        requestor: &'a str,
+    },
+    DeleteRequest {
+        requestor: &'a str,
+    },
+    SelectBid {
+        requestor: &'a str,
+    },
+    CompleteService {
+        requestor: &'a str,
+    },
+    /// Ranking the bids on a request, or recommending providers for it.
+    Rank {
+        requestor: &'a str,
+    },
+    ChangeStatus {
+        to: RequestStatus,
+        requestor: &'a str,
+        provider: Option<&'a str>,
+    },
+    DeleteBid {
+        bidder: &'a str,
+    },
+    UpdateBid {
+        bidder: &'a str,
+    },
+    WithdrawBid {
+        bidder: &'a str,
+    },
+    CounterOffer {
+        requestor: &'a str,
+    },
+    AcceptCounterOffer {
+        bidder: &'a str,
+    },
+    /// Reading the negotiation over a bid.
+    ViewCounterOffers {
+        requestor: &'a str,
+        bidder: &'a str,
+    },
+    RateService {
+        requestor: &'a str,
+        provider: &'a str,
+    },
+    UpdateRating {
+        author: &'a str,
+    },
+    DeleteRating {
+        author: &'a str,
+    },
+    UpdateProfile {
+        user_id: &'a str,
+    },
+    AccessConversation {
+        requestor: &'a str,
+        provider: &'a str,
+    },
+    /// Appealing a rating, which only the rated user can.
+    AppealRating {
+        rated_user: &'a str,
+    },
+    ListModerationCases,
+    /// Hiding or restoring a rating, or dismissing a case about it.
+    ModerateRating {
+        author: &'a str,
+        rated_user: &'a str,
+    },
+    ViewVerification {
+        user_id: &'a str,
+    },
+    ListVerifications,
+    /// Any call of the admin service.
+    Administer,
+    /// Creating, updating or deleting categories.
+    EditTaxonomy,
+    SanctionUser {
+        user_id: &'a str,
+    },
+    ReviewVerification {
+        user_id: &'a str,
+    },
+}
+
+fn deny(reason: &str) -> Result<(), Error> {
+    Err(Error::PermissionDenied(reason.to_string()))
+}
+
+/// Fails with `Error::PermissionDenied`, with the reason, unless the actor may perform the action.
+pub fn authorize(actor: &Actor, action: Action<'_>) -> Result<(), Error> {
+    // not even admins, they would lock themselves out
+    if matches!(action, Action::SanctionUser { user_id } if actor.is(user_id)) {
+        return deny("USERS CANNOT SANCTION THEMSELVES");
+    }
+
+    // nobody vouches for themselves
+    if matches!(action, Action::ReviewVerification { user_id } if actor.is(user_id)) {
+        return deny("USERS CANNOT REVIEW THEIR OWN VERIFICATION");
+    }
+
+    // conversations are private to the two sides of the exchange, admins included
+    if let Action::AccessConversation {
+        requestor,
+        provider,
+    } = action
+    {
+        if !actor.is(requestor) && !actor.is(provider) {
+            return deny("ONLY THE REQUESTOR AND THE PROVIDER CAN ACCESS THE CONVERSATION");
+        }
+    }
+
+    // a rating speaks for one side of the exchange, nobody rates on its behalf
+    if let Action::RateService {
+        requestor,
+        provider,
+    } = action
+    {
+        if !actor.is(requestor) && !actor.is(provider) {
+            return deny("ONLY THE REQUESTOR AND THE PROVIDER CAN RATE THE SERVICE");
+        }
+    }
+
+    if actor.has_role(Role::Admin) {
+        return Ok(());
+    }
+
+    match action {
+        Action::UpdateRequest { requestor } if !actor.is(requestor) => {
+            deny("ONLY THE REQUESTOR CAN UPDATE THE REQUEST")
+        }
+
+        Action::DeleteRequest { requestor } if !actor.is(requestor) => {
+            deny("ONLY THE REQUESTOR CAN DELETE THE REQUEST")
+        }
+
+        Action::SelectBid { requestor } if !actor.is(requestor) => {
+            deny("ONLY THE REQUESTOR CAN SELECT A BID")
+        }
+
+        Action::CompleteService { requestor } if !actor.is(requestor) => {
+            deny("ONLY THE REQUESTOR CAN CONFIRM THE SERVICE WAS PROVIDED")
+        }
+
+        Action::Rank { requestor } if !actor.is(requestor) => {
+            deny("ONLY THE REQUESTOR CAN RANK BIDS AND PROVIDERS")
+        }
+
+        Action::ChangeStatus {
+            to,
+            requestor,
+            provider,
+        } => {
+            let is_requestor = actor.is(requestor);
+            let is_provider = provider.is_some_and(|p| actor.is(p));
+
+            match to {
+                RequestStatus::Open | RequestStatus::BiddingClosed | RequestStatus::Cancelled
+                    if !is_requestor =>
+                {
+                    deny("ONLY THE REQUESTOR CAN OPEN, CLOSE OR CANCEL THE REQUEST")
+                }
+                RequestStatus::InProgress if !is_provider => {
+                    deny("ONLY THE PROVIDER CAN START THE SERVICE")
+                }
+                RequestStatus::Disputed if !is_requestor && !is_provider => {
+                    deny("ONLY THE REQUESTOR OR THE PROVIDER CAN DISPUTE THE REQUEST")
+                }
+                _ => Ok(()),
+            }
+        }
+
+        Action::DeleteBid { bidder } if !actor.is(bidder) => {
+            deny("ONLY THE BIDDER CAN DELETE THE BID")
+        }
+
+        Action::UpdateBid { bidder } if !actor.is(bidder) => {
+            deny("ONLY THE BIDDER CAN UPDATE THE BID")
+        }
+
+        Action::WithdrawBid { bidder } if !actor.is(bidder) => {
+            deny("ONLY THE BIDDER CAN WITHDRAW THE BID")
+        }
+
+        Action::CounterOffer { requestor } if !actor.is(requestor) => {
+            deny("ONLY THE REQUESTOR CAN MAKE A COUNTER-OFFER")
+        }
+
+        Action::AcceptCounterOffer { bidder } if !actor.is(bidder) => {
+            deny("ONLY THE BIDDER CAN ACCEPT THE COUNTER-OFFER")
+        }
+
+        Action::ViewCounterOffers { requestor, bidder }
+            if !actor.is(requestor) && !actor.is(bidder) =>
+        {
+            deny("ONLY THE REQUESTOR AND THE BIDDER CAN SEE THE COUNTER-OFFERS")
+        }
+
+        Action::UpdateRating { author } if !actor.is(author) => {
+            deny("ONLY THE AUTHOR CAN UPDATE THE RATING")
+        }
+
+        Action::DeleteRating { author } if !actor.is(author) => {
+            deny("ONLY THE AUTHOR CAN DELETE THE RATING")
+        }
+
+        Action::UpdateProfile { user_id } if !actor.is(user_id) => {
+            deny("USERS CAN ONLY UPDATE THEIR OWN PROFILE")
+        }
+
+        Action::AppealRating { rated_user } if !actor.is(rated_user) => {
+            deny("ONLY THE RATED USER CAN APPEAL THE RATING")
+        }
+
+        Action::ListModerationCases if !actor.has_role(Role::Moderator) => {
+            deny("ONLY MODERATORS CAN LIST MODERATION CASES")
+        }
+
+        Action::ModerateRating { .. } if !actor.has_role(Role::Moderator) => {
+            deny("ONLY MODERATORS CAN MODERATE RATINGS")
+        }
+
+        Action::ModerateRating { author, rated_user }
+            if actor.is(author) || actor.is(rated_user) =>
+        {
+            deny("USERS CANNOT MODERATE RATINGS THEY GAVE OR RECEIVED")
+        }
+
+        Action::ViewVerification { user_id }
+            if !actor.is(user_id) && !actor.has_role(Role::Moderator) =>
+        {
+            deny("ONLY MODERATORS CAN VIEW THE DOCUMENTS OF OTHER USERS")
+        }
+
+        Action::ListVerifications if !actor.has_role(Role::Moderator) => {
+            deny("ONLY MODERATORS CAN LIST VERIFICATIONS")
+        }
+
+        Action::ReviewVerification { .. } => deny("ONLY ADMINS CAN REVIEW VERIFICATIONS"),
+
+        Action::Administer | Action::SanctionUser { .. } => {
+            deny("ONLY ADMINS CAN MANAGE THE COMMUNITY")
+        }
+
+        Action::EditTaxonomy => deny("ONLY ADMINS CAN EDIT THE TAXONOMY"),
+
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OWNER: &str = "owner";
+    const OTHER: &str = "other";
+
+    fn allowed(actor: &Actor, action: Action<'_>) -> bool {
+        authorize(actor, action).is_ok()
+    }
+
+    // actions on something that belongs to `OWNER`
+    fn owned() -> Vec<Action<'static>> {
+        vec![
+            Action::UpdateRequest { requestor: OWNER },
+            Action::DeleteRequest { requestor: OWNER },
+            Action::SelectBid { requestor: OWNER },
+            Action::CompleteService { requestor: OWNER },
+            Action::Rank { requestor: OWNER },
+            Action::DeleteBid { bidder: OWNER },
+            Action::UpdateBid { bidder: OWNER },
+            Action::WithdrawBid { bidder: OWNER },
+            Action::CounterOffer { requestor: OWNER },
+            Action::AcceptCounterOffer { bidder: OWNER },
+            Action::ViewCounterOffers {
+                requestor: OWNER,
+                bidder: "bidder",
+            },
+            Action::ViewCounterOffers {
+                requestor: "requestor",
+                bidder: OWNER,
+            },
+            Action::RateService {
+                requestor: OWNER,
+                provider: "provider",
+            },
+            Action::UpdateRating { author: OWNER },
+            Action::DeleteRating { author: OWNER },
+            Action::UpdateProfile { user_id: OWNER },
+            Action::AccessConversation {
+                requestor: OWNER,
+                provider: "provider",
+            },
+            Action::AppealRating { rated_user: OWNER },
+            Action::ViewVerification { user_id: OWNER },
+        ]
+    }
+
+    #[test]
+    fn owners_can_act_on_what_they_own() {
+        for action in owned() {
+            assert!(
+                allowed(&Actor::new(OWNER, Role::Member), action),
+                "{action:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn members_cannot_act_on_what_others_own() {
+        for role in [Role::Unspecified, Role::Member] {
+            for action in owned() {
+                let actor = Actor::new(OTHER, role);
+                assert!(!allowed(&actor, action), "{role:?} {action:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn moderators_only_look_at_the_documents_of_others() {
+        let moderator = Actor::new(OTHER, Role::Moderator);
+
+        for action in owned() {
+            let expected = matches!(action, Action::ViewVerification { .. });
+            assert_eq!(allowed(&moderator, action), expected, "{action:?}");
+        }
+    }
+
+    #[test]
+    fn admins_can_act_on_what_others_own() {
+        for action in owned() {
+            let expected = !matches!(
+                action,
+                Action::AccessConversation { .. } | Action::RateService { .. }
+            );
+            assert_eq!(
+                allowed(&Actor::new(OTHER, Role::Admin), action),
+                expected,
+                "{action:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn roles_rank_members_below_moderators_below_admins() {
+        let moderate = Action::ModerateRating {
+            author: "author",
+            rated_user: "rated",
+        };
+
+        let cases = [
+            (Action::ListModerationCases, Role::Moderator),
+            (moderate, Role::Moderator),
+            (Action::ListVerifications, Role::Moderator),
+            (Action::ReviewVerification { user_id: OTHER }, Role::Admin),
+            (Action::SanctionUser { user_id: OTHER }, Role::Admin),
+            (Action::Administer, Role::Admin),
+            (Action::EditTaxonomy, Role::Admin),
+        ];
+
+        for (action, lowest) in cases {
+            for role in [
+                Role::Unspecified,
+                Role::Member,
+                Role::Moderator,
+                Role::Admin,
+            ] {
+                let expected = rank(role) >= rank(lowest);
+                let actor = Actor::new(OWNER, role);
+
+                assert_eq!(allowed(&actor, action), expected, "{role:?} {action:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn nobody_sanctions_themselves() {
+        for role in [Role::Member, Role::Moderator, Role::Admin] {
+            let result = authorize(
+                &Actor::new(OWNER, role),
+                Action::SanctionUser { user_id: OWNER },
+            );
+
+            assert!(
+                matches!(&result, Err(Error::PermissionDenied(reason)) if reason == "USERS CANNOT SANCTION THEMSELVES"),
+                "{role:?} {result:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn nobody_reviews_their_own_verification() {
+        for role in [Role::Moderator, Role::Admin] {
+            let actor = Actor::new(OWNER, role);
+            let action = Action::ReviewVerification { user_id: OWNER };
+
+            assert!(!allowed(&actor, action), "{role:?}");
+        }
+    }
+
+    #[test]
+    fn nobody_reads_the_conversations_of_others() {
+        for role in [Role::Member, Role::Moderator, Role::Admin] {
+            let action = Action::AccessConversation {
+                requestor: OWNER,
+                provider: "provider",
+            };
+
+            assert!(!allowed(&Actor::new(OTHER, role), action), "{role:?}");
+        }
+    }
+
+    #[test]
+    fn nobody_rates_a_service_they_took_no_part_in() {
+        for role in [Role::Member, Role::Moderator, Role::Admin] {
+            let action = Action::RateService {
+                requestor: OWNER,
+                provider: "provider",
+            };
+
+            assert!(!allowed(&Actor::new(OTHER, role), action), "{role:?}");
+        }
+    }
+
+    #[test]
+    fn moderators_cannot_moderate_ratings_they_gave_or_received() {
+        let moderator = Actor::new(OWNER, Role::Moderator);
+
+        let cases = [
+            (OWNER, OTHER, false),
+            (OTHER, OWNER, false),
+            (OTHER, "third", true),
+        ];
+
+        for (author, rated_user, expected) in cases {
+            let action = Action::ModerateRating { author, rated_user };
+            assert_eq!(allowed(&moderator, action), expected, "{action:?}");
+        }
+    }
+
+    #[test]
+    fn status_changes_depend_on_the_side_of_the_request() {
+        use RequestStatus::*;
+
+        let cases = [
+            (Open, "requestor", true),
+            (Open, "provider", false),
+            (BiddingClosed, "requestor", true),
+            (BiddingClosed, "provider", false),
+            (Cancelled, "requestor", true),
+            (Cancelled, "provider", false),
+            (InProgress, "provider", true),
+            (InProgress, "requestor", false),
+            (Disputed, "requestor", true),
+            (Disputed, "provider", true),
+            (Disputed, OTHER, false),
+        ];
+
+        for (to, user_id, expected) in cases {
+            let action = Action::ChangeStatus {
+                to,
+                requestor: "requestor",
+                provider: Some("provider"),
+            };
+
+            let actor = Actor::new(user_id, Role::Member);
+            assert_eq!(allowed(&actor, action), expected, "{user_id} {to:?}");
+        }
+    }
+}
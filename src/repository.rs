@@ -0,0 +1,420 @@
+///
+/// Storage abstraction used by the services, with one trait per collection.
+///
+/// `database::DatabaseRepository` talks to the Supabase PostgREST endpoint while
+/// `memory::MemoryRepository` keeps everything in process, which is handy for
+/// tests and local demos.
+///
+pub mod database;
+pub mod memory;
+
+use crate::error::Result;
+use crate::proto::timebank::ledger::TTransaction;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::proto::account::TUserProfile;
+use crate::proto::auth::TSession;
+use crate::proto::timebank::admin::{TAuditEvent, TRatingVersion, TSanction, TStats};
+use crate::proto::timebank::messaging::TMessage;
+use crate::proto::timebank::moderation::TModerationCase;
+use crate::proto::timebank::reputation::ReputationRole;
+use crate::proto::timebank::servicerating::{self, TServiceRating};
+use crate::proto::timebank::servicerequest::{self, TServiceRequest};
+use crate::proto::timebank::servicerequestbid::{self, TCounterOffer, TServiceRequestBid};
+use crate::proto::timebank::servicerequeststatus::{RequestStatus, TStatusTransition};
+use crate::proto::timebank::taxonomy::{TCategory, TSkill};
+use crate::proto::timebank::verification::TVerification;
+
+/// Condition a column must satisfy, see `Query`.
+#[derive(Debug, Clone)]
+pub enum Filter {
+    Eq(String),
+    Neq(String),
+    Gt(String),
+    Lt(String),
+    In(Vec<String>),
+    /// Case insensitive pattern where `%` matches any sequence of characters.
+    Ilike(String),
+    /// Inclusive lower and upper bound.
+    Range(String, String),
+    /// Array column sharing at least one element with the values.
+    Overlaps(Vec<String>),
+}
+
+/// Narrows down, orders and paginates the rows returned by the `get_*` methods.
+///
+/// Columns are expected to be validated by the caller. Fields of json columns are addressed
+/// with a dot, eg `request_data.title`.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    pub filters: Vec<(String, Filter)>,
+    /// Columns to order by and whether the order is descending.
+    pub order: Vec<(String, bool)>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl Query {
+    /// Every row whose `column` equals `value`.
+    pub fn eq(column: &str, value: &str) -> Self {
+        Self {
+            filters: vec![(column.to_string(), Filter::Eq(value.to_string()))],
+            ..Default::default()
+        }
+    }
+}
+
+#[tonic::async_trait]
+pub trait ServiceRequestRepository: Send + Sync {
+    async fn create_request(
+        &self,
+        requestor: &str,
+        payload: servicerequest::create::Payload,
+    ) -> Result<Option<TServiceRequest>>;
+
+    /// Overwrites the given columns, see `services::field_mask` for building them.
+    async fn update_request(
+        &self,
+        request_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRequest>>;
+
+    async fn delete_request(&self, request_id: &str) -> Result<()>;
+
+    async fn get_request(&self, request_id: &str) -> Result<Option<TServiceRequest>>;
+
+    /// Selects the bid of the transition's request, recording the transition and the escrow
+    /// transaction along with it. Either all of them take effect or none.
+    async fn select_bid(
+        &self,
+        bid_id: &str,
+        transition: TStatusTransition,
+        escrow: TTransaction,
+    ) -> Result<Option<TServiceRequest>>;
+
+    async fn get_requests(&self, query: &Query) -> Result<Vec<TServiceRequest>>;
+}
+
+#[tonic::async_trait]
+pub trait ServiceRequestBidRepository: Send + Sync {
+    async fn create_bid(
+        &self,
+        user_id: &str,
+        payload: servicerequestbid::create::Payload,
+    ) -> Result<Option<TServiceRequestBid>>;
+
+    async fn delete_bid(&self, bid_id: &str) -> Result<()>;
+
+    async fn get_selected_bid(&self, request_id: &str) -> Result<Option<TServiceRequestBid>>;
+
+    async fn get_bids(&self, query: &Query) -> Result<Vec<TServiceRequestBid>>;
+
+    /// Overwrites the given columns and sets `updated_at`.
+    async fn update_bid(
+        &self,
+        bid_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRequestBid>>;
+
+    async fn create_counter_offer(&self, offer: TCounterOffer) -> Result<TCounterOffer>;
+
+    async fn update_counter_offer(
+        &self,
+        offer_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TCounterOffer>>;
+
+    async fn get_counter_offers(&self, query: &Query) -> Result<Vec<TCounterOffer>>;
+}
+
+#[tonic::async_trait]
+pub trait ServiceRatingRepository: Send + Sync {
+    /// Rating of `rated_user_id` by `user_id`, hidden until revealed with `reveal_rating`.
+    async fn create_rating(
+        &self,
+        user_id: &str,
+        rated_user_id: &str,
+        role: ReputationRole,
+        payload: servicerating::create::Payload,
+    ) -> Result<Option<TServiceRating>>;
+
+    async fn update_rating(
+        &self,
+        rating_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRating>>;
+
+    /// Makes the rating public, `None` if it doesn't exist or was revealed already, which
+    /// concurrent reveals race for so that only one of them counts it.
+    async fn reveal_rating(&self, rating_id: &str) -> Result<Option<TServiceRating>>;
+
+    async fn delete_rating(&self, rating_id: &str) -> Result<()>;
+
+    async fn get_ratings(&self, query: &Query) -> Result<Vec<TServiceRating>>;
+
+    /// Keeps the values a rating had before an edit.
+    async fn create_rating_version(&self, version: TRatingVersion) -> Result<TRatingVersion>;
+
+    async fn get_rating_versions(&self, query: &Query) -> Result<Vec<TRatingVersion>>;
+}
+
+#[tonic::async_trait]
+pub trait UserProfileRepository: Send + Sync {
+    async fn get_profile(&self, user_id: &str) -> Result<Option<TUserProfile>>;
+
+    async fn get_profiles(&self, query: &Query) -> Result<Vec<TUserProfile>>;
+
+    async fn update_profile(
+        &self,
+        user_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TUserProfile>>;
+
+    /// Revealed ratings received by the user, as a provider and as a requestor.
+    async fn get_user_ratings(&self, user_id: &str) -> Result<Vec<TServiceRating>>;
+}
+
+#[tonic::async_trait]
+pub trait RequestStatusRepository: Send + Sync {
+    /// Status transitions of the request, oldest first.
+    async fn get_status_history(&self, request_id: &str) -> Result<Vec<TStatusTransition>>;
+
+    /// Records a transition, failing with `Error::InvalidTransition` if the request is no longer
+    /// in the transition's `from_status`.
+    async fn record_transition(&self, transition: TStatusTransition) -> Result<TStatusTransition>;
+
+    /// Records the transition and moves whatever the request's escrow holds to `to` together, so
+    /// the status never changes without the credits following.
+    async fn release_escrow(
+        &self,
+        transition: TStatusTransition,
+        to: &str,
+        memo: &str,
+    ) -> Result<TStatusTransition>;
+
+    /// Requests created before statuses were tracked have no history and are considered open.
+    async fn get_status(&self, request_id: &str) -> Result<RequestStatus> {
+        Ok(self
+            .get_status_history(request_id)
+            .await?
+            .last()
+            .map_or(RequestStatus::Open, |t| t.to_status()))
+    }
+}
+
+/// Narrows down the transactions returned by `LedgerRepository::list_transactions`.
+#[derive(Default)]
+pub struct TransactionFilter {
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    /// Only transactions that also have an entry for this account.
+    pub counterparty: Option<String>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+#[tonic::async_trait]
+pub trait LedgerRepository: Send + Sync {
+    /// Records a transaction. Its entries are expected to be balanced, ie sum to zero.
+    async fn record_transaction(&self, transaction: TTransaction) -> Result<TTransaction>;
+
+    /// Sum of the account's entries, only counting transactions created before `until` if given.
+    async fn get_balance(&self, account: &str, until: Option<DateTime<Utc>>) -> Result<f64>;
+
+    /// Sum of the escrow accounts the account has transactions with.
+    async fn get_escrowed(&self, account: &str) -> Result<f64>;
+
+    /// Transactions with an entry for the account, oldest first.
+    async fn list_transactions(
+        &self,
+        account: &str,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<TTransaction>>;
+}
+
+#[tonic::async_trait]
+pub trait MessageRepository: Send + Sync {
+    async fn create_message(&self, message: TMessage) -> Result<TMessage>;
+
+    async fn get_messages(&self, query: &Query) -> Result<Vec<TMessage>>;
+
+    /// Marks the unread messages of the request's conversation that weren't sent by `reader` as
+    /// read, returning them.
+    async fn mark_read(&self, request_id: &str, reader: &str) -> Result<Vec<TMessage>>;
+}
+
+/// Sessions are created and refreshed by GoTrue, this only reads and revokes them.
+#[tonic::async_trait]
+pub trait SessionRepository: Send + Sync {
+    /// Sessions of the user that haven't been revoked, most recently used first.
+    async fn get_sessions(&self, user_id: &str) -> Result<Vec<TSession>>;
+
+    /// Revokes the session along with its refresh tokens, failing with `Error::NotFound` unless
+    /// it belongs to the user.
+    async fn revoke_session(&self, user_id: &str, session_id: &str) -> Result<()>;
+}
+
+#[tonic::async_trait]
+pub trait VerificationRepository: Send + Sync {
+    async fn get_verification(&self, user_id: &str) -> Result<Option<TVerification>>;
+
+    async fn get_verifications(&self, query: &Query) -> Result<Vec<TVerification>>;
+
+    /// Creates or replaces the user's verification.
+    async fn save_verification(&self, verification: TVerification) -> Result<TVerification>;
+}
+
+#[tonic::async_trait]
+pub trait SanctionRepository: Send + Sync {
+    async fn get_sanction(&self, user_id: &str) -> Result<Option<TSanction>>;
+
+    async fn get_sanctions(&self, query: &Query) -> Result<Vec<TSanction>>;
+
+    /// Creates or replaces the user's sanction.
+    async fn save_sanction(&self, sanction: TSanction) -> Result<TSanction>;
+
+    /// Lifts the user's sanction, failing with `Error::NotFound` if they have none.
+    async fn delete_sanction(&self, user_id: &str) -> Result<()>;
+}
+
+/// The audit log is append-only, events are never updated nor deleted.
+#[tonic::async_trait]
+pub trait AuditRepository: Send + Sync {
+    async fn record_audit_event(&self, event: TAuditEvent) -> Result<TAuditEvent>;
+
+    async fn get_audit_events(&self, query: &Query) -> Result<Vec<TAuditEvent>>;
+}
+
+#[tonic::async_trait]
+pub trait StatsRepository: Send + Sync {
+    /// Platform wide counts, sanctions that have run out aren't counted.
+    async fn get_stats(&self) -> Result<TStats>;
+}
+
+/// Track record of a user as a provider, derived from their bids.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ProviderStats {
+    pub user_id: String,
+    /// Bids placed, withdrawn ones included.
+    pub bids: u64,
+    /// Requests the user's bid was selected for.
+    pub selected: u64,
+    /// Selected requests that are over, ie completed, cancelled, disputed or expired.
+    pub concluded: u64,
+    pub completed: u64,
+    /// Mean time between a request being posted and the user bidding on it.
+    pub mean_response_seconds: Option<f64>,
+}
+
+#[tonic::async_trait]
+pub trait ProviderRepository: Send + Sync {
+    /// Filters and orders on any column of `ProviderStats`, users who never bid are left out.
+    async fn get_provider_stats(&self, query: &Query) -> Result<Vec<ProviderStats>>;
+}
+
+/// Running totals of the ratings a user received for one role, see `services::reputation`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReputationTally {
+    pub id: String,
+    pub user_id: String,
+    /// A `ReputationRole`, provider or requestor.
+    pub role: i32,
+    pub count: u64,
+    pub sum: i64,
+    /// Ratings of each value, the first is the lowest of the scale.
+    pub histogram: Vec<u64>,
+    pub score: f64,
+    pub updated_at: String,
+}
+
+#[tonic::async_trait]
+pub trait ReputationRepository: Send + Sync {
+    async fn get_reputations(&self, query: &Query) -> Result<Vec<ReputationTally>>;
+
+    /// Counts the `added` rating value in and the `removed` one out of the user's tally for the
+    /// role, creating the tally if needed, and recomputes its score. Done in one step so
+    /// concurrent ratings don't overwrite each other.
+    async fn adjust_reputation(
+        &self,
+        user_id: &str,
+        role: ReputationRole,
+        added: Option<i32>,
+        removed: Option<i32>,
+    ) -> Result<ReputationTally>;
+}
+
+#[tonic::async_trait]
+pub trait ModerationRepository: Send + Sync {
+    async fn create_case(&self, case: TModerationCase) -> Result<TModerationCase>;
+
+    async fn update_case(
+        &self,
+        case_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TModerationCase>>;
+
+    async fn get_cases(&self, query: &Query) -> Result<Vec<TModerationCase>>;
+}
+
+#[tonic::async_trait]
+pub trait TaxonomyRepository: Send + Sync {
+    async fn create_category(&self, category: TCategory) -> Result<TCategory>;
+
+    async fn update_category(
+        &self,
+        category_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TCategory>>;
+
+    async fn delete_category(&self, category_id: &str) -> Result<()>;
+
+    async fn get_categories(&self, query: &Query) -> Result<Vec<TCategory>>;
+
+    /// Replaces every skill of the user in one step.
+    async fn set_skills(&self, user_id: &str, skills: Vec<TSkill>) -> Result<Vec<TSkill>>;
+
+    async fn get_skills(&self, query: &Query) -> Result<Vec<TSkill>>;
+}
+
+/// Every collection the services need, so a single backend can be shared by all of them.
+pub trait Repository:
+    ServiceRequestRepository
+    + ServiceRequestBidRepository
+    + ServiceRatingRepository
+    + UserProfileRepository
+    + LedgerRepository
+    + RequestStatusRepository
+    + MessageRepository
+    + SessionRepository
+    + VerificationRepository
+    + SanctionRepository
+    + AuditRepository
+    + StatsRepository
+    + ProviderRepository
+    + ReputationRepository
+    + ModerationRepository
+    + TaxonomyRepository
+{
+}
+
+impl<T> Repository for T where
+    T: ServiceRequestRepository
+        + ServiceRequestBidRepository
+        + ServiceRatingRepository
+        + UserProfileRepository
+        + LedgerRepository
+        + RequestStatusRepository
+        + MessageRepository
+        + SessionRepository
+        + VerificationRepository
+        + SanctionRepository
+        + AuditRepository
+        + StatsRepository
+        + ProviderRepository
+        + ReputationRepository
+        + ModerationRepository
+        + TaxonomyRepository
+{
+}
@@ -0,0 +1,1339 @@
+// Repository backed by the Supabase PostgREST endpoint
+
+use postgrest::Builder;
+use reqwest::StatusCode;
+use serde_json::{json, Map, Value};
+
+use chrono::{DateTime, Utc};
+
+use super::{
+    AuditRepository, Filter, LedgerRepository, MessageRepository, ModerationRepository,
+    ProviderRepository, ProviderStats, Query, ReputationRepository, ReputationTally,
+    RequestStatusRepository, SanctionRepository, ServiceRatingRepository,
+    ServiceRequestBidRepository, ServiceRequestRepository, SessionRepository, StatsRepository,
+    TaxonomyRepository, TransactionFilter, UserProfileRepository, VerificationRepository,
+};
+use crate::config::Config;
+use crate::error::{Error, Result};
+use crate::proto::account::TUserProfile;
+use crate::proto::auth::TSession;
+use crate::proto::timebank::admin::{TAuditEvent, TRatingVersion, TSanction, TStats};
+use crate::proto::timebank::ledger::TTransaction;
+use crate::proto::timebank::messaging::TMessage;
+use crate::proto::timebank::moderation::TModerationCase;
+use crate::proto::timebank::reputation::ReputationRole;
+use crate::proto::timebank::servicerating::{self, TServiceRating};
+use crate::proto::timebank::servicerequest::{self, TServiceRequest};
+use crate::proto::timebank::servicerequestbid::{self, TCounterOffer, TServiceRequestBid};
+use crate::proto::timebank::servicerequeststatus::TStatusTransition;
+use crate::proto::timebank::taxonomy::{TCategory, TSkill};
+use crate::proto::timebank::verification::TVerification;
+use crate::services::collection::service_rating::RATING_SCALE;
+use crate::services::reputation::{PRIOR_MEAN, PRIOR_WEIGHT};
+use crate::services::util::{miscellaneous::PostgrestClient, DatabaseErrorResponse};
+use crate::upstream::Upstream;
+
+pub struct DatabaseRepository {
+    db_client: PostgrestClient,
+    upstream: Upstream,
+}
+
+impl DatabaseRepository {
+    pub fn new(config: &Config, upstream: Upstream) -> Self {
+        Self {
+            db_client: PostgrestClient::new(
+                &config.supabase.database_endpoint,
+                upstream.client().clone(),
+            ),
+            upstream,
+        }
+    }
+
+    // the request is built anew for every attempt, as reads may be retried
+    async fn read(
+        &self,
+        request: impl Fn(&PostgrestClient) -> Builder,
+    ) -> Result<reqwest::Response> {
+        self.upstream
+            .read(|| request(&self.db_client).execute())
+            .await
+    }
+
+    async fn write(
+        &self,
+        request: impl Fn(&PostgrestClient) -> Builder,
+    ) -> Result<reqwest::Response> {
+        self.upstream
+            .write(|| request(&self.db_client).execute())
+            .await
+    }
+}
+
+// maps a non-successful PostgREST response to the matching `Error`
+async fn error(res: reqwest::Response) -> Error {
+    DatabaseErrorResponse::from_response(res).await.into()
+}
+
+// unset timestamps are empty strings in the protos and nulls in `timestamptz` columns
+fn timestamps_to_row<T: serde::Serialize>(value: &T, timestamps: &[&str]) -> Result<String> {
+    let mut row = serde_json::to_value(value)?;
+
+    if let Some(columns) = row.as_object_mut() {
+        for column in timestamps {
+            if columns.get(*column).and_then(Value::as_str) == Some("") {
+                columns.insert(column.to_string(), Value::Null);
+            }
+        }
+    }
+
+    Ok(row.to_string())
+}
+
+fn timestamps_from_rows<T: serde::de::DeserializeOwned>(
+    mut rows: Vec<Map<String, Value>>,
+    timestamps: &[&str],
+) -> Result<Vec<T>> {
+    for columns in &mut rows {
+        for column in timestamps {
+            if columns.get(*column) == Some(&Value::Null) {
+                columns.insert(column.to_string(), json!(""));
+            }
+        }
+    }
+
+    Ok(rows
+        .into_iter()
+        .map(|columns| serde_json::from_value(Value::Object(columns)))
+        .collect::<std::result::Result<_, _>>()?)
+}
+
+const VERIFICATION_TIMESTAMPS: &[&str] = &["submitted_at", "reviewed_at"];
+const SANCTION_TIMESTAMPS: &[&str] = &["until", "created_at"];
+
+// profiles embed the skills the user offers
+const PROFILE_COLUMNS: &str = "*,skills:user_skill(*)";
+
+// fields of json columns are addressed with a dot in `Query`, `column->>field` in PostgREST
+fn column_path(column: &str) -> String {
+    match column.split_once('.') {
+        Some((column, field)) => format!("{column}->>{field}"),
+        None => column.to_string(),
+    }
+}
+
+// postgres array literal of the values, quoted as they may contain commas
+fn array_literal(values: &[String]) -> String {
+    let values: Vec<String> = values
+        .iter()
+        .map(|value| format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\"")))
+        .collect();
+
+    format!("{{{}}}", values.join(","))
+}
+
+// translates the query to PostgREST filters, ordering and range
+fn apply_query(mut builder: Builder, query: &Query) -> Builder {
+    for (column, filter) in &query.filters {
+        let column = column_path(column);
+
+        builder = match filter {
+            Filter::Eq(value) => builder.eq(column, value),
+            Filter::Neq(value) => builder.neq(column, value),
+            Filter::Gt(value) => builder.gt(column, value),
+            Filter::Lt(value) => builder.lt(column, value),
+            Filter::In(values) => builder.in_(column, values),
+            Filter::Ilike(pattern) => builder.ilike(column, pattern),
+            Filter::Range(lower, upper) => builder.gte(&column, lower).lte(column, upper),
+            Filter::Overlaps(values) => builder.ov(column, array_literal(values)),
+        };
+    }
+
+    if !query.order.is_empty() {
+        let order = query
+            .order
+            .iter()
+            .map(|(column, descending)| {
+                let direction = if *descending { "desc" } else { "asc" };
+                format!("{}.{direction}", column_path(column))
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        builder = builder.order(order);
+    }
+
+    match query.limit {
+        Some(limit) => builder.range(query.offset, query.offset + limit.max(1) - 1),
+        None if query.offset > 0 => builder.range(query.offset, i64::MAX as usize),
+        None => builder,
+    }
+}
+
+#[tonic::async_trait]
+impl ServiceRequestRepository for DatabaseRepository {
+    async fn create_request(
+        &self,
+        requestor: &str,
+        payload: servicerequest::create::Payload,
+    ) -> Result<Option<TServiceRequest>> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "service_request_create",
+                    json!({
+                        "_requestor": requestor,
+                        "_request": payload.request_data,
+                        "_category_ids": payload.category_ids
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRequest> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    // json columns are overwritten whole, the columns must already be merged with the current row
+    async fn update_request(
+        &self,
+        request_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRequest>> {
+        let body = Value::Object(columns).to_string();
+
+        let res = self
+            .write(|db| {
+                db.from("service_request")
+                    .eq("id", request_id)
+                    .update(&body)
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRequest> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn delete_request(&self, request_id: &str) -> Result<()> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "service_request_delete",
+                    json!({ "_request_id": request_id }).to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::NO_CONTENT | StatusCode::OK => Ok(()),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_request(&self, request_id: &str) -> Result<Option<TServiceRequest>> {
+        Ok(self
+            .get_requests(&Query::eq("id", request_id))
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    // `service_request_select_bid` records the transition and the escrow in the same database
+    // transaction as the selection
+    async fn select_bid(
+        &self,
+        bid_id: &str,
+        transition: TStatusTransition,
+        escrow: TTransaction,
+    ) -> Result<Option<TServiceRequest>> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "service_request_select_bid",
+                    json!({
+                        "_request_id": transition.request_id,
+                        "_bid_id": bid_id,
+                        "_from": transition.from_status,
+                        "_to": transition.to_status,
+                        "_user_id": transition.user_id,
+                        "_memo": escrow.memo,
+                        "_entries": escrow.entries
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRequest> = res.json().await?;
+
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_requests(&self, query: &Query) -> Result<Vec<TServiceRequest>> {
+        let res = self
+            .read(|db| apply_query(db.from("service_request"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ServiceRequestBidRepository for DatabaseRepository {
+    async fn create_bid(
+        &self,
+        user_id: &str,
+        payload: servicerequestbid::create::Payload,
+    ) -> Result<Option<TServiceRequestBid>> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "bid_create",
+                    json!({
+                        "_user_id": user_id,
+                        "_request_id": payload.request_id,
+                        "_amount": payload.amount
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRequestBid> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn delete_bid(&self, bid_id: &str) -> Result<()> {
+        let res = self
+            .write(|db| db.rpc("bid_delete", json!({ "_bid_id": bid_id }).to_string()))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(()),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_selected_bid(&self, request_id: &str) -> Result<Option<TServiceRequestBid>> {
+        let res = self
+            .read(|db| {
+                db.rpc(
+                    "service_request_get_selected_bid",
+                    json!({ "_request_id": request_id }).to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRequestBid> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_bids(&self, query: &Query) -> Result<Vec<TServiceRequestBid>> {
+        let res = self
+            .read(|db| apply_query(db.from("service_request_bid"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn update_bid(
+        &self,
+        bid_id: &str,
+        mut columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRequestBid>> {
+        columns.insert("updated_at".to_string(), json!(Utc::now().to_rfc3339()));
+        let body = Value::Object(columns).to_string();
+
+        let res = self
+            .write(|db| {
+                db.from("service_request_bid")
+                    .eq("id", bid_id)
+                    .update(&body)
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRequestBid> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    // the id and creation time are left to the table defaults
+    async fn create_counter_offer(&self, offer: TCounterOffer) -> Result<TCounterOffer> {
+        let body = json!({
+            "bid_id": offer.bid_id,
+            "user_id": offer.user_id,
+            "amount": offer.amount,
+            "message": offer.message,
+            "status": offer.status
+        })
+        .to_string();
+
+        let res = self
+            .write(|db| db.from("bid_counter_offer").insert(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::CREATED => {
+                let values: Vec<TCounterOffer> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn update_counter_offer(
+        &self,
+        offer_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TCounterOffer>> {
+        let body = Value::Object(columns).to_string();
+
+        let res = self
+            .write(|db| {
+                db.from("bid_counter_offer")
+                    .eq("id", offer_id)
+                    .update(&body)
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TCounterOffer> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_counter_offers(&self, query: &Query) -> Result<Vec<TCounterOffer>> {
+        let res = self
+            .read(|db| apply_query(db.from("bid_counter_offer"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ServiceRatingRepository for DatabaseRepository {
+    async fn create_rating(
+        &self,
+        user_id: &str,
+        rated_user_id: &str,
+        role: ReputationRole,
+        payload: servicerating::create::Payload,
+    ) -> Result<Option<TServiceRating>> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "rating_create",
+                    json!({
+                        "_user_id": user_id,
+                        "_rated_user_id": rated_user_id,
+                        "_role": role as i32,
+                        "_value": payload.value,
+                        "_comment": payload.comment,
+                        "_request_id": payload.request_id
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRating> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn update_rating(
+        &self,
+        rating_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRating>> {
+        let body = Value::Object(columns).to_string();
+
+        let res = self
+            .write(|db| db.from("service_rating").eq("id", rating_id).update(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRating> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn reveal_rating(&self, rating_id: &str) -> Result<Option<TServiceRating>> {
+        let body = json!({
+            "revealed": true,
+            "revealed_at": Utc::now().to_rfc3339()
+        })
+        .to_string();
+
+        let res = self
+            .write(|db| {
+                db.from("service_rating")
+                    .eq("id", rating_id)
+                    .eq("revealed", "false")
+                    .update(&body)
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TServiceRating> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn delete_rating(&self, rating_id: &str) -> Result<()> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "rating_delete",
+                    json!({ "_rating_id": rating_id }).to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::NO_CONTENT | StatusCode::OK => Ok(()),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_ratings(&self, query: &Query) -> Result<Vec<TServiceRating>> {
+        let res = self
+            .read(|db| apply_query(db.from("service_rating"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn create_rating_version(&self, version: TRatingVersion) -> Result<TRatingVersion> {
+        let body = json!({
+            "rating_id": version.rating_id,
+            "value": version.value,
+            "comment": version.comment,
+            "edited_by": version.edited_by
+        })
+        .to_string();
+
+        let res = self
+            .write(|db| db.from("rating_version").insert(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::CREATED => {
+                let values: Vec<TRatingVersion> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_rating_versions(&self, query: &Query) -> Result<Vec<TRatingVersion>> {
+        let res = self
+            .read(|db| apply_query(db.from("rating_version"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl UserProfileRepository for DatabaseRepository {
+    async fn get_profile(&self, user_id: &str) -> Result<Option<TUserProfile>> {
+        let res = self
+            .read(|db| {
+                db.from("user_profile")
+                    .select(PROFILE_COLUMNS)
+                    .eq("user_id", user_id)
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TUserProfile> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_profiles(&self, query: &Query) -> Result<Vec<TUserProfile>> {
+        let res = self
+            .read(|db| apply_query(db.from("user_profile").select(PROFILE_COLUMNS), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn update_profile(
+        &self,
+        user_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TUserProfile>> {
+        let body = Value::Object(columns).to_string();
+
+        let res = self
+            .write(|db| {
+                db.from("user_profile")
+                    .select(PROFILE_COLUMNS)
+                    .eq("user_id", user_id)
+                    .update(&body)
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TUserProfile> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_user_ratings(&self, user_id: &str) -> Result<Vec<TServiceRating>> {
+        let res = self
+            .read(|db| {
+                db.from("service_rating")
+                    .eq("rated_user_id", user_id)
+                    .eq("revealed", "true")
+                    .eq("hidden", "false")
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl LedgerRepository for DatabaseRepository {
+    // `ledger_record_transaction` rejects transactions whose entries don't sum to zero
+    async fn record_transaction(&self, transaction: TTransaction) -> Result<TTransaction> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "ledger_record_transaction",
+                    json!({
+                        "_request_id": transaction.request_id,
+                        "_memo": transaction.memo,
+                        "_entries": transaction.entries
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TTransaction> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_balance(&self, account: &str, until: Option<DateTime<Utc>>) -> Result<f64> {
+        let res = self
+            .read(|db| {
+                db.rpc(
+                    "ledger_get_balance",
+                    json!({
+                        "_account": account,
+                        "_until": until.map(|t| t.to_rfc3339())
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_escrowed(&self, account: &str) -> Result<f64> {
+        let res = self
+            .read(|db| {
+                db.rpc(
+                    "ledger_get_escrowed",
+                    json!({ "_account": account }).to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn list_transactions(
+        &self,
+        account: &str,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<TTransaction>> {
+        let res = self
+            .read(|db| {
+                db.rpc(
+                    "ledger_list_transactions",
+                    json!({
+                        "_account": account,
+                        "_from": filter.from.map(|t| t.to_rfc3339()),
+                        "_to": filter.to.map(|t| t.to_rfc3339()),
+                        "_counterparty": filter.counterparty,
+                        "_offset": filter.offset,
+                        "_limit": filter.limit
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl RequestStatusRepository for DatabaseRepository {
+    async fn get_status_history(&self, request_id: &str) -> Result<Vec<TStatusTransition>> {
+        let res = self
+            .read(|db| {
+                // the id breaks ties like `service_request_record_transition` does
+                db.from("service_request_status")
+                    .eq("request_id", request_id)
+                    .order("created_at.asc,id.asc")
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    // `service_request_record_transition` only inserts the transition if the request's latest
+    // status is still `_from`, raising an exception otherwise
+    async fn record_transition(&self, transition: TStatusTransition) -> Result<TStatusTransition> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "service_request_record_transition",
+                    json!({
+                        "_request_id": transition.request_id,
+                        "_from": transition.from_status,
+                        "_to": transition.to_status,
+                        "_user_id": transition.user_id
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TStatusTransition> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn release_escrow(
+        &self,
+        transition: TStatusTransition,
+        to: &str,
+        memo: &str,
+    ) -> Result<TStatusTransition> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "service_request_release_escrow",
+                    json!({
+                        "_request_id": transition.request_id,
+                        "_from": transition.from_status,
+                        "_to": transition.to_status,
+                        "_user_id": transition.user_id,
+                        "_account": to,
+                        "_memo": memo
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TStatusTransition> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl MessageRepository for DatabaseRepository {
+    async fn create_message(&self, message: TMessage) -> Result<TMessage> {
+        let res = self
+            .write(|db| {
+                db.from("service_request_message").insert(
+                    json!({
+                        "request_id": message.request_id,
+                        "sender": message.sender,
+                        "body": message.body
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::CREATED => {
+                let values: Vec<TMessage> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_messages(&self, query: &Query) -> Result<Vec<TMessage>> {
+        let res = self
+            .read(|db| apply_query(db.from("service_request_message"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn mark_read(&self, request_id: &str, reader: &str) -> Result<Vec<TMessage>> {
+        let res = self
+            .write(|db| {
+                db.from("service_request_message")
+                    .eq("request_id", request_id)
+                    .neq("sender", reader)
+                    .eq("read", "false")
+                    .update(json!({ "read": true }).to_string())
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+// the sessions live in the `auth` schema, which PostgREST doesn't expose, so they go through
+// `security definer` functions
+#[tonic::async_trait]
+impl SessionRepository for DatabaseRepository {
+    async fn get_sessions(&self, user_id: &str) -> Result<Vec<TSession>> {
+        let res = self
+            .read(|db| {
+                db.rpc(
+                    "auth_list_sessions",
+                    json!({ "_user_id": user_id }).to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    // `auth_revoke_session` raises `no_data_found` when the user has no such session
+    async fn revoke_session(&self, user_id: &str, session_id: &str) -> Result<()> {
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "auth_revoke_session",
+                    json!({
+                        "_user_id": user_id,
+                        "_session_id": session_id
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::NO_CONTENT | StatusCode::OK => Ok(()),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl VerificationRepository for DatabaseRepository {
+    async fn get_verification(&self, user_id: &str) -> Result<Option<TVerification>> {
+        Ok(self
+            .get_verifications(&Query::eq("user_id", user_id))
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    async fn get_verifications(&self, query: &Query) -> Result<Vec<TVerification>> {
+        let res = self
+            .read(|db| apply_query(db.from("user_verification"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => timestamps_from_rows(res.json().await?, VERIFICATION_TIMESTAMPS),
+            _ => Err(error(res).await),
+        }
+    }
+
+    // `user_id` is the primary key, so an existing verification is overwritten
+    async fn save_verification(&self, verification: TVerification) -> Result<TVerification> {
+        let body = timestamps_to_row(&verification, VERIFICATION_TIMESTAMPS)?;
+
+        let res = self
+            .write(|db| db.from("user_verification").upsert(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK | StatusCode::CREATED => {
+                let values: Vec<TVerification> =
+                    timestamps_from_rows(res.json().await?, VERIFICATION_TIMESTAMPS)?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SanctionRepository for DatabaseRepository {
+    async fn get_sanction(&self, user_id: &str) -> Result<Option<TSanction>> {
+        Ok(self
+            .get_sanctions(&Query::eq("user_id", user_id))
+            .await?
+            .into_iter()
+            .next())
+    }
+
+    async fn get_sanctions(&self, query: &Query) -> Result<Vec<TSanction>> {
+        let res = self
+            .read(|db| apply_query(db.from("user_sanction"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => timestamps_from_rows(res.json().await?, SANCTION_TIMESTAMPS),
+            _ => Err(error(res).await),
+        }
+    }
+
+    // `user_id` is the primary key, so an existing sanction is overwritten
+    async fn save_sanction(&self, sanction: TSanction) -> Result<TSanction> {
+        let body = timestamps_to_row(&sanction, SANCTION_TIMESTAMPS)?;
+
+        let res = self
+            .write(|db| db.from("user_sanction").upsert(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK | StatusCode::CREATED => {
+                let values: Vec<TSanction> =
+                    timestamps_from_rows(res.json().await?, SANCTION_TIMESTAMPS)?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn delete_sanction(&self, user_id: &str) -> Result<()> {
+        let res = self
+            .write(|db| db.from("user_sanction").eq("user_id", user_id).delete())
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<Value> = res.json().await?;
+
+                if values.is_empty() {
+                    Err(Error::NotFound)
+                } else {
+                    Ok(())
+                }
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+// the `audit_event` table only grants insert and select, so the log can't be rewritten
+#[tonic::async_trait]
+impl AuditRepository for DatabaseRepository {
+    async fn record_audit_event(&self, event: TAuditEvent) -> Result<TAuditEvent> {
+        let body = json!({
+            "user_id": event.user_id,
+            "method": event.method,
+            "entity": event.entity,
+            "target_id": event.target_id,
+            "before": event.before,
+            "after": event.after,
+            "reason": event.reason
+        })
+        .to_string();
+
+        let res = self
+            .write(|db| db.from("audit_event").insert(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::CREATED => {
+                let values: Vec<TAuditEvent> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_audit_events(&self, query: &Query) -> Result<Vec<TAuditEvent>> {
+        let res = self
+            .read(|db| apply_query(db.from("audit_event"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl StatsRepository for DatabaseRepository {
+    // counting is left to the database rather than fetching every row
+    async fn get_stats(&self) -> Result<TStats> {
+        let res = self.read(|db| db.rpc("admin_stats", "{}")).await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ProviderRepository for DatabaseRepository {
+    // `provider_stats` is a view aggregating service_request_bid per user
+    async fn get_provider_stats(&self, query: &Query) -> Result<Vec<ProviderStats>> {
+        let res = self
+            .read(|db| apply_query(db.from("provider_stats"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ReputationRepository for DatabaseRepository {
+    async fn get_reputations(&self, query: &Query) -> Result<Vec<ReputationTally>> {
+        let res = self
+            .read(|db| apply_query(db.from("user_reputation"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    // `reputation_adjust` locks the tally's row, the score is
+    // (prior_weight * prior_mean + sum) / (prior_weight + count)
+    async fn adjust_reputation(
+        &self,
+        user_id: &str,
+        role: ReputationRole,
+        added: Option<i32>,
+        removed: Option<i32>,
+    ) -> Result<ReputationTally> {
+        let body = json!({
+            "_user_id": user_id,
+            "_role": role as i32,
+            "_added": added,
+            "_removed": removed,
+            "_scale_min": RATING_SCALE.start(),
+            "_scale_max": RATING_SCALE.end(),
+            "_prior_mean": PRIOR_MEAN,
+            "_prior_weight": PRIOR_WEIGHT
+        })
+        .to_string();
+
+        let res = self.write(|db| db.rpc("reputation_adjust", &body)).await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<ReputationTally> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl ModerationRepository for DatabaseRepository {
+    // the id and creation time are left to the table defaults
+    async fn create_case(&self, case: TModerationCase) -> Result<TModerationCase> {
+        let body = json!({
+            "rating_id": case.rating_id,
+            "kind": case.kind,
+            "user_id": case.user_id,
+            "reason": case.reason,
+            "status": case.status
+        })
+        .to_string();
+
+        let res = self
+            .write(|db| db.from("moderation_case").insert(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::CREATED => {
+                let values: Vec<TModerationCase> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn update_case(
+        &self,
+        case_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TModerationCase>> {
+        let body = Value::Object(columns).to_string();
+
+        let res = self
+            .write(|db| db.from("moderation_case").eq("id", case_id).update(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TModerationCase> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_cases(&self, query: &Query) -> Result<Vec<TModerationCase>> {
+        let res = self
+            .read(|db| apply_query(db.from("moderation_case"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl TaxonomyRepository for DatabaseRepository {
+    async fn create_category(&self, category: TCategory) -> Result<TCategory> {
+        let body = json!({
+            "parent_id": category.parent_id,
+            "name": category.name,
+            "description": category.description
+        })
+        .to_string();
+
+        let res = self.write(|db| db.from("category").insert(&body)).await?;
+
+        match res.status() {
+            StatusCode::CREATED => {
+                let values: Vec<TCategory> = res.json().await?;
+                values.into_iter().next().ok_or(Error::Unknown)
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn update_category(
+        &self,
+        category_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TCategory>> {
+        let body = Value::Object(columns).to_string();
+
+        let res = self
+            .write(|db| db.from("category").eq("id", category_id).update(&body))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => {
+                let values: Vec<TCategory> = res.json().await?;
+                Ok(values.into_iter().next())
+            }
+
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn delete_category(&self, category_id: &str) -> Result<()> {
+        let res = self
+            .write(|db| db.from("category").eq("id", category_id).delete())
+            .await?;
+
+        match res.status() {
+            StatusCode::NO_CONTENT | StatusCode::OK => Ok(()),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_categories(&self, query: &Query) -> Result<Vec<TCategory>> {
+        let res = self
+            .read(|db| apply_query(db.from("category"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn set_skills(&self, user_id: &str, skills: Vec<TSkill>) -> Result<Vec<TSkill>> {
+        let skills: Vec<Value> = skills
+            .into_iter()
+            .map(|skill| {
+                json!({
+                    "category_id": skill.category_id,
+                    "proficiency": skill.proficiency
+                })
+            })
+            .collect();
+
+        let res = self
+            .write(|db| {
+                db.rpc(
+                    "user_skill_set",
+                    json!({
+                        "_user_id": user_id,
+                        "_skills": skills
+                    })
+                    .to_string(),
+                )
+            })
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+
+    async fn get_skills(&self, query: &Query) -> Result<Vec<TSkill>> {
+        let res = self
+            .read(|db| apply_query(db.from("user_skill"), query))
+            .await?;
+
+        match res.status() {
+            StatusCode::OK => Ok(res.json().await?),
+            _ => Err(error(res).await),
+        }
+    }
+}
@@ -0,0 +1,1338 @@
+// In-memory repository that mirrors the semantics of the database functions
+// (`service_request_create`, `bid_create`, `service_request_select_bid` and
+// `rating_create`).
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::{Map, Value};
+
+use super::{
+    AuditRepository, Filter, LedgerRepository, MessageRepository, ModerationRepository,
+    ProviderRepository, ProviderStats, Query, ReputationRepository, ReputationTally,
+    RequestStatusRepository, SanctionRepository, ServiceRatingRepository,
+    ServiceRequestBidRepository, ServiceRequestRepository, SessionRepository, StatsRepository,
+    TaxonomyRepository, TransactionFilter, UserProfileRepository, VerificationRepository,
+};
+use crate::error::{Error, Result};
+use crate::proto::account::TUserProfile;
+use crate::proto::auth::TSession;
+use crate::proto::timebank::admin::{
+    t_stats, SanctionKind, TAuditEvent, TRatingVersion, TSanction, TStats,
+};
+use crate::proto::timebank::ledger::TTransaction;
+use crate::proto::timebank::messaging::TMessage;
+use crate::proto::timebank::moderation::TModerationCase;
+use crate::proto::timebank::reputation::ReputationRole;
+use crate::proto::timebank::servicerating::{self, TServiceRating};
+use crate::proto::timebank::servicerequest::{self, TServiceRequest};
+use crate::proto::timebank::servicerequestbid::{
+    self, BidStatus, TCounterOffer, TServiceRequestBid,
+};
+use crate::proto::timebank::servicerequeststatus::{RequestStatus, TStatusTransition};
+use crate::proto::timebank::taxonomy::{TCategory, TSkill};
+use crate::proto::timebank::verification::TVerification;
+use crate::services::ledger;
+
+#[derive(Default)]
+struct State {
+    requests: Vec<TServiceRequest>,
+    bids: Vec<TServiceRequestBid>,
+    counter_offers: Vec<TCounterOffer>,
+    ratings: Vec<TServiceRating>,
+    rating_versions: Vec<TRatingVersion>,
+    profiles: Vec<TUserProfile>,
+    transactions: Vec<TTransaction>,
+    transitions: Vec<TStatusTransition>,
+    messages: Vec<TMessage>,
+    verifications: Vec<TVerification>,
+    sanctions: Vec<TSanction>,
+    audit_events: Vec<TAuditEvent>,
+    reputations: Vec<ReputationTally>,
+    cases: Vec<TModerationCase>,
+    categories: Vec<TCategory>,
+    skills: Vec<TSkill>,
+    // request id -> selected bid id
+    selected_bids: HashMap<String, String>,
+}
+
+impl State {
+    fn request(&self, request_id: &str) -> Result<&TServiceRequest> {
+        self.requests
+            .iter()
+            .find(|r| r.id == request_id)
+            .ok_or(Error::NotFound)
+    }
+
+    fn selected_bid(&self, request_id: &str) -> Option<&TServiceRequestBid> {
+        let bid_id = self.selected_bids.get(request_id)?;
+        self.bids.iter().find(|b| &b.id == bid_id)
+    }
+
+    // fails like `service_request_record_transition` if the request has moved on from the
+    // transition's `from_status`
+    fn check_transition(&self, transition: &TStatusTransition) -> Result<()> {
+        let current = self
+            .transitions
+            .iter()
+            .rev()
+            .find(|t| t.request_id == transition.request_id)
+            .map(|t| t.to_status());
+
+        let expected = transition.from_status();
+
+        // requests without any history are either being created or predate status tracking
+        let up_to_date = match current {
+            Some(current) => current == expected,
+            None => matches!(expected, RequestStatus::Unspecified | RequestStatus::Open),
+        };
+
+        if !up_to_date {
+            return Err(Error::InvalidTransition(
+                current.unwrap_or(RequestStatus::Open),
+                transition.to_status(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn push_transition(&mut self, transition: TStatusTransition) -> TStatusTransition {
+        let transition = TStatusTransition {
+            id: new_id(),
+            created_at: now(),
+            ..transition
+        };
+
+        if let Some(request) = self
+            .requests
+            .iter_mut()
+            .find(|r| r.id == transition.request_id)
+        {
+            request.status = transition.to_status;
+        }
+
+        self.transitions.push(transition.clone());
+
+        transition
+    }
+
+    fn balance(&self, account: &str) -> f64 {
+        self.transactions
+            .iter()
+            .flat_map(|t| t.entries.iter())
+            .filter(|e| e.account == account)
+            .map(|e| e.amount)
+            .sum()
+    }
+
+    // fails like `ledger_record_transaction` if the entries don't balance or would overdraw an
+    // account, the `system:` ones credits are issued from aside
+    fn check_transaction(&self, transaction: &TTransaction) -> Result<()> {
+        let sum: f64 = transaction.entries.iter().map(|e| e.amount).sum();
+
+        if transaction.entries.len() < 2 || sum.abs() > LEDGER_EPSILON {
+            return Err(Error::UnbalancedTransaction);
+        }
+
+        let overdrawn = transaction
+            .entries
+            .iter()
+            .filter(|e| e.amount < 0.0 && !e.account.starts_with(ledger::SYSTEM_ACCOUNT_PREFIX))
+            .any(|e| {
+                let change: f64 = transaction
+                    .entries
+                    .iter()
+                    .filter(|other| other.account == e.account)
+                    .map(|other| other.amount)
+                    .sum();
+
+                self.balance(&e.account) + change < -LEDGER_EPSILON
+            });
+
+        if overdrawn {
+            return Err(Error::InsufficientBalance);
+        }
+
+        Ok(())
+    }
+
+    fn push_transaction(&mut self, transaction: TTransaction) -> TTransaction {
+        let transaction = TTransaction {
+            id: new_id(),
+            created_at: now(),
+            ..transaction
+        };
+
+        self.transactions.push(transaction.clone());
+
+        transaction
+    }
+
+    // the profile with the skills the database embeds in it
+    fn profile(&self, profile: &TUserProfile) -> TUserProfile {
+        TUserProfile {
+            skills: self
+                .skills
+                .iter()
+                .filter(|s| s.user_id == profile.user_id)
+                .cloned()
+                .collect(),
+            ..profile.clone()
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct MemoryRepository {
+    state: Mutex<State>,
+}
+
+impl MemoryRepository {
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("MEMORY REPOSITORY LOCK POISONED")
+    }
+}
+
+fn new_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339()
+}
+
+fn created_at(transaction: &TTransaction) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(&transaction.created_at)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+// amounts are hours, anything below this is floating point noise
+const LEDGER_EPSILON: f64 = 1e-9;
+
+static NULL: Value = Value::Null;
+
+// value of the column, fields of json columns are addressed with a dot and are null if missing
+fn column<'a>(row: &'a Value, column: &str) -> Result<&'a Value> {
+    let mut path = column.split('.');
+
+    let mut value = path
+        .next()
+        .and_then(|c| row.get(c))
+        .ok_or_else(|| Error::InvalidColumn(column.to_string()))?;
+
+    for field in path {
+        value = value.get(field).unwrap_or(&NULL);
+    }
+
+    Ok(value)
+}
+
+// compares the value with a filter given as a string, like postgres would after casting it
+fn compare(value: &Value, other: &str) -> Option<Ordering> {
+    match value {
+        Value::String(s) => Some(s.as_str().cmp(other)),
+        Value::Number(n) => n.as_f64()?.partial_cmp(&other.parse().ok()?),
+        Value::Bool(b) => Some(b.cmp(&other.parse().ok()?)),
+        _ => None,
+    }
+}
+
+// sql `like` pattern, PostgREST also accepts `*` in place of `%`
+fn like(text: &[char], pattern: &[char]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some(('%' | '*', rest)) => (0..=text.len()).any(|i| like(&text[i..], rest)),
+        Some((c, rest)) => {
+            matches!(text.split_first(), Some((t, text)) if (*c == '_' || t == c) && like(text, rest))
+        }
+    }
+}
+
+fn matches_filter(value: &Value, filter: &Filter) -> bool {
+    let is = |other: &str, expected: &[Ordering]| matches!(compare(value, other), Some(ordering) if expected.contains(&ordering));
+
+    match filter {
+        Filter::Eq(other) => is(other, &[Ordering::Equal]),
+        Filter::Neq(other) => is(other, &[Ordering::Less, Ordering::Greater]),
+        Filter::Gt(other) => is(other, &[Ordering::Greater]),
+        Filter::Lt(other) => is(other, &[Ordering::Less]),
+        Filter::In(others) => others.iter().any(|other| is(other, &[Ordering::Equal])),
+        Filter::Ilike(pattern) => match value {
+            Value::String(s) => {
+                let text: Vec<char> = s.to_lowercase().chars().collect();
+                let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+                like(&text, &pattern)
+            }
+            _ => false,
+        },
+        Filter::Range(lower, upper) => {
+            is(lower, &[Ordering::Equal, Ordering::Greater])
+                && is(upper, &[Ordering::Equal, Ordering::Less])
+        }
+        Filter::Overlaps(others) => match value {
+            Value::Array(values) => values.iter().any(|value| {
+                others
+                    .iter()
+                    .any(|other| matches!(compare(value, other), Some(Ordering::Equal)))
+            }),
+            _ => false,
+        },
+    }
+}
+
+// ascending order with nulls last, like postgres
+fn order_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a
+            .as_f64()
+            .partial_cmp(&b.as_f64())
+            .unwrap_or(Ordering::Equal),
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Null, Value::Null) => Ordering::Equal,
+        (Value::Null, _) => Ordering::Greater,
+        (_, Value::Null) => Ordering::Less,
+        (a, b) => a.to_string().cmp(&b.to_string()),
+    }
+}
+
+// behaves like the PostgREST request built by the database repository, rejecting unknown columns
+fn query_rows<T: Serialize + Clone>(rows: &[T], query: &Query) -> Result<Vec<T>> {
+    let mut matches = Vec::new();
+
+    for row in rows {
+        let value = serde_json::to_value(row)?;
+
+        let mut matched = true;
+
+        for (name, filter) in &query.filters {
+            matched &= matches_filter(column(&value, name)?, filter);
+        }
+
+        if !matched {
+            continue;
+        }
+
+        let mut keys = Vec::with_capacity(query.order.len());
+
+        for (name, _) in &query.order {
+            keys.push(column(&value, name)?.clone());
+        }
+
+        matches.push((keys, row.clone()));
+    }
+
+    matches.sort_by(|(a, _), (b, _)| {
+        a.iter()
+            .zip(b)
+            .zip(&query.order)
+            .map(|((a, b), (_, descending))| match order_values(a, b) {
+                ordering if *descending => ordering.reverse(),
+                ordering => ordering,
+            })
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or(Ordering::Equal)
+    });
+
+    Ok(matches
+        .into_iter()
+        .map(|(_, row)| row)
+        .skip(query.offset)
+        .take(query.limit.unwrap_or(usize::MAX))
+        .collect())
+}
+
+// overwrites the given columns, like a PostgREST PATCH
+fn apply_update<T: Serialize + DeserializeOwned>(
+    row: &mut T,
+    update: Map<String, Value>,
+) -> Result<()> {
+    let mut value = serde_json::to_value(&*row)?;
+
+    let columns = value.as_object_mut().ok_or(Error::Unknown)?;
+
+    for (column, v) in update {
+        if !columns.contains_key(&column) {
+            return Err(Error::InvalidPayload);
+        }
+
+        columns.insert(column, v);
+    }
+
+    *row = serde_json::from_value(value).map_err(|_| Error::InvalidPayload)?;
+
+    Ok(())
+}
+
+#[tonic::async_trait]
+impl ServiceRequestRepository for MemoryRepository {
+    async fn create_request(
+        &self,
+        requestor: &str,
+        payload: servicerequest::create::Payload,
+    ) -> Result<Option<TServiceRequest>> {
+        let request = TServiceRequest {
+            id: new_id(),
+            requestor: requestor.to_string(),
+            request_data: payload.request_data,
+            category_ids: payload.category_ids,
+            status: RequestStatus::Unspecified as i32,
+            created_at: now(),
+        };
+
+        self.state().requests.push(request.clone());
+
+        Ok(Some(request))
+    }
+
+    async fn update_request(
+        &self,
+        request_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRequest>> {
+        let mut state = self.state();
+
+        match state.requests.iter_mut().find(|r| r.id == request_id) {
+            Some(request) => {
+                apply_update(request, columns)?;
+                Ok(Some(request.clone()))
+            }
+
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_request(&self, request_id: &str) -> Result<()> {
+        let mut state = self.state();
+
+        state.request(request_id)?;
+
+        state.requests.retain(|r| r.id != request_id);
+        state.bids.retain(|b| b.request_id != request_id);
+
+        let State {
+            bids,
+            counter_offers,
+            ..
+        } = &mut *state;
+        counter_offers.retain(|o| bids.iter().any(|b| b.id == o.bid_id));
+
+        state.ratings.retain(|r| r.request_id != request_id);
+        state.selected_bids.remove(request_id);
+
+        Ok(())
+    }
+
+    async fn get_request(&self, request_id: &str) -> Result<Option<TServiceRequest>> {
+        Ok(self
+            .state()
+            .requests
+            .iter()
+            .find(|r| r.id == request_id)
+            .cloned())
+    }
+
+    async fn select_bid(
+        &self,
+        bid_id: &str,
+        transition: TStatusTransition,
+        escrow: TTransaction,
+    ) -> Result<Option<TServiceRequest>> {
+        let mut state = self.state();
+        let request_id = transition.request_id.clone();
+
+        state.request(&request_id)?;
+
+        if !state
+            .bids
+            .iter()
+            .any(|b| b.id == bid_id && b.request_id == request_id)
+        {
+            return Err(Error::NotFound);
+        }
+
+        // everything is checked before anything is written, like the database function either
+        // all of it takes effect or none
+        state.check_transition(&transition)?;
+
+        if state.selected_bids.contains_key(&request_id) {
+            return Err(Error::BidAlreadySelected);
+        }
+
+        state.check_transaction(&escrow)?;
+
+        state.push_transition(transition);
+        state.push_transaction(escrow);
+        state
+            .selected_bids
+            .insert(request_id.clone(), bid_id.to_string());
+
+        Ok(Some(state.request(&request_id)?.clone()))
+    }
+
+    async fn get_requests(&self, query: &Query) -> Result<Vec<TServiceRequest>> {
+        query_rows(&self.state().requests, query)
+    }
+}
+
+#[tonic::async_trait]
+impl ServiceRequestBidRepository for MemoryRepository {
+    async fn create_bid(
+        &self,
+        user_id: &str,
+        payload: servicerequestbid::create::Payload,
+    ) -> Result<Option<TServiceRequestBid>> {
+        let mut state = self.state();
+
+        let request = state.request(&payload.request_id)?;
+
+        if request.requestor == user_id {
+            return Err(Error::OwnRequest);
+        }
+
+        if state.selected_bids.contains_key(&payload.request_id) {
+            return Err(Error::BidAlreadySelected);
+        }
+
+        if state
+            .bids
+            .iter()
+            // withdrawn bids don't keep the bidder from bidding again
+            .any(|b| {
+                b.request_id == payload.request_id
+                    && b.user_id == user_id
+                    && b.status() != BidStatus::Withdrawn
+            })
+        {
+            return Err(Error::AlreadyExists);
+        }
+
+        let bid = TServiceRequestBid {
+            id: new_id(),
+            user_id: user_id.to_string(),
+            request_id: payload.request_id,
+            amount: payload.amount,
+            created_at: now(),
+            status: BidStatus::Active as i32,
+            updated_at: now(),
+        };
+
+        state.bids.push(bid.clone());
+
+        Ok(Some(bid))
+    }
+
+    async fn delete_bid(&self, bid_id: &str) -> Result<()> {
+        let mut state = self.state();
+
+        let bid = state
+            .bids
+            .iter()
+            .find(|b| b.id == bid_id)
+            .ok_or(Error::NotFound)?;
+
+        if state.selected_bids.get(&bid.request_id) == Some(&bid.id) {
+            return Err(Error::BidAlreadySelected);
+        }
+
+        state.bids.retain(|b| b.id != bid_id);
+        state.counter_offers.retain(|o| o.bid_id != bid_id);
+
+        Ok(())
+    }
+
+    async fn get_selected_bid(&self, request_id: &str) -> Result<Option<TServiceRequestBid>> {
+        Ok(self.state().selected_bid(request_id).cloned())
+    }
+
+    async fn get_bids(&self, query: &Query) -> Result<Vec<TServiceRequestBid>> {
+        query_rows(&self.state().bids, query)
+    }
+
+    async fn update_bid(
+        &self,
+        bid_id: &str,
+        mut columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRequestBid>> {
+        let mut state = self.state();
+
+        columns.insert("updated_at".to_string(), Value::String(now()));
+
+        match state.bids.iter_mut().find(|b| b.id == bid_id) {
+            Some(bid) => {
+                apply_update(bid, columns)?;
+                Ok(Some(bid.clone()))
+            }
+
+            None => Ok(None),
+        }
+    }
+
+    async fn create_counter_offer(&self, offer: TCounterOffer) -> Result<TCounterOffer> {
+        let mut state = self.state();
+
+        if !state.bids.iter().any(|b| b.id == offer.bid_id) {
+            return Err(Error::NotFound);
+        }
+
+        let offer = TCounterOffer {
+            id: new_id(),
+            created_at: now(),
+            ..offer
+        };
+
+        state.counter_offers.push(offer.clone());
+
+        Ok(offer)
+    }
+
+    async fn update_counter_offer(
+        &self,
+        offer_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TCounterOffer>> {
+        let mut state = self.state();
+
+        match state.counter_offers.iter_mut().find(|o| o.id == offer_id) {
+            Some(offer) => {
+                apply_update(offer, columns)?;
+                Ok(Some(offer.clone()))
+            }
+
+            None => Ok(None),
+        }
+    }
+
+    async fn get_counter_offers(&self, query: &Query) -> Result<Vec<TCounterOffer>> {
+        query_rows(&self.state().counter_offers, query)
+    }
+}
+
+#[tonic::async_trait]
+impl ServiceRatingRepository for MemoryRepository {
+    async fn create_rating(
+        &self,
+        user_id: &str,
+        rated_user_id: &str,
+        role: ReputationRole,
+        payload: servicerating::create::Payload,
+    ) -> Result<Option<TServiceRating>> {
+        let mut state = self.state();
+
+        let requestor = state.request(&payload.request_id)?.requestor.clone();
+        let provider = state
+            .selected_bid(&payload.request_id)
+            .map(|b| b.user_id.clone())
+            .unwrap_or_default();
+
+        // the requestor and the provider rate each other, once the service is completed
+        let rates_other_side = (user_id == requestor && rated_user_id == provider)
+            || (user_id == provider && rated_user_id == requestor);
+
+        if provider.is_empty() || !rates_other_side {
+            return Err(Error::PermissionDenied(
+                "ONLY THE REQUESTOR AND THE PROVIDER CAN RATE THE SERVICE".into(),
+            ));
+        }
+
+        if state.request(&payload.request_id)?.status() != RequestStatus::Completed {
+            return Err(Error::ServiceNotCompleted);
+        }
+
+        if state
+            .ratings
+            .iter()
+            .any(|r| r.request_id == payload.request_id && r.user_id == user_id)
+        {
+            return Err(Error::AlreadyExists);
+        }
+
+        let rating = TServiceRating {
+            id: new_id(),
+            user_id: user_id.to_string(),
+            request_id: payload.request_id,
+            value: payload.value,
+            comment: payload.comment,
+            created_at: now(),
+            rated_user_id: rated_user_id.to_string(),
+            role: role as i32,
+            ..Default::default()
+        };
+
+        state.ratings.push(rating.clone());
+
+        Ok(Some(rating))
+    }
+
+    async fn update_rating(
+        &self,
+        rating_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TServiceRating>> {
+        let mut state = self.state();
+
+        match state.ratings.iter_mut().find(|r| r.id == rating_id) {
+            Some(rating) => {
+                apply_update(rating, columns)?;
+                Ok(Some(rating.clone()))
+            }
+
+            None => Ok(None),
+        }
+    }
+
+    async fn reveal_rating(&self, rating_id: &str) -> Result<Option<TServiceRating>> {
+        let mut state = self.state();
+
+        match state
+            .ratings
+            .iter_mut()
+            .find(|r| r.id == rating_id && !r.revealed)
+        {
+            Some(rating) => {
+                rating.revealed = true;
+                rating.revealed_at = now();
+                Ok(Some(rating.clone()))
+            }
+
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_rating(&self, rating_id: &str) -> Result<()> {
+        let mut state = self.state();
+
+        if !state.ratings.iter().any(|r| r.id == rating_id) {
+            return Err(Error::NotFound);
+        }
+
+        state.ratings.retain(|r| r.id != rating_id);
+
+        Ok(())
+    }
+
+    async fn get_ratings(&self, query: &Query) -> Result<Vec<TServiceRating>> {
+        query_rows(&self.state().ratings, query)
+    }
+
+    // versions outlive the rating, for the admins to see what it said
+    async fn create_rating_version(&self, version: TRatingVersion) -> Result<TRatingVersion> {
+        let version = TRatingVersion {
+            id: new_id(),
+            created_at: now(),
+            ..version
+        };
+
+        self.state().rating_versions.push(version.clone());
+
+        Ok(version)
+    }
+
+    async fn get_rating_versions(&self, query: &Query) -> Result<Vec<TRatingVersion>> {
+        query_rows(&self.state().rating_versions, query)
+    }
+}
+
+#[tonic::async_trait]
+impl UserProfileRepository for MemoryRepository {
+    async fn get_profile(&self, user_id: &str) -> Result<Option<TUserProfile>> {
+        let state = self.state();
+
+        Ok(state
+            .profiles
+            .iter()
+            .find(|p| p.user_id == user_id)
+            .map(|p| state.profile(p)))
+    }
+
+    async fn get_profiles(&self, query: &Query) -> Result<Vec<TUserProfile>> {
+        let state = self.state();
+
+        Ok(query_rows(&state.profiles, query)?
+            .iter()
+            .map(|p| state.profile(p))
+            .collect())
+    }
+
+    async fn update_profile(
+        &self,
+        user_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TUserProfile>> {
+        let mut state = self.state();
+
+        // profiles are created on sign up in the database, do the same on first update here
+        if !state.profiles.iter().any(|p| p.user_id == user_id) {
+            state.profiles.push(TUserProfile {
+                user_id: user_id.to_string(),
+                ..Default::default()
+            });
+
+            state.transactions.push(TTransaction {
+                id: new_id(),
+                created_at: now(),
+                ..ledger::opening_balance(user_id)
+            });
+        }
+
+        let profile = state
+            .profiles
+            .iter_mut()
+            .find(|p| p.user_id == user_id)
+            .expect("PROFILE INSERTED ABOVE");
+
+        apply_update(profile, columns)?;
+        let profile = profile.clone();
+
+        Ok(Some(state.profile(&profile)))
+    }
+
+    async fn get_user_ratings(&self, user_id: &str) -> Result<Vec<TServiceRating>> {
+        Ok(self
+            .state()
+            .ratings
+            .iter()
+            .filter(|r| r.rated_user_id == user_id && r.revealed && !r.hidden)
+            .cloned()
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl LedgerRepository for MemoryRepository {
+    async fn record_transaction(&self, transaction: TTransaction) -> Result<TTransaction> {
+        let mut state = self.state();
+
+        state.check_transaction(&transaction)?;
+
+        Ok(state.push_transaction(transaction))
+    }
+
+    async fn get_balance(&self, account: &str, until: Option<DateTime<Utc>>) -> Result<f64> {
+        Ok(self
+            .state()
+            .transactions
+            .iter()
+            .filter(|t| match until {
+                Some(until) => created_at(t) < Some(until),
+                None => true,
+            })
+            .flat_map(|t| t.entries.iter())
+            .filter(|e| e.account == account)
+            .map(|e| e.amount)
+            .sum())
+    }
+
+    async fn get_escrowed(&self, account: &str) -> Result<f64> {
+        let state = self.state();
+
+        let escrow_accounts: HashSet<&str> = state
+            .transactions
+            .iter()
+            .filter(|t| t.entries.iter().any(|e| e.account == account))
+            .flat_map(|t| t.entries.iter())
+            .map(|e| e.account.as_str())
+            .filter(|account| account.starts_with(ledger::ESCROW_ACCOUNT_PREFIX))
+            .collect();
+
+        Ok(state
+            .transactions
+            .iter()
+            .flat_map(|t| t.entries.iter())
+            .filter(|e| escrow_accounts.contains(e.account.as_str()))
+            .map(|e| e.amount)
+            .sum())
+    }
+
+    async fn list_transactions(
+        &self,
+        account: &str,
+        filter: &TransactionFilter,
+    ) -> Result<Vec<TTransaction>> {
+        let has_entry =
+            |t: &TTransaction, account: &str| t.entries.iter().any(|e| e.account == account);
+
+        let matches = |t: &TTransaction| {
+            let created_at = created_at(t);
+
+            has_entry(t, account)
+                && !matches!(filter.from, Some(from) if created_at < Some(from))
+                && !matches!(filter.to, Some(to) if created_at >= Some(to))
+                && !matches!(filter.counterparty.as_deref(), Some(c) if !has_entry(t, c))
+        };
+
+        Ok(self
+            .state()
+            .transactions
+            .iter()
+            .filter(|t| matches(t))
+            .skip(filter.offset)
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl RequestStatusRepository for MemoryRepository {
+    async fn get_status_history(&self, request_id: &str) -> Result<Vec<TStatusTransition>> {
+        Ok(self
+            .state()
+            .transitions
+            .iter()
+            .filter(|t| t.request_id == request_id)
+            .cloned()
+            .collect())
+    }
+
+    async fn record_transition(&self, transition: TStatusTransition) -> Result<TStatusTransition> {
+        let mut state = self.state();
+
+        state.check_transition(&transition)?;
+
+        Ok(state.push_transition(transition))
+    }
+
+    async fn release_escrow(
+        &self,
+        transition: TStatusTransition,
+        to: &str,
+        memo: &str,
+    ) -> Result<TStatusTransition> {
+        let mut state = self.state();
+
+        state.check_transition(&transition)?;
+
+        let escrow = ledger::escrow_account(&transition.request_id);
+        let held = state.balance(&escrow);
+
+        if held > 0.0 {
+            state.push_transaction(ledger::transfer(
+                &escrow,
+                to,
+                held,
+                &transition.request_id,
+                memo,
+            ));
+        }
+
+        Ok(state.push_transition(transition))
+    }
+}
+
+#[tonic::async_trait]
+impl MessageRepository for MemoryRepository {
+    async fn create_message(&self, message: TMessage) -> Result<TMessage> {
+        let message = TMessage {
+            id: new_id(),
+            read: false,
+            created_at: now(),
+            ..message
+        };
+
+        self.state().messages.push(message.clone());
+
+        Ok(message)
+    }
+
+    async fn get_messages(&self, query: &Query) -> Result<Vec<TMessage>> {
+        query_rows(&self.state().messages, query)
+    }
+
+    async fn mark_read(&self, request_id: &str, reader: &str) -> Result<Vec<TMessage>> {
+        let mut state = self.state();
+
+        Ok(state
+            .messages
+            .iter_mut()
+            .filter(|m| m.request_id == request_id && m.sender != reader && !m.read)
+            .map(|m| {
+                m.read = true;
+                m.clone()
+            })
+            .collect())
+    }
+}
+
+// sessions are kept by GoTrue, which this store can't see, so every user has none
+#[tonic::async_trait]
+impl SessionRepository for MemoryRepository {
+    async fn get_sessions(&self, _user_id: &str) -> Result<Vec<TSession>> {
+        Ok(Vec::new())
+    }
+
+    async fn revoke_session(&self, _user_id: &str, _session_id: &str) -> Result<()> {
+        Err(Error::NotFound)
+    }
+}
+
+#[tonic::async_trait]
+impl VerificationRepository for MemoryRepository {
+    async fn get_verification(&self, user_id: &str) -> Result<Option<TVerification>> {
+        Ok(self
+            .state()
+            .verifications
+            .iter()
+            .find(|v| v.user_id == user_id)
+            .cloned())
+    }
+
+    async fn get_verifications(&self, query: &Query) -> Result<Vec<TVerification>> {
+        query_rows(&self.state().verifications, query)
+    }
+
+    async fn save_verification(&self, verification: TVerification) -> Result<TVerification> {
+        let mut state = self.state();
+
+        state
+            .verifications
+            .retain(|v| v.user_id != verification.user_id);
+        state.verifications.push(verification.clone());
+
+        Ok(verification)
+    }
+}
+
+#[tonic::async_trait]
+impl SanctionRepository for MemoryRepository {
+    async fn get_sanction(&self, user_id: &str) -> Result<Option<TSanction>> {
+        Ok(self
+            .state()
+            .sanctions
+            .iter()
+            .find(|s| s.user_id == user_id)
+            .cloned())
+    }
+
+    async fn get_sanctions(&self, query: &Query) -> Result<Vec<TSanction>> {
+        query_rows(&self.state().sanctions, query)
+    }
+
+    async fn save_sanction(&self, sanction: TSanction) -> Result<TSanction> {
+        let mut state = self.state();
+
+        state.sanctions.retain(|s| s.user_id != sanction.user_id);
+        state.sanctions.push(sanction.clone());
+
+        Ok(sanction)
+    }
+
+    async fn delete_sanction(&self, user_id: &str) -> Result<()> {
+        let mut state = self.state();
+
+        if !state.sanctions.iter().any(|s| s.user_id == user_id) {
+            return Err(Error::NotFound);
+        }
+
+        state.sanctions.retain(|s| s.user_id != user_id);
+
+        Ok(())
+    }
+}
+
+#[tonic::async_trait]
+impl AuditRepository for MemoryRepository {
+    async fn record_audit_event(&self, event: TAuditEvent) -> Result<TAuditEvent> {
+        let event = TAuditEvent {
+            id: new_id(),
+            created_at: now(),
+            ..event
+        };
+
+        self.state().audit_events.push(event.clone());
+
+        Ok(event)
+    }
+
+    async fn get_audit_events(&self, query: &Query) -> Result<Vec<TAuditEvent>> {
+        query_rows(&self.state().audit_events, query)
+    }
+}
+
+#[tonic::async_trait]
+impl StatsRepository for MemoryRepository {
+    async fn get_stats(&self) -> Result<TStats> {
+        let state = self.state();
+
+        let sanctions = |kind: SanctionKind| {
+            state
+                .sanctions
+                .iter()
+                .filter(|s| s.kind() == kind && s.in_effect())
+                .count() as u64
+        };
+
+        let mut requests_by_status: Vec<t_stats::StatusCount> = Vec::new();
+
+        for request in &state.requests {
+            match requests_by_status
+                .iter_mut()
+                .find(|c| c.status == request.status)
+            {
+                Some(count) => count.count += 1,
+                None => requests_by_status.push(t_stats::StatusCount {
+                    status: request.status,
+                    count: 1,
+                }),
+            }
+        }
+
+        let hours_exchanged = state
+            .requests
+            .iter()
+            .filter(|r| r.status() == RequestStatus::Completed)
+            .filter_map(|r| state.selected_bid(&r.id))
+            .map(|b| b.amount)
+            .sum();
+
+        // only the ratings others can see
+        let ratings: Vec<&TServiceRating> = state
+            .ratings
+            .iter()
+            .filter(|r| r.revealed && !r.hidden)
+            .collect();
+
+        let average_rating = match ratings.len() {
+            0 => 0.0,
+            count => ratings.iter().map(|r| r.value as f64).sum::<f64>() / count as f64,
+        };
+
+        Ok(TStats {
+            users: state.profiles.len() as u64,
+            suspended_users: sanctions(SanctionKind::Suspension),
+            banned_users: sanctions(SanctionKind::Ban),
+            requests: state.requests.len() as u64,
+            requests_by_status,
+            bids: state.bids.len() as u64,
+            hours_exchanged,
+            ratings: ratings.len() as u64,
+            average_rating,
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl ProviderRepository for MemoryRepository {
+    async fn get_provider_stats(&self, query: &Query) -> Result<Vec<ProviderStats>> {
+        let state = self.state();
+
+        let mut stats: Vec<ProviderStats> = Vec::new();
+        // user id -> total response time and the number of bids it adds up
+        let mut responses: HashMap<&str, (f64, u32)> = HashMap::new();
+
+        for bid in &state.bids {
+            let index = match stats.iter().position(|s| s.user_id == bid.user_id) {
+                Some(index) => index,
+                None => {
+                    stats.push(ProviderStats {
+                        user_id: bid.user_id.clone(),
+                        ..Default::default()
+                    });
+                    stats.len() - 1
+                }
+            };
+
+            let entry = &mut stats[index];
+            entry.bids += 1;
+
+            let request = match state.request(&bid.request_id) {
+                Ok(request) => request,
+                Err(_) => continue,
+            };
+
+            let posted = DateTime::parse_from_rfc3339(&request.created_at);
+            let placed = DateTime::parse_from_rfc3339(&bid.created_at);
+
+            if let (Ok(posted), Ok(placed)) = (posted, placed) {
+                let response = responses.entry(&bid.user_id).or_default();
+                response.0 += (placed - posted).num_seconds().max(0) as f64;
+                response.1 += 1;
+            }
+
+            if state.selected_bids.get(&bid.request_id) != Some(&bid.id) {
+                continue;
+            }
+
+            entry.selected += 1;
+
+            match request.status() {
+                RequestStatus::Completed => {
+                    entry.concluded += 1;
+                    entry.completed += 1;
+                }
+
+                RequestStatus::Cancelled | RequestStatus::Disputed | RequestStatus::Expired => {
+                    entry.concluded += 1;
+                }
+
+                _ => {}
+            }
+        }
+
+        for entry in &mut stats {
+            entry.mean_response_seconds = responses
+                .get(entry.user_id.as_str())
+                .map(|(total, count)| total / *count as f64);
+        }
+
+        query_rows(&stats, query)
+    }
+}
+
+#[tonic::async_trait]
+impl ReputationRepository for MemoryRepository {
+    async fn get_reputations(&self, query: &Query) -> Result<Vec<ReputationTally>> {
+        query_rows(&self.state().reputations, query)
+    }
+
+    async fn adjust_reputation(
+        &self,
+        user_id: &str,
+        role: ReputationRole,
+        added: Option<i32>,
+        removed: Option<i32>,
+    ) -> Result<ReputationTally> {
+        let mut state = self.state();
+
+        let index = match state
+            .reputations
+            .iter()
+            .position(|t| t.user_id == user_id && t.role == role as i32)
+        {
+            Some(index) => index,
+            None => {
+                state.reputations.push(ReputationTally {
+                    id: new_id(),
+                    user_id: user_id.to_string(),
+                    role: role as i32,
+                    ..Default::default()
+                });
+                state.reputations.len() - 1
+            }
+        };
+
+        let tally = &mut state.reputations[index];
+        tally.adjust(added, removed);
+        tally.updated_at = now();
+
+        Ok(tally.clone())
+    }
+}
+
+#[tonic::async_trait]
+impl ModerationRepository for MemoryRepository {
+    async fn create_case(&self, case: TModerationCase) -> Result<TModerationCase> {
+        let mut state = self.state();
+
+        if !state.ratings.iter().any(|r| r.id == case.rating_id) {
+            return Err(Error::NotFound);
+        }
+
+        let case = TModerationCase {
+            id: new_id(),
+            created_at: now(),
+            ..case
+        };
+
+        state.cases.push(case.clone());
+
+        Ok(case)
+    }
+
+    async fn update_case(
+        &self,
+        case_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TModerationCase>> {
+        let mut state = self.state();
+
+        match state.cases.iter_mut().find(|c| c.id == case_id) {
+            Some(case) => {
+                apply_update(case, columns)?;
+                Ok(Some(case.clone()))
+            }
+
+            None => Ok(None),
+        }
+    }
+
+    async fn get_cases(&self, query: &Query) -> Result<Vec<TModerationCase>> {
+        query_rows(&self.state().cases, query)
+    }
+}
+
+#[tonic::async_trait]
+impl TaxonomyRepository for MemoryRepository {
+    async fn create_category(&self, category: TCategory) -> Result<TCategory> {
+        let category = TCategory {
+            id: new_id(),
+            created_at: now(),
+            ..category
+        };
+
+        self.state().categories.push(category.clone());
+
+        Ok(category)
+    }
+
+    async fn update_category(
+        &self,
+        category_id: &str,
+        columns: Map<String, Value>,
+    ) -> Result<Option<TCategory>> {
+        let mut state = self.state();
+
+        match state.categories.iter_mut().find(|c| c.id == category_id) {
+            Some(category) => {
+                apply_update(category, columns)?;
+                Ok(Some(category.clone()))
+            }
+
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_category(&self, category_id: &str) -> Result<()> {
+        let mut state = self.state();
+
+        if !state.categories.iter().any(|c| c.id == category_id) {
+            return Err(Error::NotFound);
+        }
+
+        state.categories.retain(|c| c.id != category_id);
+
+        Ok(())
+    }
+
+    async fn get_categories(&self, query: &Query) -> Result<Vec<TCategory>> {
+        query_rows(&self.state().categories, query)
+    }
+
+    async fn set_skills(&self, user_id: &str, skills: Vec<TSkill>) -> Result<Vec<TSkill>> {
+        let mut state = self.state();
+
+        let skills: Vec<TSkill> = skills
+            .into_iter()
+            .map(|skill| TSkill {
+                id: new_id(),
+                user_id: user_id.to_string(),
+                created_at: now(),
+                ..skill
+            })
+            .collect();
+
+        state.skills.retain(|s| s.user_id != user_id);
+        state.skills.extend(skills.iter().cloned());
+
+        Ok(skills)
+    }
+
+    async fn get_skills(&self, query: &Query) -> Result<Vec<TSkill>> {
+        query_rows(&self.state().skills, query)
+    }
+}
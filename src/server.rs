@@ -1,47 +1,173 @@
-// TODO:
-// 1. create auth middleware that checks for valid jwt
-//    - how it'd work is by retrieving jwt attached in request metadata,
-//      and get the user associated with the token. the append the user id
-//      in the request metadata.
-//
+// handlers have to fail with `tonic::Status`, which is larger than clippy likes
+#![allow(clippy::result_large_err)]
 
+pub mod blob;
+pub mod config;
+pub mod error;
+pub mod events;
+pub mod middleware;
+pub mod policy;
 pub mod proto;
+pub mod repository;
 pub mod services;
+pub mod upstream;
 
+use std::sync::Arc;
+
+use blob::{local::LocalBlobStore, BlobStore};
+use config::{BlobBackend, Config, StorageBackend};
 use dotenv::dotenv;
+use events::EventBus;
+use middleware::{audit::AuditLayer, auth::AuthInterceptor, deadline::DeadlineLayer};
 use proto::{account::user_server::UserServer, auth::auth_server::AuthServer};
+use repository::{database::DatabaseRepository, memory::MemoryRepository, Repository};
 use services::collection::{
-    service_rating::{ServiceRatingServer, ServiceRatingService},
+    service_rating::{self, ServiceRatingServer, ServiceRatingService},
     service_request::{ServiceRequestServer, ServiceRequestService},
     service_request_bid::{ServiceRequestBidServer, ServiceRequestBidService},
+    service_request_status::{self, ServiceRequestStatusServer, ServiceRequestStatusService},
 };
-use services::{account::UserService, auth::AuthService};
-use tonic::transport::Server;
-
-// async fn interceptor(req: tonic::Request<()>) -> Result<tonic::Request<()>, tonic::Status> {
-//     match req.metadata().get("access_token") {
-//         Some(_) => Ok(req),
-
-//         None => Err(tonic::Status::unauthenticated("MISSING ACCESS_TOKEN")),
-//     }
-// }
+use services::{
+    account::UserService,
+    admin::{AdminServer, AdminService},
+    auth::AuthService,
+    ledger::{LedgerServer, LedgerService},
+    messaging::{MessagingServer, MessagingService},
+    moderation::{ModerationServer, ModerationService},
+    ranking::{RankingServer, RankingService},
+    reputation::{ReputationServer, ReputationService},
+    taxonomy::{TaxonomyServer, TaxonomyService},
+    verification::{VerificationServer, VerificationService},
+    watch::{WatchServer, WatchService},
+};
+use tonic::transport::{Identity, Server, ServerTlsConfig};
+use upstream::Upstream;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
+    // `RUST_LOG` sets the level, errors only by default
+    env_logger::init();
+
+    let config = Config::load().unwrap_or_else(|e| {
+        log::error!("{e}");
+        std::process::exit(1);
+    });
+
+    let upstream = Upstream::new(&config);
+
+    let repository: Arc<dyn Repository> = match config.features.storage {
+        StorageBackend::Memory => Arc::new(MemoryRepository::default()),
+        StorageBackend::Database => Arc::new(DatabaseRepository::new(&config, upstream.clone())),
+    };
+
+    let blobs: Arc<dyn BlobStore> = match config.blob_store.backend {
+        BlobBackend::Local => Arc::new(LocalBlobStore::new(&config.blob_store.path)),
+    };
+
+    let events = EventBus::new();
+
+    tokio::spawn(service_request_status::expire_periodically(
+        repository.clone(),
+        events.clone(),
+        config.requests.expire_after(),
+    ));
+
+    tokio::spawn(service_rating::reveal_periodically(
+        repository.clone(),
+        config.ratings.reveal_window(),
+    ));
+
+    let auth_interceptor = AuthInterceptor::new(&config.supabase.jwt_secret);
+
+    let mut server = Server::builder()
+        .layer(DeadlineLayer)
+        .layer(AuditLayer::new(
+            repository.clone(),
+            auth_interceptor.clone(),
+        ));
+
+    if let Some(tls) = &config.server.tls {
+        let identity = Identity::from_pem(
+            std::fs::read(&tls.cert_path)?,
+            std::fs::read(&tls.key_path)?,
+        );
+
+        server = server.tls_config(ServerTlsConfig::new().identity(identity))?;
+    }
+
+    let watch = config.features.watch.then(|| {
+        WatchServer::with_interceptor(
+            WatchService::new(repository.clone(), events.clone()),
+            auth_interceptor.clone(),
+        )
+    });
+
+    let messaging = config.features.messaging.then(|| {
+        MessagingServer::with_interceptor(
+            MessagingService::new(repository.clone(), events.clone()),
+            auth_interceptor.clone(),
+        )
+    });
 
-    let addr = dotenv::var("SOCKET_ADDRESS")
-        .expect("MISSING SOCKET ADDRESS")
-        .parse()
-        .expect("UNABLE TO PARSE SOKCET ADDRESS STRING");
-
-    Server::builder()
-        .add_service(ServiceRequestServer::new(ServiceRequestService::new()))
-        .add_service(ServiceRatingServer::new(ServiceRatingService::new()))
-        .add_service(ServiceRequestBidServer::new(ServiceRequestBidService::new()))
-        .add_service(UserServer::new(UserService::new()))
-        .add_service(AuthServer::new(AuthService::default()))
-        .serve(addr)
+    server
+        .add_service(ServiceRequestServer::with_interceptor(
+            ServiceRequestService::new(repository.clone(), events.clone()),
+            auth_interceptor.clone(),
+        ))
+        .add_service(ServiceRatingServer::with_interceptor(
+            ServiceRatingService::new(repository.clone(), &config.ratings),
+            auth_interceptor.clone(),
+        ))
+        .add_service(ServiceRequestBidServer::with_interceptor(
+            ServiceRequestBidService::new(repository.clone(), events.clone()),
+            auth_interceptor.clone(),
+        ))
+        .add_service(ServiceRequestStatusServer::with_interceptor(
+            ServiceRequestStatusService::new(repository.clone(), events.clone()),
+            auth_interceptor.clone(),
+        ))
+        .add_service(UserServer::with_interceptor(
+            UserService::new(repository.clone()),
+            auth_interceptor.clone(),
+        ))
+        .add_service(LedgerServer::with_interceptor(
+            LedgerService::new(repository.clone()),
+            auth_interceptor.clone(),
+        ))
+        .add_service(VerificationServer::with_interceptor(
+            VerificationService::new(repository.clone(), blobs),
+            auth_interceptor.clone(),
+        ))
+        .add_service(RankingServer::with_interceptor(
+            RankingService::new(repository.clone(), &config.ranking),
+            auth_interceptor.clone(),
+        ))
+        .add_service(ReputationServer::with_interceptor(
+            ReputationService::new(repository.clone()),
+            auth_interceptor.clone(),
+        ))
+        .add_service(TaxonomyServer::with_interceptor(
+            TaxonomyService::new(repository.clone()),
+            auth_interceptor.clone(),
+        ))
+        .add_service(ModerationServer::with_interceptor(
+            ModerationService::new(repository.clone()),
+            auth_interceptor.clone(),
+        ))
+        .add_service(AdminServer::with_interceptor(
+            AdminService::new(repository.clone(), events),
+            auth_interceptor.clone(),
+        ))
+        .add_optional_service(watch)
+        .add_optional_service(messaging)
+        .add_service(AuthServer::new(AuthService::new(
+            &config,
+            upstream,
+            repository,
+            auth_interceptor,
+        )))
+        .serve(config.server.listen_address)
         .await?;
 
     Ok(())
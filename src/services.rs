@@ -1,72 +1,114 @@
 pub mod account;
+pub mod admin;
 pub mod auth;
 pub mod collection;
+pub mod field_mask;
+pub mod ledger;
+pub mod messaging;
+pub mod moderation;
+pub mod query;
+pub mod ranking;
+pub mod reputation;
+pub mod taxonomy;
+#[cfg(test)]
+pub mod testing;
+pub mod verification;
+pub mod watch;
 
 pub type Result<T> = std::result::Result<T, tonic::Status>;
 
-pub mod error_messages {
-    pub const INVALID_PAYLOAD: &str = "INVALID PAYLOAD";
-    pub const UNKNOWN: &str = "AN ERROR HAS OCCURED";
-    pub const ALREADY_EXISTS: &str = "ITEM ALREADY EXISTS";
-    pub const MISSING_ARGUMENT: &str = "EXPECTED ARGUMENT MISSING";
-    pub const TOO_MANY_REQUESTS: &str = "TOO MANY REQUESTS";
-}
-
 pub mod util {
-    use postgrest::Postgrest;
-    use reqwest::{header::HeaderMap, IntoUrl, RequestBuilder};
-
     #[allow(dead_code)]
     #[derive(serde::Deserialize, Default, Debug)]
     pub struct DatabaseErrorMessage {
-        message: String,
-        code: String,
-        details: String,
-        hint: String,
+        #[serde(default)]
+        pub message: String,
+        #[serde(default)]
+        pub code: String,
+        pub details: Option<String>,
+        pub hint: Option<String>,
     }
 
-    #[allow(dead_code)]
-    #[derive(serde::Deserialize, Default, Debug)]
+    #[derive(Default, Debug)]
     pub struct DatabaseErrorResponse {
-        response_status: u16,
-        error: DatabaseErrorMessage,
+        pub response_status: u16,
+        pub error: DatabaseErrorMessage,
     }
 
-    pub struct HTTP {}
-
-    impl HTTP {
-        fn new<U: IntoUrl>(method: reqwest::Method, url: U) -> RequestBuilder {
-            let supabase_key = dotenv::var("SUPABASE_API_KEY").expect("MISSING SUPABASE API KEY!");
-
-            let mut headers = HeaderMap::new();
-            headers.insert("apiKey", supabase_key.parse().unwrap());
-
-            reqwest::Client::new()
-                .request(method, url)
-                .headers(headers)
-                .bearer_auth(supabase_key)
+    impl DatabaseErrorResponse {
+        pub async fn from_response(res: reqwest::Response) -> Self {
+            Self {
+                response_status: res.status().as_u16(),
+                error: res.json().await.unwrap_or_default(),
+            }
         }
+    }
 
-        pub fn get<U: IntoUrl>(url: U) -> RequestBuilder {
-            Self::new(reqwest::Method::GET, url)
+    pub mod helper {
+        use tonic::Request;
+
+        use crate::error::Error;
+        use crate::middleware::auth::AuthenticatedUser;
+        use crate::policy::Actor;
+        use crate::proto::timebank::role::Role;
+        use crate::repository::Repository;
+        use crate::services::{admin, Result};
+
+        /// Returns the caller verified by `AuthInterceptor`.
+        pub fn authenticated_user<T>(request: &Request<T>) -> Result<AuthenticatedUser> {
+            request
+                .extensions()
+                .get::<AuthenticatedUser>()
+                .cloned()
+                .ok_or_else(|| Error::MissingAccessToken.into())
         }
 
-        pub fn post<U: IntoUrl>(url: U) -> RequestBuilder {
-            Self::new(reqwest::Method::POST, url)
+        /// Looks up the role of the user for `policy::authorize`, users without a profile are
+        /// members. Fails if the user is suspended or banned.
+        pub async fn actor(repository: &dyn Repository, user: AuthenticatedUser) -> Result<Actor> {
+            admin::ensure_active(repository, &user.id).await?;
+
+            let role = repository
+                .get_profile(&user.id)
+                .await?
+                .map_or(Role::Member, |profile| profile.role());
+
+            Ok(Actor::new(user.id, role))
         }
     }
 
-    pub mod helper {}
-
     pub mod miscellaneous {
+        use postgrest::Builder;
+        use reqwest::header::HeaderMap;
+
+        /// PostgREST client on the HTTP client of `Upstream`, whose default headers carry the
+        /// api key. PostgREST takes the role from the bearer token, the gateway wants the `apikey`.
+        #[derive(Clone)]
+        pub struct PostgrestClient {
+            url: String,
+            client: reqwest::Client,
+        }
 
-        pub fn create_postgrest_client() -> super::Postgrest {
-            let (endpoint, api_key) = (
-                dotenv::var("SUPABASE_ENDPOINT").expect("MISSING SUPBASE POSTGREST ENDPOINT!"),
-                dotenv::var("SUPABASE_API_KEY").expect("MISSING SUPABASE API KEY!"),
-            );
-
-            super::Postgrest::new(endpoint).insert_header("apikey", api_key)
+        impl PostgrestClient {
+            pub fn new(url: &str, client: reqwest::Client) -> Self {
+                Self {
+                    url: url.trim_end_matches('/').to_string(),
+                    client,
+                }
+            }
+
+            pub fn from(&self, table: &str) -> Builder {
+                self.builder(format!("{}/{table}", self.url))
+            }
+
+            pub fn rpc(&self, function: &str, params: impl Into<String>) -> Builder {
+                self.builder(format!("{}/rpc/{function}", self.url))
+                    .rpc(params)
+            }
+
+            fn builder(&self, url: String) -> Builder {
+                Builder::new(url, None, HeaderMap::new(), self.client.clone())
+            }
         }
     }
 }
@@ -1,24 +1,27 @@
 // Service for handling user's account
 
-use postgrest::Postgrest;
-use reqwest::StatusCode;
-use serde_json::json;
-use tonic::{Request, Response, Status};
+use std::sync::Arc;
 
+use tonic::{Request, Response};
+
+use crate::error::Error;
+use crate::middleware::audit;
+use crate::policy::{self, Action};
 use crate::proto::account::user_server::User;
-use crate::proto::account::{get, get_rating, update, TUserProfile};
-use crate::proto::timebank::servicerating::TServiceRating;
-use crate::services::{error_messages, util, Result};
+use crate::proto::account::{get, get_rating, update};
+use crate::repository::Repository;
+use crate::services::{field_mask, util::helper, Result};
+
+// fields clients are allowed to change through `update`
+const MUTABLE_FIELDS: &[&str] = &["username", "name", "location"];
 
 pub struct UserService {
-    db_client: Postgrest,
+    repository: Arc<dyn Repository>,
 }
 
 impl UserService {
-    pub fn new() -> Self {
-        Self {
-            db_client: util::miscellaneous::create_postgrest_client(),
-        }
+    pub fn new(repository: Arc<dyn Repository>) -> Self {
+        Self { repository }
     }
 }
 
@@ -29,31 +32,11 @@ impl User for UserService {
 
         match payload {
             Some(payload) if !payload.user_id.is_empty() => {
-                let res = self
-                    .db_client
-                    .from("user_profile")
-                    .eq("user_id", payload.user_id)
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values: Vec<TUserProfile> = res.json().await.unwrap();
-
-                        Ok(Response::new(get::Response {
-                            user: values.into_iter().next(),
-                        }))
-                    }
-
-                    StatusCode::BAD_REQUEST => {
-                        Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD))
-                    }
-
-                    _ => Err(Status::unknown(error_messages::UNKNOWN)),
-                }
+                let user = self.repository.get_profile(&payload.user_id).await?;
+
+                Ok(Response::new(get::Response { user }))
             }
-            _ => Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -61,38 +44,54 @@ impl User for UserService {
         &self,
         request: Request<update::Request>,
     ) -> Result<Response<update::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
-            Some(payload) if !payload.user_id.is_empty() => {
-                let update::Payload { update, user_id } = payload;
-
-                let res = self
-                    .db_client
-                    .from("user_profile")
-                    .eq("user_id", user_id)
-                    .update(update)
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values: Vec<TUserProfile> = res.json().await.unwrap();
-
-                        Ok(Response::new(update::Response {
-                            user: values.into_iter().next(),
-                        }))
-                    }
-
-                    StatusCode::BAD_REQUEST => {
-                        Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD))
-                    }
-
-                    _ => Err(Status::unknown(error_messages::UNKNOWN)),
+            Some(payload) => {
+                let update::Payload {
+                    user_id,
+                    user: profile,
+                    update_mask,
+                } = payload;
+
+                // users update their own profile when no one else is named
+                let user_id = if user_id.is_empty() {
+                    user.id.clone()
+                } else {
+                    user_id
+                };
+
+                audit::target("user", &user_id);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(&actor, Action::UpdateProfile { user_id: &user_id })?;
+
+                let user = profile;
+
+                let patch = field_mask::patch(user.as_ref(), update_mask.as_ref(), MUTABLE_FIELDS)?;
+
+                let current = self.repository.get_profile(&user_id).await?;
+
+                if let Some(current) = &current {
+                    audit::before(current);
+                }
+
+                // the profile may not exist yet, in which case the patch is all there is
+                let current = current.unwrap_or_default();
+
+                let user = self
+                    .repository
+                    .update_profile(&user_id, field_mask::apply(&current, patch)?)
+                    .await?;
+
+                if let Some(user) = &user {
+                    audit::after(user);
                 }
+
+                Ok(Response::new(update::Response { user }))
             }
-            _ => Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -104,34 +103,12 @@ impl User for UserService {
 
         match payload {
             Some(payload) => {
-                let res = self
-                    .db_client
-                    .rpc(
-                        "user_get_rating",
-                        json!({
-                            "_user_id": payload.user_id,
-                        })
-                        .to_string(),
-                    )
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let ratings: Vec<TServiceRating> = res
-                            .json()
-                            .await
-                            .expect("UNABLE TO PARSE RESPONSE DATA AS `Vec<TServiceRating>`");
-
-                        Ok(Response::new(get_rating::Response { ratings }))
-                    }
-
-                    _ => Err(Status::unknown(error_messages::UNKNOWN)),
-                }
+                let ratings = self.repository.get_user_ratings(&payload.user_id).await?;
+
+                Ok(Response::new(get_rating::Response { ratings }))
             }
 
-            _ => Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 }
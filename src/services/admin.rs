@@ -0,0 +1,512 @@
+// Service for the admins to manage the community and read the audit log
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Map};
+use tonic::{Request, Response};
+
+use crate::error::{self, Error};
+use crate::events::EventBus;
+use crate::middleware::audit;
+use crate::policy::{self, Action, Actor};
+use crate::proto::timebank::admin::admin_server::Admin;
+use crate::proto::timebank::admin::{
+    adjust_balance, ban_user, cancel_request, get_stats, list_audit_events, list_rating_versions,
+    remove_rating, review_verification, search_users, suspend_user, unban_user, SanctionKind,
+    TSanction,
+};
+use crate::proto::timebank::servicerequest::TServiceRequest;
+use crate::proto::timebank::verification::{TVerification, VerificationStatus};
+use crate::proto::timebank::watch::request_event;
+use crate::repository::{Filter, Query, Repository};
+use crate::services::{
+    collection::service_request_status::{self, RequestStatus},
+    ledger, query, reputation,
+    util::helper,
+    Result,
+};
+
+pub use crate::proto::timebank::admin::admin_server::AdminServer;
+
+/// Ledger account on the other side of manual balance adjustments.
+pub const ADJUSTMENT_ACCOUNT: &str = "system:adjustment";
+
+const USER_COLUMNS: query::Columns = query::Columns {
+    key: "user_id",
+    filter: &["user_id", "username", "name", "role", "created_at"],
+    order: &["username", "name", "role", "created_at"],
+};
+
+const RATING_VERSION_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &["rating_id", "edited_by", "created_at"],
+    order: &["created_at"],
+};
+
+const AUDIT_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &["user_id", "entity", "target_id", "method", "created_at"],
+    order: &["created_at"],
+};
+
+impl TSanction {
+    /// Bans are in effect until lifted, suspensions until they run out.
+    pub fn in_effect(&self) -> bool {
+        match self.kind() {
+            SanctionKind::Ban => true,
+            SanctionKind::Suspension => DateTime::parse_from_rfc3339(&self.until)
+                .is_ok_and(|until| until.with_timezone(&Utc) > Utc::now()),
+            SanctionKind::Unspecified => false,
+        }
+    }
+}
+
+/// Fails with `Error::Banned` or `Error::Suspended` if the user is sanctioned.
+pub async fn ensure_active(repository: &dyn Repository, user_id: &str) -> error::Result<()> {
+    match repository.get_sanction(user_id).await? {
+        Some(sanction) if sanction.in_effect() => match sanction.kind() {
+            SanctionKind::Ban => Err(Error::Banned),
+            _ => Err(Error::Suspended(sanction.until)),
+        },
+
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn ensure_reason(reason: &str) -> error::Result<()> {
+    if reason.trim().is_empty() {
+        Err(Error::MissingArgument)
+    } else {
+        Ok(())
+    }
+}
+
+pub struct AdminService {
+    repository: Arc<dyn Repository>,
+    events: EventBus,
+}
+
+impl AdminService {
+    pub fn new(repository: Arc<dyn Repository>, events: EventBus) -> Self {
+        Self { repository, events }
+    }
+
+    // every rpc is for admins only
+    async fn admin<T>(&self, request: &Request<T>) -> Result<Actor> {
+        let user = helper::authenticated_user(request)?;
+        let actor = helper::actor(self.repository.as_ref(), user).await?;
+        policy::authorize(&actor, Action::Administer)?;
+
+        Ok(actor)
+    }
+
+    async fn sanction(&self, actor: &Actor, sanction: TSanction) -> Result<TSanction> {
+        policy::authorize(
+            actor,
+            Action::SanctionUser {
+                user_id: &sanction.user_id,
+            },
+        )?;
+
+        ensure_reason(&sanction.reason)?;
+
+        audit::target("user", &sanction.user_id);
+        audit::reason(&sanction.reason);
+
+        if let Some(previous) = self.repository.get_sanction(&sanction.user_id).await? {
+            audit::before(&previous);
+        }
+
+        let sanction = self.repository.save_sanction(sanction).await?;
+        audit::after(&sanction);
+
+        Ok(sanction)
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for AdminService {
+    async fn search_users(
+        &self,
+        request: Request<search_users::Request>,
+    ) -> Result<Response<search_users::Response>> {
+        self.admin(&request).await?;
+        let payload = request.into_inner().payload.unwrap_or_default();
+
+        let page = query::parse(payload.query, &USER_COLUMNS)?;
+        let (users, next_page_token) = page.split(self.repository.get_profiles(&page.query).await?);
+
+        let sanctions = if users.is_empty() {
+            Vec::new()
+        } else {
+            self.repository
+                .get_sanctions(&Query {
+                    filters: vec![(
+                        "user_id".to_string(),
+                        Filter::In(users.iter().map(|u| u.user_id.clone()).collect()),
+                    )],
+                    ..Default::default()
+                })
+                .await?
+                .into_iter()
+                .filter(TSanction::in_effect)
+                .collect()
+        };
+
+        Ok(Response::new(search_users::Response {
+            users,
+            sanctions,
+            next_page_token,
+        }))
+    }
+
+    async fn suspend_user(
+        &self,
+        request: Request<suspend_user::Request>,
+    ) -> Result<Response<suspend_user::Response>> {
+        let actor = self.admin(&request).await?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) if !payload.user_id.is_empty() => {
+                let until = DateTime::parse_from_rfc3339(&payload.until)
+                    .map_err(|_| Error::InvalidPayload)?;
+
+                if until.with_timezone(&Utc) <= Utc::now() {
+                    return Err(Error::InvalidPayload.into());
+                }
+
+                let sanction = self
+                    .sanction(
+                        &actor,
+                        TSanction {
+                            user_id: payload.user_id,
+                            kind: SanctionKind::Suspension as i32,
+                            reason: payload.reason,
+                            until: until.with_timezone(&Utc).to_rfc3339(),
+                            issued_by: actor.id.clone(),
+                            created_at: Utc::now().to_rfc3339(),
+                        },
+                    )
+                    .await?;
+
+                Ok(Response::new(suspend_user::Response {
+                    sanction: Some(sanction),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn ban_user(
+        &self,
+        request: Request<ban_user::Request>,
+    ) -> Result<Response<ban_user::Response>> {
+        let actor = self.admin(&request).await?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) if !payload.user_id.is_empty() => {
+                let sanction = self
+                    .sanction(
+                        &actor,
+                        TSanction {
+                            user_id: payload.user_id,
+                            kind: SanctionKind::Ban as i32,
+                            reason: payload.reason,
+                            issued_by: actor.id.clone(),
+                            created_at: Utc::now().to_rfc3339(),
+                            ..Default::default()
+                        },
+                    )
+                    .await?;
+
+                Ok(Response::new(ban_user::Response {
+                    sanction: Some(sanction),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn unban_user(
+        &self,
+        request: Request<unban_user::Request>,
+    ) -> Result<Response<unban_user::Response>> {
+        self.admin(&request).await?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                ensure_reason(&payload.reason)?;
+
+                let sanction = self
+                    .repository
+                    .get_sanction(&payload.user_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("user", &payload.user_id);
+                audit::reason(&payload.reason);
+                audit::before(&sanction);
+
+                self.repository.delete_sanction(&payload.user_id).await?;
+
+                Ok(Response::new(unban_user::Response {}))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn review_verification(
+        &self,
+        request: Request<review_verification::Request>,
+    ) -> Result<Response<review_verification::Response>> {
+        let actor = self.admin(&request).await?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                policy::authorize(
+                    &actor,
+                    Action::ReviewVerification {
+                        user_id: &payload.user_id,
+                    },
+                )?;
+
+                let decision = payload.decision();
+
+                match decision {
+                    VerificationStatus::Approved => {}
+                    VerificationStatus::Rejected => ensure_reason(&payload.reason)?,
+                    _ => return Err(Error::InvalidPayload.into()),
+                }
+
+                let verification = self
+                    .repository
+                    .get_verification(&payload.user_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                if verification.status() != VerificationStatus::Pending {
+                    return Err(Error::VerificationNotPending.into());
+                }
+
+                audit::target("verification", &verification.user_id);
+                audit::reason(&payload.reason);
+                audit::before(&verification);
+
+                let verification = self
+                    .repository
+                    .save_verification(TVerification {
+                        status: decision as i32,
+                        reviewed_at: Utc::now().to_rfc3339(),
+                        reviewer: actor.id,
+                        reason: payload.reason,
+                        ..verification
+                    })
+                    .await?;
+
+                audit::after(&verification);
+
+                Ok(Response::new(review_verification::Response {
+                    verification: Some(verification),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn cancel_request(
+        &self,
+        request: Request<cancel_request::Request>,
+    ) -> Result<Response<cancel_request::Response>> {
+        let actor = self.admin(&request).await?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let service_request = self
+                    .repository
+                    .get_request(&payload.request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("service_request", &service_request.id);
+                audit::reason(&payload.reason);
+                audit::before(&service_request);
+
+                let refunded = self
+                    .repository
+                    .get_balance(&ledger::escrow_account(&service_request.id), None)
+                    .await?;
+
+                let transition = service_request_status::transition_releasing_escrow(
+                    self.repository.as_ref(),
+                    &service_request.id,
+                    RequestStatus::Cancelled,
+                    &actor.id,
+                    &service_request.requestor,
+                    "ESCROW REFUNDED",
+                )
+                .await?;
+
+                let cancelled = TServiceRequest {
+                    status: RequestStatus::Cancelled as i32,
+                    ..service_request.clone()
+                };
+
+                audit::after(&cancelled);
+
+                self.events
+                    .publish_request(request_event::Kind::Updated, &cancelled);
+
+                Ok(Response::new(cancel_request::Response {
+                    transition: Some(transition),
+                    refunded: refunded.max(0.0),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn remove_rating(
+        &self,
+        request: Request<remove_rating::Request>,
+    ) -> Result<Response<remove_rating::Response>> {
+        self.admin(&request).await?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let rating = self
+                    .repository
+                    .get_ratings(&Query::eq("id", &payload.rating_id))
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("rating", &rating.id);
+                audit::reason(&payload.reason);
+                audit::before(&rating);
+
+                if rating.hidden {
+                    return Err(Error::RatingAlreadyHidden.into());
+                }
+
+                // hidden rather than deleted, so the cases opened on it are kept
+                let columns = Map::from_iter([("hidden".to_string(), json!(true))]);
+                let removed = self
+                    .repository
+                    .update_rating(&rating.id, columns)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+                audit::after(&removed);
+
+                reputation::record(self.repository.as_ref(), &rating, None, Some(rating.value))
+                    .await?;
+
+                Ok(Response::new(remove_rating::Response {}))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn list_rating_versions(
+        &self,
+        request: Request<list_rating_versions::Request>,
+    ) -> Result<Response<list_rating_versions::Response>> {
+        self.admin(&request).await?;
+        let payload = request.into_inner().payload.unwrap_or_default();
+
+        let page = query::parse(payload.query, &RATING_VERSION_COLUMNS)?;
+        let (versions, next_page_token) =
+            page.split(self.repository.get_rating_versions(&page.query).await?);
+
+        Ok(Response::new(list_rating_versions::Response {
+            versions,
+            next_page_token,
+        }))
+    }
+
+    async fn adjust_balance(
+        &self,
+        request: Request<adjust_balance::Request>,
+    ) -> Result<Response<adjust_balance::Response>> {
+        self.admin(&request).await?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload)
+                if !payload.user_id.is_empty()
+                    && payload.amount.is_finite()
+                    && payload.amount != 0.0 =>
+            {
+                ensure_reason(&payload.reason)?;
+
+                audit::target("user", &payload.user_id);
+                audit::reason(&payload.reason);
+
+                let previous = self.repository.get_balance(&payload.user_id, None).await?;
+                audit::before(&json!({ "balance": previous }));
+
+                let transaction = self
+                    .repository
+                    .record_transaction(ledger::transfer(
+                        ADJUSTMENT_ACCOUNT,
+                        &payload.user_id,
+                        payload.amount,
+                        "",
+                        &payload.reason,
+                    ))
+                    .await?;
+
+                let balance = self.repository.get_balance(&payload.user_id, None).await?;
+                audit::after(&json!({ "balance": balance, "transaction_id": transaction.id }));
+
+                Ok(Response::new(adjust_balance::Response {
+                    transaction: Some(transaction),
+                    balance,
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn get_stats(
+        &self,
+        request: Request<get_stats::Request>,
+    ) -> Result<Response<get_stats::Response>> {
+        self.admin(&request).await?;
+
+        let stats = self.repository.get_stats().await?;
+
+        Ok(Response::new(get_stats::Response { stats: Some(stats) }))
+    }
+
+    async fn list_audit_events(
+        &self,
+        request: Request<list_audit_events::Request>,
+    ) -> Result<Response<list_audit_events::Response>> {
+        self.admin(&request).await?;
+        let payload = request.into_inner().payload.unwrap_or_default();
+
+        let page = query::parse(payload.query, &AUDIT_COLUMNS)?;
+        let (events, next_page_token) =
+            page.split(self.repository.get_audit_events(&page.query).await?);
+
+        Ok(Response::new(list_audit_events::Response {
+            events,
+            next_page_token,
+        }))
+    }
+}
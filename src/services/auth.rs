@@ -1,21 +1,165 @@
 ///
 /// Service for handling auth related operations eg sign_in, regi&ster
 ///
-// TODO:
-// (1) Handle KYC during registration process
-//
-use serde_json::json;
+/// Identity verification happens after registration, see `services::verification`.
+///
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use tonic::{Request, Response, Status};
 
+use crate::config::Config;
+use crate::error::Error;
+use crate::middleware::audit;
+use crate::middleware::auth::{self, AuthInterceptor, AuthenticatedUser};
 use crate::proto::auth::auth_server::Auth;
-use crate::proto::auth::{sign_in, sign_up};
-use crate::services::error_messages;
-use reqwest::{self, StatusCode};
+use crate::proto::auth::{
+    confirm_password_reset, list_sessions, refresh_token, request_password_reset,
+    resend_verification_email, revoke_session, sign_in, sign_out, sign_up, verify_email,
+};
+use crate::repository::Repository;
+use crate::services::admin;
+use crate::upstream::Upstream;
+use reqwest::RequestBuilder;
+
+/// Tokens GoTrue returns when signing in or refreshing.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct TokenResponse {
+    access_token: String,
+    refresh_token: String,
+    /// Seconds the access token is valid for.
+    expires_in: i64,
+    /// Unix time the access token expires at, only returned by newer GoTrue versions.
+    expires_at: Option<i64>,
+    user: TokenUser,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct TokenUser {
+    id: String,
+}
+
+impl TokenResponse {
+    fn expires_at(&self) -> i64 {
+        self.expires_at
+            .unwrap_or_else(|| Utc::now().timestamp() + self.expires_in)
+    }
+}
+
+pub struct AuthService {
+    upstream: Upstream,
+    endpoint: String,
+    repository: Arc<dyn Repository>,
+    // sign in and sign up are public, the other calls authenticate the caller themselves
+    authenticator: AuthInterceptor,
+}
+
+impl AuthService {
+    pub fn new(
+        config: &Config,
+        upstream: Upstream,
+        repository: Arc<dyn Repository>,
+        authenticator: AuthInterceptor,
+    ) -> Self {
+        Self {
+            upstream,
+            endpoint: config
+                .supabase
+                .auth_endpoint
+                .trim_end_matches('/')
+                .to_string(),
+            repository,
+            authenticator,
+        }
+    }
+
+    fn post(&self, path: &str) -> RequestBuilder {
+        self.upstream
+            .client()
+            .post(format!("{}{path}", self.endpoint))
+    }
+
+    fn put(&self, path: &str) -> RequestBuilder {
+        self.upstream
+            .client()
+            .put(format!("{}{path}", self.endpoint))
+    }
+
+    fn authenticate<T>(&self, request: &Request<T>) -> Result<AuthenticatedUser, Status> {
+        Ok(self.authenticator.authenticate(request.metadata())?)
+    }
+
+    // sends the request, returning the body of a successful response
+    async fn call(&self, request: impl Fn() -> RequestBuilder) -> Result<Value, Status> {
+        let res = self.upstream.write(|| request().send()).await?;
+
+        let res_status = res.status();
+        // some endpoints answer with an empty body
+        let res_data = res.json::<Value>().await.unwrap_or_default();
+
+        if res_status.is_success() {
+            Ok(res_data)
+        } else {
+            Err(Error::from_auth_response(res_status, &res_data).into())
+        }
+    }
+
+    // exchanges the grant for a new pair of tokens
+    async fn token(&self, grant_type: &str, body: Value) -> Result<TokenResponse, Status> {
+        let path = format!("/token?grant_type={grant_type}");
+        let res_data = self.call(|| self.post(&path).json(&body)).await?;
+
+        Ok(serde_json::from_value(res_data).map_err(Error::from)?)
+    }
+
+    // GoTrue has already started a session when the user turns out to be suspended or banned,
+    // it is ended so the tokens can't be used nor refreshed
+    async fn ensure_active(&self, tokens: &TokenResponse) -> Result<(), Status> {
+        let inactive = match admin::ensure_active(self.repository.as_ref(), &tokens.user.id).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+
+        let ended = self
+            .call(|| {
+                self.post("/logout?scope=local")
+                    .bearer_auth(&tokens.access_token)
+            })
+            .await;
+
+        if let Err(e) = ended {
+            log::error!(
+                "UNABLE TO END THE SESSION OF INACTIVE USER {}: {}",
+                tokens.user.id,
+                e.message()
+            );
+        }
 
-use crate::services::util::HTTP;
+        Err(inactive.into())
+    }
+
+    // `kind` is the GoTrue verification type, eg `signup` or `recovery`
+    async fn verify(&self, kind: &str, email: &str, token: &str) -> Result<TokenResponse, Status> {
+        if token.is_empty() {
+            return Err(Error::MissingArgument.into());
+        }
 
-#[derive(Default)]
-pub struct AuthService {}
+        // tokens from the emailed links are verified on their own, the 6 digit codes need the email
+        let mut body = json!({ "type": kind, "token": token });
+
+        if !email.is_empty() {
+            body["email"] = json!(email);
+        }
+
+        let res_data = self.call(|| self.post("/verify").json(&body)).await?;
+
+        Ok(serde_json::from_value(res_data).map_err(Error::from)?)
+    }
+}
 
 #[tonic::async_trait]
 impl Auth for AuthService {
@@ -27,43 +171,30 @@ impl Auth for AuthService {
 
         if let Some(payload) = payload {
             if payload.email.is_empty() || payload.password.is_empty() {
-                return Err(Status::invalid_argument("Missing email or password"));
+                return Err(Error::MissingArgument.into());
             }
 
-            let res = HTTP::post(
-                "https://quepskrrpovzwydvfezs.supabase.co/auth/v1/token?grant_type=password",
-            )
-            .json(&payload)
-            .send()
-            .await
-            .unwrap();
-
-            let res_status = res.status();
-            let res_data = res.json::<serde_json::Value>().await.unwrap();
-
-            match res_status {
-                StatusCode::OK => Ok(Response::new(sign_in::Response {
-                    auth_token: res_data["access_token"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                    user_id: res_data["user"]["id"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_string(),
-                })),
-
-                StatusCode::BAD_REQUEST => Err(Status::invalid_argument(
-                    res_data["error_description"]
-                        .as_str()
-                        .unwrap_or_default()
-                        .to_uppercase(),
-                )),
-
-                _ => Err(Status::unknown(error_messages::UNKNOWN)),
-            }
+            let tokens = self
+                .token(
+                    "password",
+                    json!({ "email": payload.email, "password": payload.password }),
+                )
+                .await?;
+
+            self.ensure_active(&tokens).await?;
+
+            audit::user(&tokens.user.id);
+            audit::target("user", &tokens.user.id);
+
+            Ok(Response::new(sign_in::Response {
+                expires_at: tokens.expires_at(),
+                auth_token: tokens.access_token,
+                user_id: tokens.user.id,
+                refresh_token: tokens.refresh_token,
+                expires_in: tokens.expires_in,
+            }))
         } else {
-            Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD))
+            Err(Error::InvalidPayload.into())
         }
     }
 
@@ -74,32 +205,236 @@ impl Auth for AuthService {
         let payload = request.into_inner().payload;
 
         if let Some(payload) = payload {
-            let res = HTTP::post("https://quepskrrpovzwydvfezs.supabase.co/auth/v1/signup")
-                .json(&json!({ "email": payload.email, "password": payload.password }))
-                .send()
-                .await
-                .unwrap();
-
-            match res.status() {
-                StatusCode::OK => Ok(Response::new(sign_up::Response {})),
-
-                StatusCode::UNPROCESSABLE_ENTITY => Err(Status::new(
-                    tonic::Code::ResourceExhausted,
-                    error_messages::INVALID_PAYLOAD,
-                )),
-
-                StatusCode::TOO_MANY_REQUESTS => Err(Status::new(
-                    tonic::Code::ResourceExhausted,
-                    error_messages::TOO_MANY_REQUESTS,
-                )),
-
-                _ => Err(Status::new(tonic::Code::Unknown, error_messages::UNKNOWN)),
+            let body = json!({ "email": payload.email, "password": payload.password });
+
+            let res_data = self.call(|| self.post("/signup").json(&body)).await?;
+
+            // the user is returned on its own while the email is unconfirmed, and alongside the
+            // tokens when GoTrue confirms emails automatically
+            let user = match res_data.get("user") {
+                Some(user) if user.is_object() => user,
+                _ => &res_data,
+            };
+
+            let user_id = user["id"].as_str().unwrap_or_default();
+
+            audit::user(user_id);
+            audit::target("user", user_id);
+
+            Ok(Response::new(sign_up::Response {
+                user_id: user_id.to_string(),
+                email_confirmed: !user["email_confirmed_at"].is_null(),
+                confirmation_sent_at: user["confirmation_sent_at"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            }))
+        } else {
+            Err(Error::InvalidPayload.into())
+        }
+    }
+
+    async fn refresh_token(
+        &self,
+        request: Request<refresh_token::Request>,
+    ) -> Result<Response<refresh_token::Response>, Status> {
+        let payload = request.into_inner().payload;
+
+        if let Some(payload) = payload {
+            if payload.refresh_token.is_empty() {
+                return Err(Error::MissingArgument.into());
+            }
+
+            // GoTrue revokes the given refresh token and returns a new one
+            let tokens = self
+                .token(
+                    "refresh_token",
+                    json!({ "refresh_token": payload.refresh_token }),
+                )
+                .await?;
+
+            self.ensure_active(&tokens).await?;
+
+            audit::user(&tokens.user.id);
+            audit::target("user", &tokens.user.id);
+
+            Ok(Response::new(refresh_token::Response {
+                expires_at: tokens.expires_at(),
+                auth_token: tokens.access_token,
+                user_id: tokens.user.id,
+                refresh_token: tokens.refresh_token,
+                expires_in: tokens.expires_in,
+            }))
+        } else {
+            Err(Error::InvalidPayload.into())
+        }
+    }
+
+    async fn sign_out(
+        &self,
+        request: Request<sign_out::Request>,
+    ) -> Result<Response<sign_out::Response>, Status> {
+        let user = self.authenticate(&request)?;
+        audit::target("user", &user.id);
+
+        // verified above, GoTrue needs the token itself to know which session to end
+        let access_token = auth::access_token(request.metadata())
+            .ok_or(Error::MissingAccessToken)?
+            .to_string();
+
+        let all_sessions = request
+            .into_inner()
+            .payload
+            .is_some_and(|payload| payload.all_sessions);
+
+        let path = if all_sessions {
+            "/logout?scope=global"
+        } else {
+            "/logout?scope=local"
+        };
+
+        self.call(|| self.post(path).bearer_auth(&access_token))
+            .await?;
+
+        Ok(Response::new(sign_out::Response {}))
+    }
+
+    async fn list_sessions(
+        &self,
+        request: Request<list_sessions::Request>,
+    ) -> Result<Response<list_sessions::Response>, Status> {
+        let user = self.authenticate(&request)?;
+
+        let mut sessions = self.repository.get_sessions(&user.id).await?;
+
+        for session in &mut sessions {
+            session.current = user.session_id.as_deref() == Some(session.id.as_str());
+        }
+
+        Ok(Response::new(list_sessions::Response { sessions }))
+    }
+
+    async fn revoke_session(
+        &self,
+        request: Request<revoke_session::Request>,
+    ) -> Result<Response<revoke_session::Response>, Status> {
+        let user = self.authenticate(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) if !payload.session_id.is_empty() => {
+                audit::target("session", &payload.session_id);
+
+                self.repository
+                    .revoke_session(&user.id, &payload.session_id)
+                    .await?;
+
+                Ok(Response::new(revoke_session::Response {}))
+            }
+
+            Some(_) => Err(Error::MissingArgument.into()),
+            None => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn request_password_reset(
+        &self,
+        request: Request<request_password_reset::Request>,
+    ) -> Result<Response<request_password_reset::Response>, Status> {
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) if !payload.email.is_empty() => {
+                let body = json!({ "email": payload.email });
+                self.call(|| self.post("/recover").json(&body)).await?;
+
+                Ok(Response::new(request_password_reset::Response {}))
+            }
+
+            Some(_) => Err(Error::MissingArgument.into()),
+            None => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn confirm_password_reset(
+        &self,
+        request: Request<confirm_password_reset::Request>,
+    ) -> Result<Response<confirm_password_reset::Response>, Status> {
+        let payload = request.into_inner().payload;
+
+        if let Some(payload) = payload {
+            if payload.new_password.is_empty() {
+                return Err(Error::MissingArgument.into());
             }
+
+            // verifying the recovery token signs the user in, which allows changing the password
+            let tokens = self
+                .verify("recovery", &payload.email, &payload.token)
+                .await?;
+
+            audit::user(&tokens.user.id);
+            audit::target("user", &tokens.user.id);
+
+            let body = json!({ "password": payload.new_password });
+
+            self.call(|| {
+                self.put("/user")
+                    .bearer_auth(&tokens.access_token)
+                    .json(&body)
+            })
+            .await?;
+
+            Ok(Response::new(confirm_password_reset::Response {}))
+        } else {
+            Err(Error::InvalidPayload.into())
+        }
+    }
+
+    async fn resend_verification_email(
+        &self,
+        request: Request<resend_verification_email::Request>,
+    ) -> Result<Response<resend_verification_email::Response>, Status> {
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) if !payload.email.is_empty() => {
+                let body = json!({ "type": "signup", "email": payload.email });
+                self.call(|| self.post("/resend").json(&body)).await?;
+
+                Ok(Response::new(resend_verification_email::Response {}))
+            }
+
+            Some(_) => Err(Error::MissingArgument.into()),
+            None => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn verify_email(
+        &self,
+        request: Request<verify_email::Request>,
+    ) -> Result<Response<verify_email::Response>, Status> {
+        let payload = request.into_inner().payload;
+
+        if let Some(payload) = payload {
+            let tokens = self
+                .verify("signup", &payload.email, &payload.token)
+                .await?;
+
+            // verifying signs the user in, which banned users mustn't be
+            self.ensure_active(&tokens).await?;
+
+            audit::user(&tokens.user.id);
+            audit::target("user", &tokens.user.id);
+
+            Ok(Response::new(verify_email::Response {
+                expires_at: tokens.expires_at(),
+                auth_token: tokens.access_token,
+                user_id: tokens.user.id,
+                refresh_token: tokens.refresh_token,
+                expires_in: tokens.expires_in,
+            }))
         } else {
-            Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            ))
+            Err(Error::InvalidPayload.into())
         }
     }
 }
@@ -1,3 +1,4 @@
 pub mod service_rating;
 pub mod service_request;
 pub mod service_request_bid;
+pub mod service_request_status;
@@ -1,22 +1,147 @@
-use postgrest::Postgrest;
-use reqwest::StatusCode;
-use serde_json::json;
-use tonic::{Request, Response, Status};
-
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use tonic::{Request, Response};
+
+use crate::config::RatingConfig;
+use crate::error::{self, Error};
+use crate::middleware::audit;
+use crate::policy::{self, Action};
+use crate::proto::timebank::admin::TRatingVersion;
+use crate::proto::timebank::reputation::ReputationRole;
 use crate::proto::timebank::servicerating::service_rating_server::ServiceRating;
 use crate::proto::timebank::servicerating::{create, delete, get, update, TServiceRating};
-use crate::services::{error_messages, util, Result};
+use crate::repository::{Filter, Query, Repository};
+use crate::services::{
+    collection::service_request_status::{self, RequestStatus},
+    field_mask, query, reputation,
+    util::helper,
+    Result,
+};
 
 pub use crate::proto::timebank::servicerating::service_rating_server::ServiceRatingServer;
 
+/// Values a rating can take, in stars.
+pub const RATING_SCALE: RangeInclusive<i32> = 1..=5;
+
+// fields clients are allowed to change through `update`
+const MUTABLE_FIELDS: &[&str] = &["value", "comment"];
+
+const QUERY_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &[
+        "id",
+        "request_id",
+        "user_id",
+        "rated_user_id",
+        "role",
+        "value",
+        "created_at",
+    ],
+    order: &["value", "created_at"],
+};
+
+// how often ratings whose window ran out are looked for
+const REVEAL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fails with `Error::RatingOutOfScale` if `value` isn't on `RATING_SCALE`.
+pub fn ensure_value(value: i32) -> error::Result<()> {
+    if RATING_SCALE.contains(&value) {
+        Ok(())
+    } else {
+        Err(Error::RatingOutOfScale(value))
+    }
+}
+
+/// Fails with `Error::RatingLocked` once the rating is revealed, or `window` has passed since it
+/// was created.
+pub fn ensure_editable(rating: &TServiceRating, window: Duration) -> error::Result<()> {
+    // the other side may have answered what it says
+    if rating.revealed {
+        return Err(Error::RatingLocked);
+    }
+
+    let created_at = DateTime::parse_from_rfc3339(&rating.created_at)
+        .map_err(|e| Error::Internal(e.to_string()))?;
+    let window = chrono::Duration::from_std(window).map_err(|e| Error::Internal(e.to_string()))?;
+
+    if Utc::now() > created_at.with_timezone(&Utc) + window {
+        Err(Error::RatingLocked)
+    } else {
+        Ok(())
+    }
+}
+
+/// Makes the ratings public, counting them in the reputation of the users they rate.
+pub async fn reveal(
+    repository: &dyn Repository,
+    ratings: Vec<TServiceRating>,
+) -> error::Result<Vec<TServiceRating>> {
+    let mut revealed = Vec::with_capacity(ratings.len());
+
+    for rating in ratings {
+        if rating.revealed {
+            revealed.push(rating);
+            continue;
+        }
+
+        // only the reveal that changed the rating counts it, the others lost the race
+        if let Some(rating) = repository.reveal_rating(&rating.id).await? {
+            reputation::record(repository, &rating, Some(rating.value), None).await?;
+            revealed.push(rating);
+        }
+    }
+
+    Ok(revealed)
+}
+
+/// Reveals the ratings that stayed hidden for longer than `window`.
+pub async fn reveal_expired(repository: &dyn Repository, window: Duration) -> error::Result<()> {
+    let cutoff = chrono::Duration::from_std(window).map_err(|e| Error::Internal(e.to_string()))?;
+
+    let hidden = repository
+        .get_ratings(&Query {
+            filters: vec![
+                ("revealed".to_string(), Filter::Eq("false".to_string())),
+                (
+                    "created_at".to_string(),
+                    Filter::Lt((Utc::now() - cutoff).to_rfc3339()),
+                ),
+            ],
+            ..Default::default()
+        })
+        .await?;
+
+    reveal(repository, hidden).await?;
+
+    Ok(())
+}
+
+/// Runs `reveal_expired` every minute, for as long as the server runs.
+pub async fn reveal_periodically(repository: Arc<dyn Repository>, window: Duration) {
+    let mut interval = tokio::time::interval(REVEAL_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = reveal_expired(repository.as_ref(), window).await {
+            log::error!("UNABLE TO REVEAL RATINGS: {e}");
+        }
+    }
+}
+
 pub struct ServiceRatingService {
-    db_client: Postgrest,
+    repository: Arc<dyn Repository>,
+    edit_window: Duration,
 }
 
 impl ServiceRatingService {
-    pub fn new() -> Self {
+    pub fn new(repository: Arc<dyn Repository>, config: &RatingConfig) -> Self {
         Self {
-            db_client: util::miscellaneous::create_postgrest_client(),
+            repository,
+            edit_window: config.edit_window(),
         }
     }
 }
@@ -27,55 +152,91 @@ impl ServiceRating for ServiceRatingService {
         &self,
         request: Request<create::Request>,
     ) -> Result<Response<create::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
-                let create::Payload {
-                    user_id,
-                    comment,
-                    request_id,
-                    value,
-                } = payload;
-
-                let res = self
-                    .db_client
-                    .rpc(
-                        "rating_create",
-                        json!({
-                            "_user_id": user_id,
-                            "_value": value,
-                            "_comment": comment,
-                            "_request_id": request_id
-                        })
-                        .to_string(),
-                    )
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values = res.json::<Vec<TServiceRating>>().await.unwrap();
-
-                        Ok(Response::new(create::Response {
-                            rating: values.into_iter().next(),
-                        }))
+                ensure_value(payload.value)?;
+
+                let request = self
+                    .repository
+                    .get_request(&payload.request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                let provider = self
+                    .repository
+                    .get_selected_bid(&request.id)
+                    .await?
+                    .ok_or(Error::NoBidSelected)?
+                    .user_id;
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::RateService {
+                        requestor: &request.requestor,
+                        provider: &provider,
+                    },
+                )?;
+
+                service_request_status::ensure_status(
+                    self.repository.as_ref(),
+                    &payload.request_id,
+                    &[RequestStatus::Completed],
+                )
+                .await?;
+
+                let author = if actor.id == provider {
+                    &provider
+                } else {
+                    &request.requestor
+                };
+
+                let (rated, role) = if *author == provider {
+                    (&request.requestor, ReputationRole::Requestor)
+                } else {
+                    (&provider, ReputationRole::Provider)
+                };
+
+                let rating = self
+                    .repository
+                    .create_rating(author, rated, role, payload)
+                    .await?;
+
+                let rating = match rating {
+                    Some(rating) => {
+                        audit::target("rating", &rating.id);
+
+                        // hidden until the other side rates back, to prevent retaliation
+                        let ratings = self
+                            .repository
+                            .get_ratings(&Query::eq("request_id", &request.id))
+                            .await?;
+
+                        let rating = if ratings.len() > 1 {
+                            reveal(self.repository.as_ref(), ratings)
+                                .await?
+                                .into_iter()
+                                .find(|r| r.id == rating.id)
+                                .unwrap_or(rating)
+                        } else {
+                            rating
+                        };
+
+                        audit::after(&rating);
+
+                        Some(rating)
                     }
 
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
+                    None => None,
+                };
 
-                        Err(s)
-                    }
-                }
+                Ok(Response::new(create::Response { rating }))
             }
 
-            _ => Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -84,37 +245,23 @@ impl ServiceRating for ServiceRatingService {
 
         match payload {
             Some(payload) => {
-                let get::Payload { column, filter } = payload;
-                let res = self
-                    .db_client
-                    .from("service_rating")
-                    .eq(column, filter)
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let ratings = res.json::<Vec<TServiceRating>>().await.unwrap();
-                        Ok(Response::new(get::Response { ratings }))
-                    }
+                let mut page = query::parse(payload.query, &QUERY_COLUMNS)?;
 
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
+                page.query.filters.extend([
+                    ("revealed".to_string(), Filter::Eq("true".to_string())),
+                    ("hidden".to_string(), Filter::Eq("false".to_string())),
+                ]);
 
-                        Err(s)
-                    }
-                }
+                let (ratings, next_page_token) =
+                    page.split(self.repository.get_ratings(&page.query).await?);
+
+                Ok(Response::new(get::Response {
+                    ratings,
+                    next_page_token,
+                }))
             }
 
-            _ => Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            )),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -122,42 +269,43 @@ impl ServiceRating for ServiceRatingService {
         &self,
         request: Request<delete::Request>,
     ) -> Result<Response<delete::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
                 let delete::Payload { rating_id } = payload;
 
-                let res = self
-                    .db_client
-                    .rpc(
-                        "rating_delete",
-                        json!({ "_rating_id": rating_id }).to_string(),
-                    )
-                    .execute()
-                    .await
-                    .unwrap();
+                let rating = self
+                    .repository
+                    .get_ratings(&Query::eq("id", &rating_id))
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::NotFound)?;
 
-                match res.status() {
-                    StatusCode::NO_CONTENT | StatusCode::OK => {
-                        Ok(Response::new(delete::Response {}))
-                    }
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
-
-                        Err(s)
-                    }
-                }
+                audit::target("rating", &rating.id);
+                audit::before(&rating);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::DeleteRating {
+                        author: &rating.user_id,
+                    },
+                )?;
+
+                ensure_editable(&rating, self.edit_window)?;
+
+                self.repository.delete_rating(&rating_id).await?;
+
+                reputation::record(self.repository.as_ref(), &rating, None, Some(rating.value))
+                    .await?;
+
+                Ok(Response::new(delete::Response {}))
             }
 
-            _ => Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            )),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -165,46 +313,230 @@ impl ServiceRating for ServiceRatingService {
         &self,
         request: Request<update::Request>,
     ) -> Result<Response<update::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
-                let update::Payload { rating_id, body } = payload;
-
-                let res = self
-                    .db_client
-                    .from("service_rating")
-                    .eq("id", rating_id)
-                    .update(body)
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values = res.json::<Vec<TServiceRating>>().await.unwrap();
-
-                        Ok(Response::new(update::Response {
-                            rating: values.into_iter().next(),
-                        }))
-                    }
+                let update::Payload {
+                    rating_id,
+                    rating,
+                    update_mask,
+                } = payload;
 
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
+                let patch =
+                    field_mask::patch(rating.as_ref(), update_mask.as_ref(), MUTABLE_FIELDS)?;
 
-                        Err(s)
-                    }
+                if let Some(rating) = rating.as_ref().filter(|_| patch.contains_key("value")) {
+                    ensure_value(rating.value)?;
                 }
+
+                let current = self
+                    .repository
+                    .get_ratings(&Query::eq("id", &rating_id))
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("rating", &rating_id);
+                audit::before(&current);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::UpdateRating {
+                        author: &current.user_id,
+                    },
+                )?;
+
+                ensure_editable(&current, self.edit_window)?;
+
+                // the previous values stay readable by the admins
+                self.repository
+                    .create_rating_version(TRatingVersion {
+                        rating_id: current.id.clone(),
+                        value: current.value,
+                        comment: current.comment.clone(),
+                        edited_by: actor.id.clone(),
+                        ..Default::default()
+                    })
+                    .await?;
+
+                let rating = self
+                    .repository
+                    .update_rating(&rating_id, field_mask::apply(&current, patch)?)
+                    .await?;
+
+                if let Some(rating) = &rating {
+                    audit::after(rating);
+
+                    reputation::record(
+                        self.repository.as_ref(),
+                        rating,
+                        Some(rating.value),
+                        Some(current.value),
+                    )
+                    .await?;
+                }
+
+                Ok(Response::new(update::Response { rating }))
             }
 
-            _ => Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            )),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::testing::{self, PROVIDER, REQUESTOR};
+
+    fn service(repository: &Arc<dyn Repository>) -> ServiceRatingService {
+        let config = RatingConfig {
+            reveal_window_hours: 24,
+            edit_window_hours: 24,
+        };
+
+        ServiceRatingService::new(repository.clone(), &config)
+    }
+
+    async fn rate(
+        service: &ServiceRatingService,
+        user_id: &str,
+        request_id: &str,
+    ) -> TServiceRating {
+        service
+            .create(testing::request(
+                user_id,
+                create::Request {
+                    payload: Some(create::Payload {
+                        request_id: request_id.to_string(),
+                        value: 4,
+                        ..Default::default()
+                    }),
+                },
+            ))
+            .await
+            .unwrap()
+            .into_inner()
+            .rating
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn ratings_stay_hidden_until_the_other_side_rates_back() {
+        let repository = testing::repository();
+        let service = service(&repository);
+        let request = testing::completed_request(&repository, 4.0).await;
+
+        let first = rate(&service, REQUESTOR, &request.id).await;
+
+        assert!(!first.revealed);
+        assert_eq!(first.rated_user_id, PROVIDER);
+        assert!(
+            testing::tally(repository.as_ref(), PROVIDER, ReputationRole::Provider)
+                .await
+                .is_none()
+        );
+
+        let second = rate(&service, PROVIDER, &request.id).await;
+        assert!(second.revealed);
+
+        let ratings = repository
+            .get_ratings(&Query::eq("request_id", &request.id))
+            .await
+            .unwrap();
+        assert!(ratings.iter().all(|r| r.revealed));
+
+        for (user_id, role) in [
+            (PROVIDER, ReputationRole::Provider),
+            (REQUESTOR, ReputationRole::Requestor),
+        ] {
+            let tally = testing::tally(repository.as_ref(), user_id, role).await;
+            assert_eq!(tally.map(|t| t.count), Some(1), "{user_id}");
+        }
+    }
+
+    #[tokio::test]
+    async fn ratings_are_counted_once_however_often_they_are_revealed() {
+        let repository = testing::repository();
+        let service = service(&repository);
+        let request = testing::completed_request(&repository, 4.0).await;
+
+        // both copies are from before the reveal, as two concurrent reveals would have them
+        let stale = rate(&service, REQUESTOR, &request.id).await;
+
+        reveal(repository.as_ref(), vec![stale.clone()])
+            .await
+            .unwrap();
+        reveal(repository.as_ref(), vec![stale]).await.unwrap();
+
+        let tally = testing::tally(repository.as_ref(), PROVIDER, ReputationRole::Provider).await;
+        assert_eq!(tally.map(|t| t.count), Some(1));
+    }
+
+    #[tokio::test]
+    async fn ratings_are_revealed_once_the_window_runs_out() {
+        let repository = testing::repository();
+        let service = service(&repository);
+        let request = testing::completed_request(&repository, 4.0).await;
+
+        let rating = rate(&service, REQUESTOR, &request.id).await;
+
+        reveal_expired(repository.as_ref(), Duration::from_secs(3600))
+            .await
+            .unwrap();
+        assert!(
+            !repository
+                .get_ratings(&Query::eq("id", &rating.id))
+                .await
+                .unwrap()[0]
+                .revealed
+        );
+
+        reveal_expired(repository.as_ref(), Duration::ZERO)
+            .await
+            .unwrap();
+        assert!(
+            repository
+                .get_ratings(&Query::eq("id", &rating.id))
+                .await
+                .unwrap()[0]
+                .revealed
+        );
+    }
+
+    #[tokio::test]
+    async fn revealed_ratings_can_no_longer_be_changed() {
+        let repository = testing::repository();
+        let service = service(&repository);
+        let request = testing::completed_request(&repository, 4.0).await;
+
+        let first = rate(&service, REQUESTOR, &request.id).await;
+        rate(&service, PROVIDER, &request.id).await;
+
+        let status = service
+            .delete(testing::request(
+                REQUESTOR,
+                delete::Request {
+                    payload: Some(delete::Payload {
+                        rating_id: first.id.clone(),
+                    }),
+                },
+            ))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::FailedPrecondition);
+        assert_eq!(
+            repository
+                .get_ratings(&Query::eq("id", &first.id))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
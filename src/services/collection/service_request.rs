@@ -1,31 +1,57 @@
-// TODO:
-// include db_client's response error in the rpc response metadata
+use std::sync::Arc;
 
-use postgrest::Postgrest;
-use reqwest::StatusCode;
-use serde_json::json;
-use tonic::{Request, Response, Status};
+use tonic::{Request, Response};
 
 use crate::{
+    error::Error,
+    events::EventBus,
+    middleware::audit,
+    policy::{self, Action},
+    proto::timebank::reputation::ReputationRole,
     proto::timebank::servicerequest::service_request_server::ServiceRequest,
     proto::timebank::servicerequest::{
-        complete_service, create, delete, get, get_rating, select_bid, update,
+        complete_service, create, delete, get, get_rating, select_bid, update, TServiceRequest,
+    },
+    proto::timebank::servicerequestbid::BidStatus,
+    proto::timebank::servicerequeststatus::TStatusTransition,
+    proto::timebank::watch::{bid_event, request_event},
+    repository::{Filter, Query, Repository},
+    services::{
+        collection::service_request_bid,
+        collection::service_request_status::{self, RequestStatus},
+        field_mask, ledger, query, taxonomy,
+        util::helper,
+        verification, Result,
     },
-    proto::timebank::{servicerating::TServiceRating, servicerequest::TServiceRequest},
-    services::{error_messages, util, Result},
 };
 
 pub use crate::proto::timebank::servicerequest::service_request_server::ServiceRequestServer;
 
+// fields clients are allowed to change through `update`
+const MUTABLE_FIELDS: &[&str] = &["request_data", "category_ids"];
+
+// `category_ids` also matches the categories under the ones filtered on
+const QUERY_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &[
+        "id",
+        "requestor",
+        "status",
+        "created_at",
+        "category_ids",
+        "request_data.*",
+    ],
+    order: &["created_at", "status", "request_data.*"],
+};
+
 pub struct ServiceRequestService {
-    db_client: Postgrest,
+    repository: Arc<dyn Repository>,
+    events: EventBus,
 }
 
 impl ServiceRequestService {
-    pub fn new() -> Self {
-        Self {
-            db_client: util::miscellaneous::create_postgrest_client(),
-        }
+    pub fn new(repository: Arc<dyn Repository>, events: EventBus) -> Self {
+        Self { repository, events }
     }
 }
 
@@ -35,90 +61,113 @@ impl ServiceRequest for ServiceRequestService {
         &self,
         request: Request<create::Request>,
     ) -> Result<Response<create::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
-            Some(create::Payload {
-                request_data,
-                requestor,
-            }) => {
-                let res = self
-                    .db_client
-                    .rpc(
-                        "service_request_create",
-                        json!({
-                            "_requestor": requestor,
-                            "_request": request_data
+            Some(payload) => {
+                let requestor = helper::actor(self.repository.as_ref(), user).await?.id;
+
+                verification::ensure_verified(self.repository.as_ref(), &requestor).await?;
+
+                taxonomy::ensure_categories(self.repository.as_ref(), &payload.category_ids)
+                    .await?;
+
+                let mut request = self.repository.create_request(&requestor, payload).await?;
+
+                if let Some(request) = &mut request {
+                    audit::target("service_request", &request.id);
+
+                    self.repository
+                        .record_transition(TStatusTransition {
+                            request_id: request.id.clone(),
+                            from_status: RequestStatus::Unspecified as i32,
+                            to_status: RequestStatus::Open as i32,
+                            user_id: requestor,
+                            ..Default::default()
                         })
-                        .to_string(),
-                    )
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values: Vec<TServiceRequest> = res.json().await.unwrap();
-
-                        Ok(Response::new(create::Response {
-                            request: values.into_iter().next(),
-                        }))
-                    }
-
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
-
-                        Err(s)
-                    }
+                        .await?;
+
+                    request.status = RequestStatus::Open as i32;
+                    audit::after(request);
+
+                    self.events
+                        .publish_request(request_event::Kind::Created, request);
                 }
+
+                Ok(Response::new(create::Response { request }))
             }
 
-            _ => Err(Status::internal(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
-    // updating a column with json-type value must also include all values
-    // that are not being updated
     async fn update(
         &self,
         request: Request<update::Request>,
     ) -> Result<Response<update::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) if !payload.request_id.is_empty() => {
-                let update::Payload { update, request_id } = payload;
-
-                let res = self
-                    .db_client
-                    .from("service_request")
-                    .eq("id", request_id)
-                    .update(update)
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values: Vec<TServiceRequest> = res.json().await.unwrap();
-
-                        Ok(Response::new(update::Response {
-                            request: values.into_iter().next(),
-                        }))
-                    }
-
-                    StatusCode::BAD_REQUEST => {
-                        Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD))
-                    }
-
-                    _ => Err(Status::unknown(error_messages::UNKNOWN)),
+                let update::Payload {
+                    request_id,
+                    request,
+                    update_mask,
+                } = payload;
+
+                let patch =
+                    field_mask::patch(request.as_ref(), update_mask.as_ref(), MUTABLE_FIELDS)?;
+
+                let current = self
+                    .repository
+                    .get_request(&request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("service_request", &request_id);
+                audit::before(&current);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::UpdateRequest {
+                        requestor: &current.requestor,
+                    },
+                )?;
+
+                // the request can't change anymore once a provider has agreed to it
+                service_request_status::ensure_status(
+                    self.repository.as_ref(),
+                    &request_id,
+                    &[RequestStatus::Open, RequestStatus::BiddingClosed],
+                )
+                .await?;
+
+                if let Some(request) = request
+                    .as_ref()
+                    .filter(|_| patch.contains_key("category_ids"))
+                {
+                    taxonomy::ensure_categories(self.repository.as_ref(), &request.category_ids)
+                        .await?;
+                }
+
+                let request = self
+                    .repository
+                    .update_request(&request_id, field_mask::apply(&current, patch)?)
+                    .await?;
+
+                if let Some(request) = &request {
+                    audit::after(request);
+
+                    self.events
+                        .publish_request(request_event::Kind::Updated, request);
                 }
+
+                Ok(Response::new(update::Response { request }))
             }
-            _ => Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -126,46 +175,58 @@ impl ServiceRequest for ServiceRequestService {
         &self,
         request: Request<delete::Request>,
     ) -> Result<Response<delete::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
-                let res = self
-                    .db_client
-                    .rpc(
-                        "service_request_delete",
-                        json!(
-                            {
-                                "_request_id": payload.request_id
-                            }
-                        )
-                        .to_string(),
-                    )
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::NO_CONTENT | StatusCode::OK => {
-                        Ok(Response::new(delete::Response {}))
-                    }
-
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
-
-                        Err(s)
-                    }
-                }
+                let request = self
+                    .repository
+                    .get_request(&payload.request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("service_request", &request.id);
+                audit::before(&request);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::DeleteRequest {
+                        requestor: &request.requestor,
+                    },
+                )?;
+
+                // assigned requests have to be cancelled first, completed ones are kept for history
+                service_request_status::ensure_status(
+                    self.repository.as_ref(),
+                    &request.id,
+                    &[
+                        RequestStatus::Open,
+                        RequestStatus::BiddingClosed,
+                        RequestStatus::Cancelled,
+                        RequestStatus::Expired,
+                    ],
+                )
+                .await?;
+
+                ledger::release_escrow(
+                    self.repository.as_ref(),
+                    &request.id,
+                    &request.requestor,
+                    "ESCROW REFUNDED",
+                )
+                .await?;
+
+                self.repository.delete_request(&request.id).await?;
+
+                self.events
+                    .publish_request(request_event::Kind::Deleted, &request);
+
+                Ok(Response::new(delete::Response {}))
             }
 
-            _ => Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            )),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -173,54 +234,93 @@ impl ServiceRequest for ServiceRequestService {
         &self,
         request: Request<select_bid::Request>,
     ) -> Result<Response<select_bid::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
-                let res = self
-                    .db_client
-                    .rpc(
-                        "service_request_select_bid",
-                        json!(
-                            {
-                                "_request_id": payload.request_id,
-                                "_bid_id": payload.bid_id
-                            }
-                        )
-                        .to_string(),
+                let request = self
+                    .repository
+                    .get_request(&payload.request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("service_request", &request.id);
+                audit::before(&request);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::SelectBid {
+                        requestor: &request.requestor,
+                    },
+                )?;
+
+                verification::ensure_verified(self.repository.as_ref(), &request.requestor).await?;
+
+                let from = service_request_status::ensure_transition(
+                    self.repository.as_ref(),
+                    &request.id,
+                    RequestStatus::Assigned,
+                )
+                .await?;
+
+                let bid = self
+                    .repository
+                    .get_bids(&Query::eq("id", &payload.bid_id))
+                    .await?
+                    .into_iter()
+                    .find(|b| b.request_id == request.id)
+                    .ok_or(Error::NotFound)?;
+
+                if bid.status() == BidStatus::Withdrawn {
+                    return Err(Error::BidWithdrawn.into());
+                }
+
+                // bids from before amounts were checked could otherwise credit the requestor
+                service_request_bid::ensure_amount(bid.amount)?;
+
+                // bids placed before verification was required may come from unverified users
+                verification::ensure_verified(self.repository.as_ref(), &bid.user_id).await?;
+
+                // the selection, the transition and the escrow are recorded together. The
+                // transition keeps a concurrent selection from escrowing too, only one of them
+                // can move the request out of its current status, and the escrow fails with
+                // `InsufficientBalance` if the requestor can't pay the bid.
+                let mut selected = self
+                    .repository
+                    .select_bid(
+                        &bid.id,
+                        service_request_status::change(
+                            &request.id,
+                            from,
+                            RequestStatus::Assigned,
+                            &actor.id,
+                        ),
+                        ledger::transfer(
+                            &request.requestor,
+                            &ledger::escrow_account(&request.id),
+                            bid.amount,
+                            &request.id,
+                            "ESCROW HELD",
+                        ),
                     )
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values: Vec<TServiceRequest> = res
-                            .json()
-                            .await
-                            .expect("UNABLE TO PARSE JSON AS `Vec<TServiceRequest>`");
-
-                        Ok(Response::new(select_bid::Response {
-                            request: values.into_iter().next(),
-                        }))
-                    }
-
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
-
-                        Err(s)
-                    }
+                    .await?;
+
+                self.events.publish_bid(bid_event::Kind::Selected, &bid);
+
+                if let Some(selected) = &mut selected {
+                    selected.status = RequestStatus::Assigned as i32;
+                    audit::after(selected);
+
+                    self.events
+                        .publish_request(request_event::Kind::Assigned, selected);
                 }
+
+                Ok(Response::new(select_bid::Response { request: selected }))
             }
 
-            _ => Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            )),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -228,36 +328,30 @@ impl ServiceRequest for ServiceRequestService {
         &self,
         request: Request<get_rating::Request>,
     ) -> Result<Response<get_rating::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
-                let res = self
-                    .db_client
-                    .from("service_rating")
-                    .eq("request_id", payload.request_id)
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values: Vec<TServiceRating> = res.json().await.unwrap();
-
-                        Ok(Response::new(get_rating::Response {
-                            rating: values.into_iter().next(),
-                        }))
-                    }
-
-                    StatusCode::BAD_REQUEST => {
-                        Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD))
-                    }
-
-                    _ => Err(Status::unknown(error_messages::UNKNOWN)),
-                }
+                // unrevealed ratings are only shown to their author, those hidden by moderators to no one
+                let ratings: Vec<_> = self
+                    .repository
+                    .get_ratings(&Query::eq("request_id", &payload.request_id))
+                    .await?
+                    .into_iter()
+                    .filter(|r| !r.hidden && (r.revealed || r.user_id == user.id))
+                    .collect();
+
+                // the requestor's rating of the provider, for clients that expect a single one
+                let rating = ratings
+                    .iter()
+                    .find(|r| r.role() == ReputationRole::Provider)
+                    .cloned();
+
+                Ok(Response::new(get_rating::Response { rating, ratings }))
             }
 
-            _ => Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -265,30 +359,26 @@ impl ServiceRequest for ServiceRequestService {
         let payload = request.into_inner().payload;
 
         match payload {
-            Some(get::Payload { column, filter }) => {
-                let res = self
-                    .db_client
-                    .from("service_request")
-                    .eq(column, filter)
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let requests: Vec<TServiceRequest> = res.json().await.unwrap();
-
-                        Ok(Response::new(get::Response { requests }))
-                    }
-
-                    _ => Err(Status::unknown(error_messages::UNKNOWN)),
-                }
+            Some(payload) => {
+                let mut page = query::parse(payload.query, &QUERY_COLUMNS)?;
+                taxonomy::expand(
+                    self.repository.as_ref(),
+                    &mut page.query,
+                    "category_ids",
+                    Filter::Overlaps,
+                )
+                .await?;
+
+                let (requests, next_page_token) =
+                    page.split(self.repository.get_requests(&page.query).await?);
+
+                Ok(Response::new(get::Response {
+                    requests,
+                    next_page_token,
+                }))
             }
 
-            _ => Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            )),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -296,43 +386,198 @@ impl ServiceRequest for ServiceRequestService {
         &self,
         request: Request<complete_service::Request>,
     ) -> Result<Response<complete_service::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
-            Some(complete_service::Payload {
-                request_id,
-                user_id,
-            }) => {
-                let res = self
-                    .db_client
-                    .rpc(
-                        "service_request_complete_service",
-                        json!({
-                            "_user_id": user_id,
-                            "_request_id": request_id
-                        })
-                        .to_string(),
-                    )
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::NO_CONTENT => Ok(Response::new(complete_service::Response {})),
-
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
-
-                        Err(s)
-                    }
-                }
+            Some(complete_service::Payload { request_id, .. }) => {
+                let request = self
+                    .repository
+                    .get_request(&request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("service_request", &request.id);
+                audit::before(&request);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::CompleteService {
+                        requestor: &request.requestor,
+                    },
+                )?;
+
+                let bid = self
+                    .repository
+                    .get_selected_bid(&request_id)
+                    .await?
+                    .ok_or(Error::NoBidSelected)?;
+
+                // the transition and the payout are recorded together, so a request can only be
+                // completed, and its provider paid with the credits held since their bid was
+                // selected, once
+                service_request_status::transition_releasing_escrow(
+                    self.repository.as_ref(),
+                    &request.id,
+                    RequestStatus::Completed,
+                    &actor.id,
+                    &bid.user_id,
+                    "SERVICE COMPLETED",
+                )
+                .await?;
+
+                let completed = TServiceRequest {
+                    status: RequestStatus::Completed as i32,
+                    ..request
+                };
+
+                audit::after(&completed);
+
+                self.events
+                    .publish_request(request_event::Kind::Completed, &completed);
+
+                Ok(Response::new(complete_service::Response {}))
             }
 
-            _ => Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::ledger::OPENING_BALANCE;
+    use crate::services::testing::{self, PROVIDER, REQUESTOR};
+
+    async fn balance(repository: &dyn Repository, account: &str) -> f64 {
+        repository.get_balance(account, None).await.unwrap()
+    }
+
+    fn select(request_id: &str, bid_id: &str) -> Request<select_bid::Request> {
+        testing::request(
+            REQUESTOR,
+            select_bid::Request {
+                payload: Some(select_bid::Payload {
+                    request_id: request_id.to_string(),
+                    bid_id: bid_id.to_string(),
+                }),
+            },
+        )
+    }
+
+    fn complete(user_id: &str, request_id: &str) -> Request<complete_service::Request> {
+        testing::request(
+            user_id,
+            complete_service::Request {
+                payload: Some(complete_service::Payload {
+                    request_id: request_id.to_string(),
+                    ..Default::default()
+                }),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn selecting_a_bid_holds_its_amount_in_escrow() {
+        let repository = testing::repository();
+        let service = ServiceRequestService::new(repository.clone(), EventBus::new());
+        let (request, bid) = testing::bid_on_request(&repository, 4.0).await;
+
+        service
+            .select_bid(select(&request.id, &bid.id))
+            .await
+            .unwrap();
+
+        let status = repository.get_status(&request.id).await.unwrap();
+        assert_eq!(status, RequestStatus::Assigned);
+
+        let escrow = ledger::escrow_account(&request.id);
+        assert_eq!(
+            balance(repository.as_ref(), REQUESTOR).await,
+            OPENING_BALANCE - 4.0
+        );
+        assert_eq!(balance(repository.as_ref(), &escrow).await, 4.0);
+        assert_eq!(
+            balance(repository.as_ref(), PROVIDER).await,
+            OPENING_BALANCE
+        );
+    }
+
+    #[tokio::test]
+    async fn completing_the_service_pays_the_provider_once() {
+        let repository = testing::repository();
+        let service = ServiceRequestService::new(repository.clone(), EventBus::new());
+        let (request, bid) = testing::bid_on_request(&repository, 4.0).await;
+
+        service
+            .select_bid(select(&request.id, &bid.id))
+            .await
+            .unwrap();
+        service
+            .complete_service(complete(REQUESTOR, &request.id))
+            .await
+            .unwrap();
+
+        let again = service
+            .complete_service(complete(REQUESTOR, &request.id))
+            .await;
+        assert!(again.is_err());
+
+        let escrow = ledger::escrow_account(&request.id);
+        assert_eq!(
+            balance(repository.as_ref(), REQUESTOR).await,
+            OPENING_BALANCE - 4.0
+        );
+        assert_eq!(balance(repository.as_ref(), &escrow).await, 0.0);
+        assert_eq!(
+            balance(repository.as_ref(), PROVIDER).await,
+            OPENING_BALANCE + 4.0
+        );
+    }
+
+    #[tokio::test]
+    async fn only_the_requestor_completes_the_service() {
+        let repository = testing::repository();
+        let service = ServiceRequestService::new(repository.clone(), EventBus::new());
+        let (request, bid) = testing::bid_on_request(&repository, 4.0).await;
+
+        service
+            .select_bid(select(&request.id, &bid.id))
+            .await
+            .unwrap();
+
+        let status = service
+            .complete_service(complete(PROVIDER, &request.id))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), tonic::Code::PermissionDenied);
+        assert_eq!(
+            balance(repository.as_ref(), PROVIDER).await,
+            OPENING_BALANCE
+        );
+    }
+
+    #[tokio::test]
+    async fn bids_the_requestor_cannot_pay_are_not_selected() {
+        let repository = testing::repository();
+        let service = ServiceRequestService::new(repository.clone(), EventBus::new());
+        let (request, bid) = testing::bid_on_request(&repository, OPENING_BALANCE + 2.0).await;
+
+        let status = service
+            .select_bid(select(&request.id, &bid.id))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.message(), Error::InsufficientBalance.to_string());
+        assert_eq!(
+            balance(repository.as_ref(), REQUESTOR).await,
+            OPENING_BALANCE
+        );
+
+        let status = repository.get_status(&request.id).await.unwrap();
+        assert_eq!(status, RequestStatus::Open);
+    }
+}
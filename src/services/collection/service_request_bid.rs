@@ -1,51 +1,117 @@
-use postgrest::Postgrest;
-use reqwest::StatusCode;
-use serde_json::json;
-use tonic::{Request, Response, Status};
+use std::sync::Arc;
 
+use serde_json::{json, Map};
+use tonic::{Request, Response};
+
+use crate::error::{self, Error};
+use crate::events::EventBus;
+use crate::middleware::audit;
+use crate::policy::{self, Action};
 use crate::proto::timebank::servicerequestbid::service_request_bid_server::ServiceRequestBid;
-use crate::proto::timebank::servicerequestbid::{create, delete, get, TServiceRequestBid};
-use crate::services::{error_messages, util, Result};
+use crate::proto::timebank::servicerequestbid::{
+    accept_counter_offer, counter_offer, create, delete, get, list_counter_offers, update_bid,
+    withdraw_bid, BidStatus, CounterOfferStatus, TCounterOffer, TServiceRequestBid,
+};
+use crate::proto::timebank::watch::bid_event;
+use crate::repository::{Query, Repository};
+use crate::services::{
+    collection::service_request_status::{self, RequestStatus},
+    query,
+    util::helper,
+    verification, Result,
+};
 
 pub use crate::proto::timebank::servicerequestbid::service_request_bid_server::ServiceRequestBidServer;
 
+const QUERY_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &[
+        "id",
+        "request_id",
+        "user_id",
+        "amount",
+        "status",
+        "created_at",
+    ],
+    order: &["amount", "created_at", "updated_at"],
+};
+
+/// Fails with `Error::InvalidPayload` unless `amount` is a positive number of hours.
+pub fn ensure_amount(amount: f64) -> error::Result<()> {
+    if amount.is_finite() && amount > 0.0 {
+        Ok(())
+    } else {
+        Err(Error::InvalidPayload)
+    }
+}
+
 pub struct ServiceRequestBidService {
-    db_client: Postgrest,
+    repository: Arc<dyn Repository>,
+    events: EventBus,
 }
 
 impl ServiceRequestBidService {
-    pub fn new() -> Self {
-        Self {
-            db_client: util::miscellaneous::create_postgrest_client(),
+    pub fn new(repository: Arc<dyn Repository>, events: EventBus) -> Self {
+        Self { repository, events }
+    }
+
+    async fn bid(&self, bid_id: &str) -> Result<TServiceRequestBid> {
+        Ok(self
+            .repository
+            .get_bids(&Query::eq("id", bid_id))
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(Error::NotFound)?)
+    }
+
+    // bids are only negotiated while active and until the requestor has made their choice
+    async fn ensure_negotiable(&self, bid: &TServiceRequestBid) -> Result<()> {
+        if bid.status() == BidStatus::Withdrawn {
+            return Err(Error::BidWithdrawn.into());
+        }
+
+        if self
+            .repository
+            .get_selected_bid(&bid.request_id)
+            .await?
+            .is_some()
+        {
+            return Err(Error::BidAlreadySelected.into());
+        }
+
+        service_request_status::ensure_status(
+            self.repository.as_ref(),
+            &bid.request_id,
+            &[RequestStatus::Open, RequestStatus::BiddingClosed],
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    // a bid has at most one pending counter-offer, the latest
+    async fn supersede_counter_offers(&self, bid_id: &str) -> Result<()> {
+        let pending = self
+            .repository
+            .get_counter_offers(&Query::eq("bid_id", bid_id))
+            .await?
+            .into_iter()
+            .filter(|o| o.status() == CounterOfferStatus::Pending);
+
+        for offer in pending {
+            self.repository
+                .update_counter_offer(&offer.id, status(CounterOfferStatus::Superseded))
+                .await?;
         }
+
+        Ok(())
     }
 }
 
-// async fn handle_db_response<F, T>(
-//     res: reqwest::Response,
-//     f: F,
-// ) -> std::result::Result<Response<T>, Status>
-// where
-//     F: FnOnce(
-//         reqwest::Response,
-//     ) -> core::pin::Pin<
-//         Box<dyn ::core::future::Future<Output = Result<Response<T>>> + ::core::marker::Send>,
-//     >,
-// {
-//     match res.status() {
-//         StatusCode::OK => f(res).await,
-
-//         _ => {
-//             let mut s = Status::unknown(error_messages::UNKNOWN);
-//             s.metadata_mut().append(
-//                 "error",
-//                 res.text().await.unwrap_or_default().parse().unwrap(),
-//             );
-
-//             Err(s)
-//         }
-//     }
-// }
+fn status(status: CounterOfferStatus) -> Map<String, serde_json::Value> {
+    Map::from_iter([("status".to_string(), json!(status as i32))])
+}
 
 #[tonic::async_trait]
 impl ServiceRequestBid for ServiceRequestBidService {
@@ -53,47 +119,37 @@ impl ServiceRequestBid for ServiceRequestBidService {
         &self,
         request: Request<create::Request>,
     ) -> Result<Response<create::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
-                let res = self
-                    .db_client
-                    .rpc(
-                        "bid_create",
-                        json!({
-                            "_user_id": payload.user_id,
-                            "_request_id": payload.request_id,
-                            "_amount": payload.amount
-                        })
-                        .to_string(),
-                    )
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => {
-                        let values: Vec<TServiceRequestBid> = res.json().await.unwrap();
-
-                        Ok(Response::new(create::Response {
-                            bid: values.into_iter().next(),
-                        }))
-                    }
-
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
-
-                        Err(s)
-                    }
+                ensure_amount(payload.amount)?;
+
+                let user_id = helper::actor(self.repository.as_ref(), user).await?.id;
+
+                verification::ensure_verified(self.repository.as_ref(), &user_id).await?;
+
+                service_request_status::ensure_status(
+                    self.repository.as_ref(),
+                    &payload.request_id,
+                    &[RequestStatus::Open],
+                )
+                .await?;
+
+                let bid = self.repository.create_bid(&user_id, payload).await?;
+
+                if let Some(bid) = &bid {
+                    audit::target("bid", &bid.id);
+                    audit::after(bid);
+
+                    self.events.publish_bid(bid_event::Kind::Created, bid);
                 }
+
+                Ok(Response::new(create::Response { bid }))
             }
 
-            _ => Err(Status::invalid_argument(error_messages::INVALID_PAYLOAD)),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
@@ -101,80 +157,323 @@ impl ServiceRequestBid for ServiceRequestBidService {
         &self,
         request: Request<delete::Request>,
     ) -> Result<Response<delete::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let bid = self
+                    .repository
+                    .get_bids(&Query::eq("id", &payload.bid_id))
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("bid", &bid.id);
+                audit::before(&bid);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::DeleteBid {
+                        bidder: &bid.user_id,
+                    },
+                )?;
+
+                // bids can't be taken back once the requestor has made their choice
+                service_request_status::ensure_status(
+                    self.repository.as_ref(),
+                    &bid.request_id,
+                    &[RequestStatus::Open, RequestStatus::BiddingClosed],
+                )
+                .await?;
+
+                self.repository.delete_bid(&bid.id).await?;
+
+                self.events.publish_bid(bid_event::Kind::Deleted, &bid);
+
+                Ok(Response::new(delete::Response {}))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn update_bid(
+        &self,
+        request: Request<update_bid::Request>,
+    ) -> Result<Response<update_bid::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
-                let res = self
-                    .db_client
-                    .rpc(
-                        "bid_delete",
-                        json!({
-                            "_bid_id": payload.bid_id
-                        })
-                        .to_string(),
+                ensure_amount(payload.amount)?;
+
+                let bid = self.bid(&payload.bid_id).await?;
+
+                audit::target("bid", &bid.id);
+                audit::before(&bid);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::UpdateBid {
+                        bidder: &bid.user_id,
+                    },
+                )?;
+
+                self.ensure_negotiable(&bid).await?;
+
+                // the requestor countered the previous amount, not this one
+                self.supersede_counter_offers(&bid.id).await?;
+
+                let bid = self
+                    .repository
+                    .update_bid(
+                        &bid.id,
+                        Map::from_iter([("amount".to_string(), json!(payload.amount))]),
                     )
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => Ok(Response::new(delete::Response {})),
-
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
-
-                        Err(s)
-                    }
+                    .await?;
+
+                if let Some(bid) = &bid {
+                    audit::after(bid);
+
+                    self.events.publish_bid(bid_event::Kind::Updated, bid);
                 }
+
+                Ok(Response::new(update_bid::Response { bid }))
             }
 
-            _ => Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            )),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 
-    async fn get(&self, request: Request<get::Request>) -> Result<Response<get::Response>> {
+    async fn withdraw_bid(
+        &self,
+        request: Request<withdraw_bid::Request>,
+    ) -> Result<Response<withdraw_bid::Response>> {
+        let user = helper::authenticated_user(&request)?;
         let payload = request.into_inner().payload;
 
         match payload {
             Some(payload) => {
-                let get::Payload { column, filter } = payload;
-                let res = self
-                    .db_client
-                    .from("service_request_bid")
-                    .eq(column, filter)
-                    .execute()
-                    .await
-                    .unwrap();
-
-                match res.status() {
-                    StatusCode::OK => Ok(Response::new(get::Response {
-                        bids: res.json::<Vec<TServiceRequestBid>>().await.unwrap(),
-                    })),
-
-                    _ => {
-                        let mut s = Status::unknown(error_messages::UNKNOWN);
-                        s.metadata_mut().append(
-                            "error",
-                            res.text().await.unwrap_or_default().parse().unwrap(),
-                        );
-
-                        Err(s)
-                    }
+                let bid = self.bid(&payload.bid_id).await?;
+
+                audit::target("bid", &bid.id);
+                audit::before(&bid);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::WithdrawBid {
+                        bidder: &bid.user_id,
+                    },
+                )?;
+
+                self.ensure_negotiable(&bid).await?;
+                self.supersede_counter_offers(&bid.id).await?;
+
+                // unlike `delete` the bid is kept, so the requestor can see it was withdrawn
+                let bid = self
+                    .repository
+                    .update_bid(
+                        &bid.id,
+                        Map::from_iter([(
+                            "status".to_string(),
+                            json!(BidStatus::Withdrawn as i32),
+                        )]),
+                    )
+                    .await?;
+
+                if let Some(bid) = &bid {
+                    audit::after(bid);
+
+                    self.events.publish_bid(bid_event::Kind::Withdrawn, bid);
                 }
+
+                Ok(Response::new(withdraw_bid::Response { bid }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn counter_offer(
+        &self,
+        request: Request<counter_offer::Request>,
+    ) -> Result<Response<counter_offer::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                ensure_amount(payload.amount)?;
+
+                let bid = self.bid(&payload.bid_id).await?;
+
+                let service_request = self
+                    .repository
+                    .get_request(&bid.request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::CounterOffer {
+                        requestor: &service_request.requestor,
+                    },
+                )?;
+
+                self.ensure_negotiable(&bid).await?;
+                self.supersede_counter_offers(&bid.id).await?;
+
+                let offer = self
+                    .repository
+                    .create_counter_offer(TCounterOffer {
+                        bid_id: bid.id,
+                        user_id: actor.id,
+                        amount: payload.amount,
+                        message: payload.message,
+                        status: CounterOfferStatus::Pending as i32,
+                        ..Default::default()
+                    })
+                    .await?;
+
+                audit::target("counter_offer", &offer.id);
+                audit::after(&offer);
+
+                Ok(Response::new(counter_offer::Response {
+                    counter_offer: Some(offer),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn accept_counter_offer(
+        &self,
+        request: Request<accept_counter_offer::Request>,
+    ) -> Result<Response<accept_counter_offer::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let offer = self
+                    .repository
+                    .get_counter_offers(&Query::eq("id", &payload.counter_offer_id))
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::NotFound)?;
+
+                let bid = self.bid(&offer.bid_id).await?;
+
+                audit::target("bid", &bid.id);
+                audit::before(&bid);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::AcceptCounterOffer {
+                        bidder: &bid.user_id,
+                    },
+                )?;
+
+                if offer.status() != CounterOfferStatus::Pending {
+                    return Err(Error::CounterOfferNotPending.into());
+                }
+
+                self.ensure_negotiable(&bid).await?;
+
+                self.repository
+                    .update_counter_offer(&offer.id, status(CounterOfferStatus::Accepted))
+                    .await?;
+
+                let bid = self
+                    .repository
+                    .update_bid(
+                        &bid.id,
+                        Map::from_iter([("amount".to_string(), json!(offer.amount))]),
+                    )
+                    .await?;
+
+                if let Some(bid) = &bid {
+                    audit::after(bid);
+
+                    self.events.publish_bid(bid_event::Kind::Updated, bid);
+                }
+
+                Ok(Response::new(accept_counter_offer::Response { bid }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn list_counter_offers(
+        &self,
+        request: Request<list_counter_offers::Request>,
+    ) -> Result<Response<list_counter_offers::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let bid = self.bid(&payload.bid_id).await?;
+
+                let service_request = self
+                    .repository
+                    .get_request(&bid.request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::ViewCounterOffers {
+                        requestor: &service_request.requestor,
+                        bidder: &bid.user_id,
+                    },
+                )?;
+
+                let counter_offers = self
+                    .repository
+                    .get_counter_offers(&Query {
+                        order: vec![("created_at".to_string(), false)],
+                        ..Query::eq("bid_id", &bid.id)
+                    })
+                    .await?;
+
+                Ok(Response::new(list_counter_offers::Response {
+                    counter_offers,
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn get(&self, request: Request<get::Request>) -> Result<Response<get::Response>> {
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let page = query::parse(payload.query, &QUERY_COLUMNS)?;
+                let (bids, next_page_token) =
+                    page.split(self.repository.get_bids(&page.query).await?);
+
+                Ok(Response::new(get::Response {
+                    bids,
+                    next_page_token,
+                }))
             }
 
-            _ => Err(Status::new(
-                tonic::Code::InvalidArgument,
-                error_messages::INVALID_PAYLOAD,
-            )),
+            _ => Err(Error::InvalidPayload.into()),
         }
     }
 }
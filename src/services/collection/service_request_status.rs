@@ -0,0 +1,379 @@
+// Lifecycle of a service request:
+//
+//   open <-> bidding closed -> assigned -> in progress -> completed
+//
+// with requests that are not completed yet being able to be cancelled, open ones
+// expiring and assigned ones being disputed.
+
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::Utc;
+use tonic::{Request, Response};
+
+use crate::error::{self, Error};
+use crate::events::EventBus;
+use crate::middleware::audit;
+use crate::policy::{self, Action};
+use crate::proto::timebank::servicerequest::TServiceRequest;
+use crate::proto::timebank::servicerequeststatus::service_request_status_server::ServiceRequestStatus;
+use crate::proto::timebank::servicerequeststatus::{get, get_history, update, TStatusTransition};
+use crate::proto::timebank::watch::request_event;
+use crate::repository::{Filter, Query, Repository};
+use crate::services::{util::helper, Result};
+
+pub use crate::proto::timebank::servicerequeststatus::service_request_status_server::ServiceRequestStatusServer;
+pub use crate::proto::timebank::servicerequeststatus::RequestStatus;
+
+impl fmt::Display for RequestStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RequestStatus::Unspecified => "UNSPECIFIED",
+            RequestStatus::Open => "OPEN",
+            RequestStatus::BiddingClosed => "BIDDING CLOSED",
+            RequestStatus::Assigned => "ASSIGNED",
+            RequestStatus::InProgress => "IN PROGRESS",
+            RequestStatus::Completed => "COMPLETED",
+            RequestStatus::Cancelled => "CANCELLED",
+            RequestStatus::Disputed => "DISPUTED",
+            RequestStatus::Expired => "EXPIRED",
+        })
+    }
+}
+
+// how often requests left open for too long are looked for
+const EXPIRE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The transition table of the request lifecycle.
+pub fn can_transition(from: RequestStatus, to: RequestStatus) -> bool {
+    use RequestStatus::*;
+
+    matches!(
+        (from, to),
+        (Unspecified, Open)
+            | (Open, BiddingClosed)
+            | (BiddingClosed, Open)
+            | (Open | BiddingClosed, Assigned)
+            | (Open | BiddingClosed, Expired)
+            | (Assigned, InProgress)
+            | (Assigned | InProgress, Disputed)
+            | (Assigned | InProgress | Disputed, Completed)
+            | (
+                Open | BiddingClosed | Assigned | InProgress | Disputed,
+                Cancelled
+            )
+    )
+}
+
+/// Fails with `FailedPrecondition` unless the request currently has one of the `allowed` statuses.
+pub async fn ensure_status(
+    repository: &dyn Repository,
+    request_id: &str,
+    allowed: &[RequestStatus],
+) -> error::Result<RequestStatus> {
+    let status = repository.get_status(request_id).await?;
+
+    if allowed.contains(&status) {
+        Ok(status)
+    } else {
+        Err(Error::InvalidStatus(status))
+    }
+}
+
+/// Fails with `FailedPrecondition` if the request can't move to `to` from its current status.
+pub async fn ensure_transition(
+    repository: &dyn Repository,
+    request_id: &str,
+    to: RequestStatus,
+) -> error::Result<RequestStatus> {
+    let from = repository.get_status(request_id).await?;
+
+    if can_transition(from, to) {
+        Ok(from)
+    } else {
+        Err(Error::InvalidTransition(from, to))
+    }
+}
+
+/// The change of the request from `from` to `to`, made by the user.
+pub fn change(
+    request_id: &str,
+    from: RequestStatus,
+    to: RequestStatus,
+    user_id: &str,
+) -> TStatusTransition {
+    TStatusTransition {
+        request_id: request_id.to_string(),
+        from_status: from as i32,
+        to_status: to as i32,
+        user_id: user_id.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Moves the request to `to`, recording who made the change.
+pub async fn transition(
+    repository: &dyn Repository,
+    request_id: &str,
+    to: RequestStatus,
+    user_id: &str,
+) -> error::Result<TStatusTransition> {
+    let from = ensure_transition(repository, request_id, to).await?;
+
+    repository
+        .record_transition(change(request_id, from, to, user_id))
+        .await
+}
+
+/// Moves the request to `to` like `transition`, paying whatever its escrow holds to `account`
+/// along with it.
+pub async fn transition_releasing_escrow(
+    repository: &dyn Repository,
+    request_id: &str,
+    to: RequestStatus,
+    user_id: &str,
+    account: &str,
+    memo: &str,
+) -> error::Result<TStatusTransition> {
+    let from = ensure_transition(repository, request_id, to).await?;
+
+    repository
+        .release_escrow(change(request_id, from, to, user_id), account, memo)
+        .await
+}
+
+/// Expires the requests that are still open, or have bidding closed, `after` their creation.
+pub async fn expire_stale(
+    repository: &dyn Repository,
+    events: &EventBus,
+    after: Duration,
+) -> error::Result<()> {
+    let cutoff = chrono::Duration::from_std(after).map_err(|e| Error::Internal(e.to_string()))?;
+
+    let stale = repository
+        .get_requests(&Query {
+            filters: vec![
+                (
+                    "status".to_string(),
+                    Filter::In(vec![
+                        (RequestStatus::Open as i32).to_string(),
+                        (RequestStatus::BiddingClosed as i32).to_string(),
+                    ]),
+                ),
+                (
+                    "created_at".to_string(),
+                    Filter::Lt((Utc::now() - cutoff).to_rfc3339()),
+                ),
+            ],
+            ..Default::default()
+        })
+        .await?;
+
+    for service_request in stale {
+        // nobody expires the request, the change is recorded in the requestor's name. A request
+        // whose status changed meanwhile fails the transition and is left as it is.
+        let expired = transition(
+            repository,
+            &service_request.id,
+            RequestStatus::Expired,
+            &service_request.requestor,
+        )
+        .await;
+
+        match expired {
+            Ok(transition) => events.publish_request(
+                request_event::Kind::Updated,
+                &TServiceRequest {
+                    status: transition.to_status,
+                    ..service_request
+                },
+            ),
+
+            Err(e) => log::warn!("UNABLE TO EXPIRE REQUEST {}: {e}", service_request.id),
+        }
+    }
+
+    Ok(())
+}
+
+/// Runs `expire_stale` every minute, for as long as the server runs.
+pub async fn expire_periodically(
+    repository: Arc<dyn Repository>,
+    events: EventBus,
+    after: Duration,
+) {
+    let mut interval = tokio::time::interval(EXPIRE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        if let Err(e) = expire_stale(repository.as_ref(), &events, after).await {
+            log::error!("UNABLE TO EXPIRE REQUESTS: {e}");
+        }
+    }
+}
+
+pub struct ServiceRequestStatusService {
+    repository: Arc<dyn Repository>,
+    events: EventBus,
+}
+
+impl ServiceRequestStatusService {
+    pub fn new(repository: Arc<dyn Repository>, events: EventBus) -> Self {
+        Self { repository, events }
+    }
+}
+
+#[tonic::async_trait]
+impl ServiceRequestStatus for ServiceRequestStatusService {
+    async fn get(&self, request: Request<get::Request>) -> Result<Response<get::Response>> {
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let status = self.repository.get_status(&payload.request_id).await?;
+
+                Ok(Response::new(get::Response {
+                    status: status as i32,
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn update(
+        &self,
+        request: Request<update::Request>,
+    ) -> Result<Response<update::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let service_request = self
+                    .repository
+                    .get_request(&payload.request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("service_request", &service_request.id);
+                audit::before(&service_request);
+
+                let provider = self
+                    .repository
+                    .get_selected_bid(&service_request.id)
+                    .await?
+                    .map(|bid| bid.user_id);
+
+                match payload.status() {
+                    RequestStatus::Open
+                    | RequestStatus::BiddingClosed
+                    | RequestStatus::Cancelled
+                    | RequestStatus::InProgress
+                    | RequestStatus::Disputed => {}
+                    // assigning and completing have their own rpcs, users can't expire requests
+                    _ => return Err(Error::InvalidPayload.into()),
+                }
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::ChangeStatus {
+                        to: payload.status(),
+                        requestor: &service_request.requestor,
+                        provider: provider.as_deref(),
+                    },
+                )?;
+
+                // nothing will be provided anymore, give the credits back to the requestor
+                let transition = if payload.status() == RequestStatus::Cancelled {
+                    transition_releasing_escrow(
+                        self.repository.as_ref(),
+                        &service_request.id,
+                        payload.status(),
+                        &actor.id,
+                        &service_request.requestor,
+                        "ESCROW REFUNDED",
+                    )
+                    .await?
+                } else {
+                    transition(
+                        self.repository.as_ref(),
+                        &service_request.id,
+                        payload.status(),
+                        &actor.id,
+                    )
+                    .await?
+                };
+
+                let updated = TServiceRequest {
+                    status: transition.to_status,
+                    ..service_request
+                };
+
+                audit::after(&updated);
+
+                self.events
+                    .publish_request(request_event::Kind::Updated, &updated);
+
+                Ok(Response::new(update::Response {
+                    transition: Some(transition),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn get_history(
+        &self,
+        request: Request<get_history::Request>,
+    ) -> Result<Response<get_history::Response>> {
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let transitions = self
+                    .repository
+                    .get_status_history(&payload.request_id)
+                    .await?;
+
+                Ok(Response::new(get_history::Response { transitions }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::testing;
+
+    #[tokio::test]
+    async fn requests_left_open_expire() {
+        let repository = testing::repository();
+        let (request, _) = testing::bid_on_request(&repository, 4.0).await;
+
+        expire_stale(
+            repository.as_ref(),
+            &EventBus::new(),
+            Duration::from_secs(3600),
+        )
+        .await
+        .unwrap();
+
+        let status = repository.get_status(&request.id).await.unwrap();
+        assert_eq!(status, RequestStatus::Open);
+
+        expire_stale(repository.as_ref(), &EventBus::new(), Duration::ZERO)
+            .await
+            .unwrap();
+
+        let status = repository.get_status(&request.id).await.unwrap();
+        assert_eq!(status, RequestStatus::Expired);
+    }
+}
@@ -0,0 +1,101 @@
+// Partial updates driven by a `google.protobuf.FieldMask`.
+//
+// Only the paths named in the mask are taken from the message sent by the client, and each of
+// them must be in the entity's whitelist of mutable fields. The resulting patch is deep merged
+// into the current row so nested json columns, eg `request_data`, keep whatever isn't updated.
+
+use prost_types::FieldMask;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::{Error, Result};
+
+/// Builds the patch described by `mask` out of `message`.
+///
+/// A path is allowed if it's in `mutable` or nested under one of its fields, eg
+/// `request_data.title` when `request_data` is mutable.
+pub fn patch<T: Serialize>(
+    message: Option<&T>,
+    mask: Option<&FieldMask>,
+    mutable: &[&str],
+) -> Result<Map<String, Value>> {
+    let paths = mask.map(|m| m.paths.as_slice()).unwrap_or_default();
+
+    if paths.is_empty() {
+        return Err(Error::MissingArgument);
+    }
+
+    let message = serde_json::to_value(message.ok_or(Error::InvalidPayload)?)?;
+    let mut patch = Map::new();
+
+    for path in paths {
+        let allowed = mutable
+            .iter()
+            .any(|field| path == field || path.starts_with(&format!("{field}.")));
+
+        if !allowed {
+            return Err(Error::ImmutableField(path.clone()));
+        }
+
+        let value = path
+            .split('.')
+            .try_fold(&message, |value, key| value.get(key))
+            .ok_or(Error::InvalidPayload)?;
+
+        insert(&mut patch, path, value.clone());
+    }
+
+    Ok(patch)
+}
+
+/// Deep merges `patch` into `row`, returning the new value of every column the patch touches.
+pub fn apply<T: Serialize>(row: &T, patch: Map<String, Value>) -> Result<Map<String, Value>> {
+    let columns: Vec<String> = patch.keys().cloned().collect();
+
+    let mut row = serde_json::to_value(row)?;
+    merge(&mut row, Value::Object(patch));
+
+    Ok(columns
+        .into_iter()
+        .filter_map(|column| {
+            let value = row.get(&column)?.clone();
+            Some((column, value))
+        })
+        .collect())
+}
+
+/// Objects are merged key by key, anything else is replaced.
+pub fn merge(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+
+        (target, patch) => *target = patch,
+    }
+}
+
+// sets the value at the dotted path, creating the intermediate objects
+fn insert(target: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((key, rest)) => {
+            let child = target
+                .entry(key)
+                .or_insert_with(|| Value::Object(Map::new()));
+
+            if !child.is_object() {
+                *child = Value::Object(Map::new());
+            }
+
+            if let Value::Object(child) = child {
+                insert(child, rest, value);
+            }
+        }
+
+        None => {
+            target.insert(path.to_string(), value);
+        }
+    }
+}
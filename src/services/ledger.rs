@@ -0,0 +1,236 @@
+// Service for the time credit ledger, balances are always derived from the transaction history
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use tonic::{Request, Response};
+
+use crate::error::{self, Error};
+use crate::proto::timebank::ledger::ledger_server::Ledger;
+use crate::proto::timebank::ledger::{
+    get_balance, get_statement, list_transactions, TLedgerEntry, TTransaction,
+};
+use crate::repository::{Repository, TransactionFilter};
+use crate::services::{util::helper, Result};
+
+pub use crate::proto::timebank::ledger::ledger_server::LedgerServer;
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+/// Prefix of the ledger accounts holding escrowed credits.
+pub const ESCROW_ACCOUNT_PREFIX: &str = "escrow:";
+
+/// Prefix of the ledger accounts credits are issued from, the only ones that may go below zero.
+pub const SYSTEM_ACCOUNT_PREFIX: &str = "system:";
+
+/// Ledger account the opening balances of new members are drawn from.
+pub const OPENING_ACCOUNT: &str = "system:opening";
+
+/// Hours every member starts with, granted when their profile is created. The database grants
+/// the same amount in `ledger_grant_opening_balance`.
+pub const OPENING_BALANCE: f64 = 10.0;
+
+/// Ledger account holding the credits escrowed for a service request once a bid is selected.
+pub fn escrow_account(request_id: &str) -> String {
+    format!("{ESCROW_ACCOUNT_PREFIX}{request_id}")
+}
+
+/// Builds a balanced transaction that moves `amount` hours from one account to another.
+pub fn transfer(from: &str, to: &str, amount: f64, request_id: &str, memo: &str) -> TTransaction {
+    TTransaction {
+        request_id: request_id.to_string(),
+        memo: memo.to_string(),
+        entries: vec![
+            TLedgerEntry {
+                account: from.to_string(),
+                amount: -amount,
+            },
+            TLedgerEntry {
+                account: to.to_string(),
+                amount,
+            },
+        ],
+        ..Default::default()
+    }
+}
+
+/// The transaction granting a new member their opening balance.
+pub fn opening_balance(user_id: &str) -> TTransaction {
+    transfer(
+        OPENING_ACCOUNT,
+        user_id,
+        OPENING_BALANCE,
+        "",
+        "OPENING BALANCE",
+    )
+}
+
+/// Moves whatever is held in escrow for the request to `to`.
+pub async fn release_escrow(
+    repository: &dyn Repository,
+    request_id: &str,
+    to: &str,
+    memo: &str,
+) -> error::Result<()> {
+    let escrow = escrow_account(request_id);
+    let held = repository.get_balance(&escrow, None).await?;
+
+    if held > 0.0 {
+        repository
+            .record_transaction(transfer(&escrow, to, held, request_id, memo))
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Net amount the transaction moved in or out of the account.
+pub fn net_amount(transaction: &TTransaction, account: &str) -> f64 {
+    transaction
+        .entries
+        .iter()
+        .filter(|e| e.account == account)
+        .map(|e| e.amount)
+        .sum()
+}
+
+// empty strings are treated as "not set"
+fn parse_timestamp(value: &str) -> Result<Option<DateTime<Utc>>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+
+    DateTime::parse_from_rfc3339(value)
+        .map(|t| Some(t.with_timezone(&Utc)))
+        .map_err(|_| Error::InvalidPayload.into())
+}
+
+pub struct LedgerService {
+    repository: Arc<dyn Repository>,
+}
+
+impl LedgerService {
+    pub fn new(repository: Arc<dyn Repository>) -> Self {
+        Self { repository }
+    }
+}
+
+#[tonic::async_trait]
+impl Ledger for LedgerService {
+    async fn get_balance(
+        &self,
+        request: Request<get_balance::Request>,
+    ) -> Result<Response<get_balance::Response>> {
+        let user_id = helper::authenticated_user(&request)?.id;
+
+        let balance = self.repository.get_balance(&user_id, None).await?;
+
+        // the only escrow accounts a user has transactions with are the ones of their own requests
+        let escrowed = self.repository.get_escrowed(&user_id).await?;
+
+        Ok(Response::new(get_balance::Response { balance, escrowed }))
+    }
+
+    async fn list_transactions(
+        &self,
+        request: Request<list_transactions::Request>,
+    ) -> Result<Response<list_transactions::Response>> {
+        let user_id = helper::authenticated_user(&request)?.id;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let page_size = match payload.page_size as usize {
+                    0 => DEFAULT_PAGE_SIZE,
+                    size => size.min(MAX_PAGE_SIZE),
+                };
+
+                let offset = if payload.page_token.is_empty() {
+                    0
+                } else {
+                    payload
+                        .page_token
+                        .parse::<usize>()
+                        .map_err(|_| Error::InvalidPayload)?
+                };
+
+                let filter = TransactionFilter {
+                    from: parse_timestamp(&payload.from)?,
+                    to: parse_timestamp(&payload.to)?,
+                    counterparty: Some(payload.counterparty).filter(|c| !c.is_empty()),
+                    offset,
+                    // fetch one extra to know whether there is a next page
+                    limit: Some(page_size + 1),
+                };
+
+                let mut transactions = self.repository.list_transactions(&user_id, &filter).await?;
+
+                let next_page_token = if transactions.len() > page_size {
+                    transactions.truncate(page_size);
+                    (offset + page_size).to_string()
+                } else {
+                    String::new()
+                };
+
+                Ok(Response::new(list_transactions::Response {
+                    transactions,
+                    next_page_token,
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn get_statement(
+        &self,
+        request: Request<get_statement::Request>,
+    ) -> Result<Response<get_statement::Response>> {
+        let user_id = helper::authenticated_user(&request)?.id;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let from = parse_timestamp(&payload.from)?;
+                let to = parse_timestamp(&payload.to)?;
+
+                let opening_balance = match from {
+                    Some(from) => self.repository.get_balance(&user_id, Some(from)).await?,
+                    None => 0.0,
+                };
+
+                let transactions = self
+                    .repository
+                    .list_transactions(
+                        &user_id,
+                        &TransactionFilter {
+                            from,
+                            to,
+                            ..Default::default()
+                        },
+                    )
+                    .await?;
+
+                let (mut total_credit, mut total_debit) = (0.0, 0.0);
+
+                for transaction in &transactions {
+                    match net_amount(transaction, &user_id) {
+                        amount if amount > 0.0 => total_credit += amount,
+                        amount => total_debit -= amount,
+                    }
+                }
+
+                Ok(Response::new(get_statement::Response {
+                    opening_balance,
+                    closing_balance: opening_balance + total_credit - total_debit,
+                    total_credit,
+                    total_debit,
+                    transactions,
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+}
@@ -0,0 +1,373 @@
+// Service for the direct messages between a requestor and the provider of their request
+
+use std::pin::Pin;
+use std::sync::Arc;
+
+use chrono::Utc;
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::Stream;
+use tonic::{Request, Response, Status, Streaming};
+
+use crate::error::{self, Error};
+use crate::events::{Event, EventBus};
+use crate::middleware::audit;
+use crate::policy::{self, Action, Actor};
+use crate::proto::timebank::messaging::messaging_server::Messaging;
+use crate::proto::timebank::messaging::{
+    chat, get_conversation, list_messages, mark_read, send_message, TConversation, TMessage,
+    TReadReceipt,
+};
+use crate::repository::{Filter, Query, Repository};
+use crate::services::{query, util::helper, Result};
+
+pub use crate::proto::timebank::messaging::messaging_server::MessagingServer;
+
+// events buffered per chat stream before the forwarding task waits for the client
+const CHAT_BUFFER: usize = 64;
+
+/// Returns the requestor and provider of the request, failing unless the actor may access their
+/// conversation.
+async fn participants(
+    repository: &dyn Repository,
+    request_id: &str,
+    actor: &Actor,
+) -> error::Result<(String, String)> {
+    let request = repository
+        .get_request(request_id)
+        .await?
+        .ok_or(Error::NotFound)?;
+
+    // there is no one to talk to until a bid is selected
+    let provider = repository
+        .get_selected_bid(request_id)
+        .await?
+        .ok_or(Error::NoBidSelected)?
+        .user_id;
+
+    policy::authorize(
+        actor,
+        Action::AccessConversation {
+            requestor: &request.requestor,
+            provider: &provider,
+        },
+    )?;
+
+    Ok((request.requestor, provider))
+}
+
+async fn send(
+    repository: &dyn Repository,
+    events: &EventBus,
+    request_id: &str,
+    sender: &str,
+    body: String,
+) -> error::Result<TMessage> {
+    if body.trim().is_empty() {
+        return Err(Error::MissingArgument);
+    }
+
+    let message = repository
+        .create_message(TMessage {
+            request_id: request_id.to_string(),
+            sender: sender.to_string(),
+            body,
+            ..Default::default()
+        })
+        .await?;
+
+    events.publish(Event::Message(message.clone()));
+
+    Ok(message)
+}
+
+async fn read(
+    repository: &dyn Repository,
+    events: &EventBus,
+    request_id: &str,
+    reader: &str,
+) -> error::Result<TReadReceipt> {
+    let messages = repository.mark_read(request_id, reader).await?;
+
+    let receipt = TReadReceipt {
+        request_id: request_id.to_string(),
+        reader: reader.to_string(),
+        message_ids: messages.into_iter().map(|m| m.id).collect(),
+        read_at: Utc::now().to_rfc3339(),
+    };
+
+    if !receipt.message_ids.is_empty() {
+        events.publish(Event::Receipt(receipt.clone()));
+    }
+
+    Ok(receipt)
+}
+
+// handles a payload sent on a chat stream opened for `request_id`
+async fn handle_chat(
+    repository: &dyn Repository,
+    events: &EventBus,
+    request_id: &str,
+    user_id: &str,
+    payload: chat::Payload,
+) -> error::Result<()> {
+    if !payload.request_id.is_empty() && payload.request_id != request_id {
+        return Err(Error::InvalidPayload);
+    }
+
+    if !payload.body.is_empty() {
+        send(repository, events, request_id, user_id, payload.body).await?;
+    }
+
+    if payload.read {
+        read(repository, events, request_id, user_id).await?;
+    }
+
+    Ok(())
+}
+
+pub struct MessagingService {
+    repository: Arc<dyn Repository>,
+    events: EventBus,
+}
+
+impl MessagingService {
+    pub fn new(repository: Arc<dyn Repository>, events: EventBus) -> Self {
+        Self { repository, events }
+    }
+}
+
+#[tonic::async_trait]
+impl Messaging for MessagingService {
+    async fn get_conversation(
+        &self,
+        request: Request<get_conversation::Request>,
+    ) -> Result<Response<get_conversation::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                let (requestor, provider) =
+                    participants(self.repository.as_ref(), &payload.request_id, &actor).await?;
+
+                let unread = self
+                    .repository
+                    .get_messages(&Query {
+                        filters: vec![
+                            (
+                                "request_id".to_string(),
+                                Filter::Eq(payload.request_id.clone()),
+                            ),
+                            ("sender".to_string(), Filter::Neq(actor.id)),
+                            ("read".to_string(), Filter::Eq("false".to_string())),
+                        ],
+                        ..Default::default()
+                    })
+                    .await?;
+
+                Ok(Response::new(get_conversation::Response {
+                    conversation: Some(TConversation {
+                        request_id: payload.request_id,
+                        requestor,
+                        provider,
+                        unread_count: unread.len() as u32,
+                    }),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn send_message(
+        &self,
+        request: Request<send_message::Request>,
+    ) -> Result<Response<send_message::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                participants(self.repository.as_ref(), &payload.request_id, &actor).await?;
+
+                let message = send(
+                    self.repository.as_ref(),
+                    &self.events,
+                    &payload.request_id,
+                    &actor.id,
+                    payload.body,
+                )
+                .await?;
+
+                // private conversations stay out of the audit log, only the id is recorded
+                audit::target("message", &message.id);
+
+                Ok(Response::new(send_message::Response {
+                    message: Some(message),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn list_messages(
+        &self,
+        request: Request<list_messages::Request>,
+    ) -> Result<Response<list_messages::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                participants(self.repository.as_ref(), &payload.request_id, &actor).await?;
+
+                let page = query::page(
+                    Query {
+                        filters: vec![("request_id".to_string(), Filter::Eq(payload.request_id))],
+                        order: vec![("created_at".to_string(), true), ("id".to_string(), false)],
+                        ..Default::default()
+                    },
+                    payload.page_size,
+                    &payload.page_token,
+                )?;
+
+                let (messages, next_page_token) =
+                    page.split(self.repository.get_messages(&page.query).await?);
+
+                Ok(Response::new(list_messages::Response {
+                    messages,
+                    next_page_token,
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn mark_read(
+        &self,
+        request: Request<mark_read::Request>,
+    ) -> Result<Response<mark_read::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                participants(self.repository.as_ref(), &payload.request_id, &actor).await?;
+
+                let receipt = read(
+                    self.repository.as_ref(),
+                    &self.events,
+                    &payload.request_id,
+                    &actor.id,
+                )
+                .await?;
+
+                audit::target("service_request", &payload.request_id);
+
+                Ok(Response::new(mark_read::Response {
+                    receipt: Some(receipt),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    type ChatStream =
+        Pin<Box<dyn Stream<Item = std::result::Result<chat::Response, Status>> + Send>>;
+
+    async fn chat(
+        &self,
+        request: Request<Streaming<chat::Request>>,
+    ) -> Result<Response<Self::ChatStream>> {
+        let user = helper::authenticated_user(&request)?;
+        let mut inbound = request.into_inner();
+
+        // the first payload tells which conversation the stream is for
+        let first = inbound
+            .message()
+            .await?
+            .and_then(|r| r.payload)
+            .ok_or(Error::InvalidPayload)?;
+
+        let request_id = first.request_id.clone();
+
+        let actor = helper::actor(self.repository.as_ref(), user).await?;
+        participants(self.repository.as_ref(), &request_id, &actor).await?;
+
+        // subscribed before handling any payload so the caller also receives their own messages
+        let mut events = self.events.subscribe();
+        let (sender, receiver) = mpsc::channel(CHAT_BUFFER);
+
+        let outbound = sender.clone();
+        let conversation = request_id.clone();
+
+        tokio::spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    // the client went away
+                    _ = outbound.closed() => break,
+                };
+
+                let event = match event {
+                    Ok(Event::Message(message)) if message.request_id == conversation => {
+                        chat::response::Event::Message(message)
+                    }
+
+                    Ok(Event::Receipt(receipt)) if receipt.request_id == conversation => {
+                        chat::response::Event::Receipt(receipt)
+                    }
+
+                    Ok(_) | Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                let response = chat::Response { event: Some(event) };
+
+                if outbound.send(Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let repository = self.repository.clone();
+        let bus = self.events.clone();
+
+        tokio::spawn(async move {
+            let mut payload = first;
+
+            loop {
+                if let Err(error) =
+                    handle_chat(repository.as_ref(), &bus, &request_id, &actor.id, payload).await
+                {
+                    let _ = sender.send(Err(error.into())).await;
+                    break;
+                }
+
+                payload = match inbound.message().await {
+                    Ok(Some(chat::Request {
+                        payload: Some(payload),
+                    })) => payload,
+
+                    Ok(Some(_)) => {
+                        let _ = sender.send(Err(Error::InvalidPayload.into())).await;
+                        break;
+                    }
+
+                    // the client is done sending
+                    Ok(None) | Err(_) => break,
+                };
+            }
+        });
+
+        Ok(Response::new(Box::pin(ReceiverStream::new(receiver))))
+    }
+}
@@ -0,0 +1,611 @@
+// Service for reporting and appealing ratings, and for the moderators to act on them
+
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde_json::{json, Map};
+use tonic::{Request, Response};
+
+use crate::error::{self, Error};
+use crate::middleware::audit;
+use crate::policy::{self, Action};
+use crate::proto::timebank::moderation::moderation_server::Moderation;
+use crate::proto::timebank::moderation::{
+    appeal_rating, dismiss_case, hide_rating, list_cases, report_rating, restore_rating, CaseKind,
+    CaseStatus, TModerationCase,
+};
+use crate::proto::timebank::servicerating::TServiceRating;
+use crate::repository::{Filter, Query, Repository};
+use crate::services::{admin::ensure_reason, query, reputation, util::helper, Result};
+
+pub use crate::proto::timebank::moderation::moderation_server::ModerationServer;
+
+const QUERY_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &["rating_id", "kind", "user_id", "status", "created_at"],
+    order: &["created_at"],
+};
+
+// every pending case about `rating_id`, of `kind` if given
+fn pending(rating_id: &str, kind: Option<CaseKind>) -> Query {
+    let mut filters = vec![
+        ("rating_id".to_string(), Filter::Eq(rating_id.to_string())),
+        (
+            "status".to_string(),
+            Filter::Eq((CaseStatus::Pending as i32).to_string()),
+        ),
+    ];
+
+    if let Some(kind) = kind {
+        filters.push(("kind".to_string(), Filter::Eq((kind as i32).to_string())));
+    }
+
+    Query {
+        filters,
+        ..Default::default()
+    }
+}
+
+fn resolution(status: CaseStatus, moderator: &str, reason: &str) -> Map<String, serde_json::Value> {
+    Map::from_iter([
+        ("status".to_string(), json!(status as i32)),
+        ("resolved_by".to_string(), json!(moderator)),
+        ("resolution".to_string(), json!(reason)),
+        ("resolved_at".to_string(), json!(Utc::now().to_rfc3339())),
+    ])
+}
+
+pub struct ModerationService {
+    repository: Arc<dyn Repository>,
+}
+
+impl ModerationService {
+    pub fn new(repository: Arc<dyn Repository>) -> Self {
+        Self { repository }
+    }
+
+    async fn rating(&self, rating_id: &str) -> error::Result<TServiceRating> {
+        self.repository
+            .get_ratings(&Query::eq("id", rating_id))
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(Error::NotFound)
+    }
+
+    // opens a case about a rating others can see, one pending per user and kind
+    async fn open(
+        &self,
+        rating: &TServiceRating,
+        kind: CaseKind,
+        user_id: &str,
+        reason: String,
+    ) -> error::Result<TModerationCase> {
+        if !rating.revealed || rating.hidden {
+            return Err(Error::NotFound);
+        }
+
+        let opened = self
+            .repository
+            .get_cases(&pending(&rating.id, Some(kind)))
+            .await?;
+
+        if opened.iter().any(|c| c.user_id == user_id) {
+            return Err(Error::AlreadyExists);
+        }
+
+        let case = self
+            .repository
+            .create_case(TModerationCase {
+                rating_id: rating.id.clone(),
+                kind: kind as i32,
+                user_id: user_id.to_string(),
+                reason,
+                status: CaseStatus::Pending as i32,
+                ..Default::default()
+            })
+            .await?;
+
+        audit::target("moderation_case", &case.id);
+        audit::after(&case);
+
+        Ok(case)
+    }
+
+    // sets `hidden` on the rating and moves it in or out of the reputation of the rated user
+    async fn set_hidden(
+        &self,
+        rating: &TServiceRating,
+        hidden: bool,
+    ) -> error::Result<TServiceRating> {
+        let columns = Map::from_iter([("hidden".to_string(), json!(hidden))]);
+
+        let updated = self
+            .repository
+            .update_rating(&rating.id, columns)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+        if hidden {
+            reputation::record(self.repository.as_ref(), rating, None, Some(rating.value)).await?;
+        } else {
+            reputation::record(
+                self.repository.as_ref(),
+                &updated,
+                Some(updated.value),
+                None,
+            )
+            .await?;
+        }
+
+        Ok(updated)
+    }
+}
+
+#[tonic::async_trait]
+impl Moderation for ModerationService {
+    async fn report_rating(
+        &self,
+        request: Request<report_rating::Request>,
+    ) -> Result<Response<report_rating::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                ensure_reason(&payload.reason)?;
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                let rating = self.rating(&payload.rating_id).await?;
+
+                let case = self
+                    .open(&rating, CaseKind::Report, &actor.id, payload.reason)
+                    .await?;
+
+                Ok(Response::new(report_rating::Response {
+                    moderation_case: Some(case),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn appeal_rating(
+        &self,
+        request: Request<appeal_rating::Request>,
+    ) -> Result<Response<appeal_rating::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                ensure_reason(&payload.reason)?;
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                let rating = self.rating(&payload.rating_id).await?;
+
+                policy::authorize(
+                    &actor,
+                    Action::AppealRating {
+                        rated_user: &rating.rated_user_id,
+                    },
+                )?;
+
+                let case = self
+                    .open(&rating, CaseKind::Appeal, &actor.id, payload.reason)
+                    .await?;
+
+                Ok(Response::new(appeal_rating::Response {
+                    moderation_case: Some(case),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn list_cases(
+        &self,
+        request: Request<list_cases::Request>,
+    ) -> Result<Response<list_cases::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload.unwrap_or_default();
+
+        let actor = helper::actor(self.repository.as_ref(), user).await?;
+        policy::authorize(&actor, Action::ListModerationCases)?;
+
+        let unordered = payload.query.as_ref().is_none_or(|q| q.order_by.is_empty());
+        let mut page = query::parse(payload.query, &QUERY_COLUMNS)?;
+
+        // the queue is worked through oldest first, rather than the newest first of `parse`
+        if unordered {
+            for (_, descending) in &mut page.query.order {
+                *descending = false;
+            }
+        }
+
+        let (cases, next_page_token) = page.split(self.repository.get_cases(&page.query).await?);
+
+        Ok(Response::new(list_cases::Response {
+            cases,
+            next_page_token,
+        }))
+    }
+
+    async fn hide_rating(
+        &self,
+        request: Request<hide_rating::Request>,
+    ) -> Result<Response<hide_rating::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                ensure_reason(&payload.reason)?;
+
+                let rating = self.rating(&payload.rating_id).await?;
+
+                audit::target("rating", &rating.id);
+                audit::reason(&payload.reason);
+                audit::before(&rating);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::ModerateRating {
+                        author: &rating.user_id,
+                        rated_user: &rating.rated_user_id,
+                    },
+                )?;
+
+                if rating.hidden {
+                    return Err(Error::RatingAlreadyHidden.into());
+                }
+
+                let hidden = self.set_hidden(&rating, true).await?;
+                audit::after(&hidden);
+
+                let mut cases = Vec::new();
+
+                for case in self
+                    .repository
+                    .get_cases(&pending(&rating.id, None))
+                    .await?
+                {
+                    let columns = resolution(CaseStatus::Actioned, &actor.id, &payload.reason);
+
+                    if let Some(case) = self.repository.update_case(&case.id, columns).await? {
+                        cases.push(case);
+                    }
+                }
+
+                Ok(Response::new(hide_rating::Response { cases }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn restore_rating(
+        &self,
+        request: Request<restore_rating::Request>,
+    ) -> Result<Response<restore_rating::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                ensure_reason(&payload.reason)?;
+
+                let rating = self.rating(&payload.rating_id).await?;
+
+                audit::target("rating", &rating.id);
+                audit::reason(&payload.reason);
+                audit::before(&rating);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::ModerateRating {
+                        author: &rating.user_id,
+                        rated_user: &rating.rated_user_id,
+                    },
+                )?;
+
+                if !rating.hidden {
+                    return Err(Error::RatingNotHidden.into());
+                }
+
+                let restored = self.set_hidden(&rating, false).await?;
+                audit::after(&restored);
+
+                Ok(Response::new(restore_rating::Response {}))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn dismiss_case(
+        &self,
+        request: Request<dismiss_case::Request>,
+    ) -> Result<Response<dismiss_case::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                ensure_reason(&payload.reason)?;
+
+                let case = self
+                    .repository
+                    .get_cases(&Query::eq("id", &payload.case_id))
+                    .await?
+                    .into_iter()
+                    .next()
+                    .ok_or(Error::NotFound)?;
+
+                audit::target("moderation_case", &case.id);
+                audit::reason(&payload.reason);
+                audit::before(&case);
+
+                let rating = self.rating(&case.rating_id).await?;
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::ModerateRating {
+                        author: &rating.user_id,
+                        rated_user: &rating.rated_user_id,
+                    },
+                )?;
+
+                if case.status() != CaseStatus::Pending {
+                    return Err(Error::CaseNotPending.into());
+                }
+
+                let columns = resolution(CaseStatus::Dismissed, &actor.id, &payload.reason);
+                let case = self.repository.update_case(&case.id, columns).await?;
+
+                if let Some(case) = &case {
+                    audit::after(case);
+                }
+
+                Ok(Response::new(dismiss_case::Response {
+                    moderation_case: case,
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RatingConfig;
+    use crate::events::EventBus;
+    use crate::proto::timebank::admin::{admin_server::Admin, remove_rating};
+    use crate::proto::timebank::reputation::ReputationRole;
+    use crate::proto::timebank::role::Role;
+    use crate::proto::timebank::servicerating::{create, service_rating_server::ServiceRating};
+    use crate::services::admin::AdminService;
+    use crate::services::collection::service_rating::ServiceRatingService;
+    use crate::services::testing::{self, PROVIDER, REQUESTOR};
+
+    const REPORTER: &str = "reporter";
+    const MODERATOR: &str = "moderator";
+    const ADMIN: &str = "admin";
+
+    // the requestor's rating of the provider, revealed by the provider rating back
+    async fn rated(repository: &Arc<dyn Repository>) -> TServiceRating {
+        let config = RatingConfig {
+            reveal_window_hours: 24,
+            edit_window_hours: 24,
+        };
+
+        let ratings = ServiceRatingService::new(repository.clone(), &config);
+        let request = testing::completed_request(repository, 4.0).await;
+
+        for user_id in [REQUESTOR, PROVIDER] {
+            ratings
+                .create(testing::request(
+                    user_id,
+                    create::Request {
+                        payload: Some(create::Payload {
+                            request_id: request.id.clone(),
+                            value: 2,
+                            ..Default::default()
+                        }),
+                    },
+                ))
+                .await
+                .unwrap();
+        }
+
+        testing::user(repository.as_ref(), REPORTER, Role::Member).await;
+        testing::user(repository.as_ref(), MODERATOR, Role::Moderator).await;
+
+        repository
+            .get_ratings(&Query::eq("user_id", REQUESTOR))
+            .await
+            .unwrap()
+            .remove(0)
+    }
+
+    fn report(rating_id: &str) -> Request<report_rating::Request> {
+        testing::request(
+            REPORTER,
+            report_rating::Request {
+                payload: Some(report_rating::Payload {
+                    rating_id: rating_id.to_string(),
+                    reason: "Insulting".to_string(),
+                }),
+            },
+        )
+    }
+
+    fn hide(user_id: &str, rating_id: &str) -> Request<hide_rating::Request> {
+        testing::request(
+            user_id,
+            hide_rating::Request {
+                payload: Some(hide_rating::Payload {
+                    rating_id: rating_id.to_string(),
+                    reason: "Abusive".to_string(),
+                }),
+            },
+        )
+    }
+
+    async fn provider_ratings(repository: &dyn Repository) -> Option<u64> {
+        testing::tally(repository, PROVIDER, ReputationRole::Provider)
+            .await
+            .map(|t| t.count)
+    }
+
+    #[tokio::test]
+    async fn hiding_a_reported_rating_resolves_the_case_and_takes_it_out_of_everything() {
+        let repository = testing::repository();
+        let service = ModerationService::new(repository.clone());
+        let rating = rated(&repository).await;
+
+        let case = service
+            .report_rating(report(&rating.id))
+            .await
+            .unwrap()
+            .into_inner()
+            .moderation_case
+            .unwrap();
+
+        assert_eq!(case.status(), CaseStatus::Pending);
+        assert_eq!(
+            service
+                .report_rating(report(&rating.id))
+                .await
+                .unwrap_err()
+                .code(),
+            tonic::Code::AlreadyExists
+        );
+
+        assert_eq!(provider_ratings(repository.as_ref()).await, Some(1));
+        assert_eq!(repository.get_stats().await.unwrap().ratings, 2);
+
+        let cases = service
+            .hide_rating(hide(MODERATOR, &rating.id))
+            .await
+            .unwrap()
+            .into_inner()
+            .cases;
+
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].status(), CaseStatus::Actioned);
+        assert_eq!(cases[0].resolved_by, MODERATOR);
+
+        assert_eq!(provider_ratings(repository.as_ref()).await, Some(0));
+        assert_eq!(repository.get_stats().await.unwrap().ratings, 1);
+        assert!(repository
+            .get_user_ratings(PROVIDER)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // nobody reports what nobody sees
+        assert_eq!(
+            service
+                .report_rating(report(&rating.id))
+                .await
+                .unwrap_err()
+                .code(),
+            tonic::Code::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn restoring_a_rating_counts_it_again() {
+        let repository = testing::repository();
+        let service = ModerationService::new(repository.clone());
+        let rating = rated(&repository).await;
+
+        service
+            .hide_rating(hide(MODERATOR, &rating.id))
+            .await
+            .unwrap();
+
+        service
+            .restore_rating(testing::request(
+                MODERATOR,
+                restore_rating::Request {
+                    payload: Some(restore_rating::Payload {
+                        rating_id: rating.id.clone(),
+                        reason: "Fair criticism".to_string(),
+                    }),
+                },
+            ))
+            .await
+            .unwrap();
+
+        assert_eq!(provider_ratings(repository.as_ref()).await, Some(1));
+        assert!(!service.rating(&rating.id).await.unwrap().hidden);
+    }
+
+    #[tokio::test]
+    async fn only_moderators_uninvolved_in_the_rating_hide_it() {
+        let repository = testing::repository();
+        let service = ModerationService::new(repository.clone());
+        let rating = rated(&repository).await;
+
+        // the rated provider turned moderator
+        let columns = Map::from_iter([("role".to_string(), json!(Role::Moderator as i32))]);
+        repository.update_profile(PROVIDER, columns).await.unwrap();
+
+        for user_id in [REPORTER, PROVIDER] {
+            let status = service
+                .hide_rating(hide(user_id, &rating.id))
+                .await
+                .unwrap_err();
+
+            assert_eq!(status.code(), tonic::Code::PermissionDenied, "{user_id}");
+        }
+
+        assert!(!service.rating(&rating.id).await.unwrap().hidden);
+        assert_eq!(provider_ratings(repository.as_ref()).await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn removing_a_reported_rating_keeps_its_case() {
+        let repository = testing::repository();
+        let service = ModerationService::new(repository.clone());
+        let admin = AdminService::new(repository.clone(), EventBus::new());
+        let rating = rated(&repository).await;
+
+        testing::user(repository.as_ref(), ADMIN, Role::Admin).await;
+
+        service.report_rating(report(&rating.id)).await.unwrap();
+
+        admin
+            .remove_rating(testing::request(
+                ADMIN,
+                remove_rating::Request {
+                    payload: Some(remove_rating::Payload {
+                        rating_id: rating.id.clone(),
+                        reason: "Abusive".to_string(),
+                    }),
+                },
+            ))
+            .await
+            .unwrap();
+
+        assert!(service.rating(&rating.id).await.unwrap().hidden);
+        assert_eq!(provider_ratings(repository.as_ref()).await, Some(0));
+        assert_eq!(
+            repository
+                .get_cases(&Query::eq("rating_id", &rating.id))
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+}
@@ -0,0 +1,153 @@
+// Validation and pagination of the `timebank.query.Query` taken by the `get` rpcs.
+//
+// Columns are checked against a per collection whitelist before reaching the repository so
+// clients can't probe arbitrary columns through the filters.
+
+use crate::error::{Error, Result};
+use crate::proto::timebank::query::{self as proto, predicate::Operator};
+use crate::repository::{Filter, Query};
+
+pub const DEFAULT_PAGE_SIZE: usize = 20;
+pub const MAX_PAGE_SIZE: usize = 100;
+
+/// Columns of a collection that can be queried. An entry ending with `.*` allows any field of
+/// that json column, eg `request_data.*`.
+pub struct Columns {
+    /// Unique column that keeps the order stable between pages.
+    pub key: &'static str,
+    pub filter: &'static [&'static str],
+    pub order: &'static [&'static str],
+}
+
+/// A validated query along with the size of the page it fetches.
+pub struct Page {
+    /// Fetches one row more than the page size, to know whether there is a next page.
+    pub query: Query,
+    size: usize,
+}
+
+impl Page {
+    /// Truncates the rows fetched with `query` to the page, returning them along with the token
+    /// of the next page, empty if this is the last one.
+    pub fn split<T>(&self, mut rows: Vec<T>) -> (Vec<T>, String) {
+        if rows.len() > self.size {
+            rows.truncate(self.size);
+            (rows, encode_token(self.query.offset + self.size))
+        } else {
+            (rows, String::new())
+        }
+    }
+}
+
+pub fn parse(query: Option<proto::Query>, columns: &Columns) -> Result<Page> {
+    let query = query.unwrap_or_default();
+
+    let mut filters = Vec::with_capacity(query.predicates.len());
+
+    for predicate in query.predicates {
+        if !is_allowed(&predicate.column, columns.filter) {
+            return Err(Error::InvalidColumn(predicate.column));
+        }
+
+        let operator = predicate.operator();
+        let mut values = predicate.values;
+
+        let filter = match (operator, values.len()) {
+            (Operator::In, len) if len > 0 => Filter::In(values),
+            (Operator::Range, 2) => {
+                let upper = values.pop().unwrap_or_default();
+                let lower = values.pop().unwrap_or_default();
+                Filter::Range(lower, upper)
+            }
+            (Operator::Eq, 1) => Filter::Eq(values.remove(0)),
+            (Operator::Neq, 1) => Filter::Neq(values.remove(0)),
+            (Operator::Gt, 1) => Filter::Gt(values.remove(0)),
+            (Operator::Lt, 1) => Filter::Lt(values.remove(0)),
+            (Operator::Ilike, 1) => Filter::Ilike(values.remove(0)),
+            _ => return Err(Error::InvalidPayload),
+        };
+
+        filters.push((predicate.column, filter));
+    }
+
+    let mut order = Vec::with_capacity(query.order_by.len() + 1);
+
+    for proto::Order { column, descending } in query.order_by {
+        if !is_allowed(&column, columns.order) {
+            return Err(Error::InvalidColumn(column));
+        }
+
+        order.push((column, descending));
+    }
+
+    // every collection has these, newest first unless asked otherwise and the key keeps the
+    // order stable between pages
+    if order.is_empty() {
+        order.push(("created_at".to_string(), true));
+    }
+
+    if !order.iter().any(|(column, _)| column == columns.key) {
+        order.push((columns.key.to_string(), false));
+    }
+
+    page(
+        Query {
+            filters,
+            order,
+            ..Default::default()
+        },
+        query.page_size,
+        &query.page_token,
+    )
+}
+
+/// Paginates a query built by the server, for rpcs that don't take a `Query`.
+pub fn page(query: Query, page_size: u32, page_token: &str) -> Result<Page> {
+    let size = match page_size as usize {
+        0 => DEFAULT_PAGE_SIZE,
+        size => size.min(MAX_PAGE_SIZE),
+    };
+
+    let offset = if page_token.is_empty() {
+        0
+    } else {
+        decode_token(page_token).ok_or(Error::InvalidPayload)?
+    };
+
+    Ok(Page {
+        query: Query {
+            offset,
+            limit: Some(size + 1),
+            ..query
+        },
+        size,
+    })
+}
+
+fn is_allowed(column: &str, whitelist: &[&str]) -> bool {
+    whitelist
+        .iter()
+        .any(|allowed| match allowed.strip_suffix(".*") {
+            Some(json_column) => matches!(
+                column.strip_prefix(json_column).and_then(|field| field.strip_prefix('.')),
+                Some(field) if is_identifier(field)
+            ),
+
+            None => column == *allowed,
+        })
+}
+
+// json fields end up in the PostgREST column path, keep them to plain identifiers
+fn is_identifier(field: &str) -> bool {
+    !field.is_empty() && field.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// tokens are opaque to clients, they only need to hand them back
+fn encode_token(offset: usize) -> String {
+    base64::encode_config(offset.to_string(), base64::URL_SAFE_NO_PAD)
+}
+
+fn decode_token(token: &str) -> Option<usize> {
+    let decoded = base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()?;
+    String::from_utf8(decoded).ok()?.parse().ok()
+}
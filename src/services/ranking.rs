@@ -0,0 +1,524 @@
+// Service scoring the bids on a request and recommending providers for it
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use tonic::{Request, Response};
+
+use crate::config::{RankingConfig, RankingWeights};
+use crate::error::Error;
+use crate::middleware::auth::AuthenticatedUser;
+use crate::policy::{self, Action};
+use crate::proto::account::TUserProfile;
+use crate::proto::timebank::location::TLocation;
+use crate::proto::timebank::ranking::ranking_server::Ranking;
+use crate::proto::timebank::ranking::{
+    rank_bids, recommend_providers, TRankedBid, TRecommendation, TSignals,
+};
+use crate::proto::timebank::reputation::ReputationRole;
+use crate::proto::timebank::servicerequest::TServiceRequest;
+use crate::proto::timebank::servicerequestbid::BidStatus;
+use crate::proto::timebank::taxonomy::Proficiency;
+use crate::proto::timebank::verification::VerificationStatus;
+use crate::repository::{Filter, ProviderStats, Query, Repository, ReputationTally};
+use crate::services::{taxonomy, util::helper, Result};
+
+pub use crate::proto::timebank::ranking::ranking_server::RankingServer;
+
+// providers considered for recommendations, the most experienced first
+const CANDIDATES: usize = 100;
+
+// words shorter than this are mostly stop words
+const MIN_KEYWORD_LENGTH: usize = 4;
+
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+fn users(user_ids: &[String]) -> Query {
+    Query {
+        filters: vec![("user_id".to_string(), Filter::In(user_ids.to_vec()))],
+        ..Default::default()
+    }
+}
+
+fn keywords(request: &TServiceRequest) -> HashSet<String> {
+    let data = request.request_data.clone().unwrap_or_default();
+
+    format!("{} {}", data.title, data.description)
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= MIN_KEYWORD_LENGTH)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn location(request: &TServiceRequest) -> Option<&TLocation> {
+    request.request_data.as_ref()?.location.as_ref()
+}
+
+// best proficiency the user declared in one of the categories, 0 if none
+fn skill(profile: Option<&TUserProfile>, categories: &HashSet<String>) -> f64 {
+    profile
+        .into_iter()
+        .flat_map(|p| &p.skills)
+        .filter(|s| categories.contains(&s.category_id))
+        .map(|s| s.proficiency as f64 / Proficiency::Expert as i32 as f64)
+        .fold(0.0, f64::max)
+}
+
+// great-circle distance
+fn distance_km(a: &TLocation, b: &TLocation) -> f64 {
+    let (lat_a, lat_b) = (a.latitude.to_radians(), b.latitude.to_radians());
+    let d_lat = lat_b - lat_a;
+    let d_lon = (b.longitude - a.longitude).to_radians();
+
+    let h = (d_lat / 2.0).sin().powi(2) + lat_a.cos() * lat_b.cos() * (d_lon / 2.0).sin().powi(2);
+
+    2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+}
+
+// 1 at 0, halved every `halving`
+fn decay(value: f64, halving: f64) -> f64 {
+    0.5_f64.powf(value.max(0.0) / halving)
+}
+
+fn score(weights: &RankingWeights, signals: &TSignals) -> f64 {
+    let parts = [
+        (weights.amount, signals.amount),
+        (weights.rating, Some(signals.rating)),
+        (weights.completion_rate, Some(signals.completion_rate)),
+        (weights.response_time, Some(signals.response_time)),
+        (weights.distance, signals.distance),
+        (weights.relevance, signals.relevance),
+        (weights.skill, signals.skill),
+    ];
+
+    let (total, weight) = parts
+        .into_iter()
+        .filter_map(|(weight, signal)| Some((weight * signal?, weight)))
+        .fold((0.0, 0.0), |(total, sum), (part, weight)| {
+            (total + part, sum + weight)
+        });
+
+    if weight > 0.0 {
+        total / weight
+    } else {
+        0.0
+    }
+}
+
+pub struct RankingService {
+    repository: Arc<dyn Repository>,
+    config: RankingConfig,
+}
+
+impl RankingService {
+    pub fn new(repository: Arc<dyn Repository>, config: &RankingConfig) -> Self {
+        Self {
+            repository,
+            config: config.clone(),
+        }
+    }
+
+    // the request, once the caller is known to be allowed to rank for it
+    async fn request(&self, user: AuthenticatedUser, request_id: &str) -> Result<TServiceRequest> {
+        let service_request = self
+            .repository
+            .get_request(request_id)
+            .await?
+            .ok_or(Error::NotFound)?;
+
+        let actor = helper::actor(self.repository.as_ref(), user).await?;
+        policy::authorize(
+            &actor,
+            Action::Rank {
+                requestor: &service_request.requestor,
+            },
+        )?;
+
+        Ok(service_request)
+    }
+
+    async fn profiles(&self, user_ids: &[String]) -> Result<HashMap<String, TUserProfile>> {
+        Ok(self
+            .repository
+            .get_profiles(&users(user_ids))
+            .await?
+            .into_iter()
+            .map(|p| (p.user_id.clone(), p))
+            .collect())
+    }
+
+    async fn stats(&self, user_ids: &[String]) -> Result<HashMap<String, ProviderStats>> {
+        Ok(self
+            .repository
+            .get_provider_stats(&users(user_ids))
+            .await?
+            .into_iter()
+            .map(|s| (s.user_id.clone(), s))
+            .collect())
+    }
+
+    // reputations as a provider
+    async fn reputations(&self, user_ids: &[String]) -> Result<HashMap<String, ReputationTally>> {
+        let mut query = users(user_ids);
+        query.filters.push((
+            "role".to_string(),
+            Filter::Eq((ReputationRole::Provider as i32).to_string()),
+        ));
+
+        Ok(self
+            .repository
+            .get_reputations(&query)
+            .await?
+            .into_iter()
+            .map(|t| (t.user_id.clone(), t))
+            .collect())
+    }
+
+    // what the user's track record says about them, users without one are given the benefit
+    // of the doubt
+    fn signals(
+        &self,
+        stats: Option<&ProviderStats>,
+        reputation: Option<&ReputationTally>,
+        distance_km: Option<f64>,
+    ) -> TSignals {
+        let stats = stats.cloned().unwrap_or_default();
+
+        TSignals {
+            rating: reputation.map_or(0.5, ReputationTally::normalized_score),
+            completion_rate: (stats.completed as f64 + 1.0) / (stats.concluded as f64 + 2.0),
+            response_time: stats.mean_response_seconds.map_or(0.5, |seconds| {
+                decay(seconds / 3600.0, self.config.response_halving_hours)
+            }),
+            distance: distance_km.map(|km| decay(km, self.config.distance_halving_km)),
+            ..Default::default()
+        }
+    }
+
+    fn distance_to(
+        &self,
+        request: &TServiceRequest,
+        profile: Option<&TUserProfile>,
+    ) -> Option<f64> {
+        let user = profile?.location.as_ref()?;
+        Some(distance_km(location(request)?, user))
+    }
+
+    // keywords of the other requests each user bid on
+    async fn past_keywords(
+        &self,
+        user_ids: &[String],
+        request_id: &str,
+    ) -> Result<HashMap<String, HashSet<String>>> {
+        let bids = self.repository.get_bids(&users(user_ids)).await?;
+
+        let request_ids: Vec<String> = bids
+            .iter()
+            .map(|b| b.request_id.clone())
+            .filter(|id| id != request_id)
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+
+        if request_ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let requests: HashMap<String, HashSet<String>> = self
+            .repository
+            .get_requests(&Query {
+                filters: vec![("id".to_string(), Filter::In(request_ids))],
+                ..Default::default()
+            })
+            .await?
+            .into_iter()
+            .map(|r| (r.id.clone(), keywords(&r)))
+            .collect();
+
+        let mut keywords: HashMap<String, HashSet<String>> = HashMap::new();
+
+        for bid in bids {
+            if let Some(words) = requests.get(&bid.request_id) {
+                keywords
+                    .entry(bid.user_id)
+                    .or_default()
+                    .extend(words.iter().cloned());
+            }
+        }
+
+        Ok(keywords)
+    }
+
+    // categories whose skills fit the request, those it is tagged with along with the ones above
+    // and under them
+    async fn related_categories(&self, request: &TServiceRequest) -> Result<HashSet<String>> {
+        if request.category_ids.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let categories = taxonomy::categories(self.repository.as_ref()).await?;
+
+        let mut related = taxonomy::descendants(&categories, &request.category_ids);
+
+        for id in &request.category_ids {
+            related.extend(taxonomy::ancestors(&categories, id));
+        }
+
+        Ok(related)
+    }
+
+    // users offering skills in the categories
+    async fn skilled(&self, categories: &HashSet<String>) -> Result<HashSet<String>> {
+        if categories.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        Ok(self
+            .repository
+            .get_skills(&Query {
+                filters: vec![(
+                    "category_id".to_string(),
+                    Filter::In(categories.iter().cloned().collect()),
+                )],
+                order: vec![("proficiency".to_string(), true)],
+                limit: Some(CANDIDATES),
+                ..Default::default()
+            })
+            .await?
+            .into_iter()
+            .map(|s| s.user_id)
+            .collect())
+    }
+
+    // verified users who aren't sanctioned, ie those who could bid
+    async fn eligible(&self, user_ids: &[String]) -> Result<HashSet<String>> {
+        let verified = self
+            .repository
+            .get_verifications(&Query {
+                filters: vec![
+                    ("user_id".to_string(), Filter::In(user_ids.to_vec())),
+                    (
+                        "status".to_string(),
+                        Filter::Eq((VerificationStatus::Approved as i32).to_string()),
+                    ),
+                ],
+                ..Default::default()
+            })
+            .await?;
+
+        let sanctioned: HashSet<String> = self
+            .repository
+            .get_sanctions(&users(user_ids))
+            .await?
+            .into_iter()
+            .filter(|s| s.in_effect())
+            .map(|s| s.user_id)
+            .collect();
+
+        Ok(verified
+            .into_iter()
+            .map(|v| v.user_id)
+            .filter(|id| !sanctioned.contains(id))
+            .collect())
+    }
+}
+
+#[tonic::async_trait]
+impl Ranking for RankingService {
+    async fn rank_bids(
+        &self,
+        request: Request<rank_bids::Request>,
+    ) -> Result<Response<rank_bids::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let service_request = self.request(user, &payload.request_id).await?;
+
+                let bids: Vec<_> = self
+                    .repository
+                    .get_bids(&Query::eq("request_id", &service_request.id))
+                    .await?
+                    .into_iter()
+                    .filter(|b| b.status() != BidStatus::Withdrawn)
+                    .collect();
+
+                if bids.is_empty() {
+                    return Ok(Response::new(rank_bids::Response { bids: Vec::new() }));
+                }
+
+                let bidders: Vec<String> = bids.iter().map(|b| b.user_id.clone()).collect();
+                let profiles = self.profiles(&bidders).await?;
+                let stats = self.stats(&bidders).await?;
+                let reputations = self.reputations(&bidders).await?;
+
+                let lowest = bids
+                    .iter()
+                    .map(|b| b.amount)
+                    .filter(|amount| *amount > 0.0)
+                    .fold(f64::INFINITY, f64::min);
+
+                let mut ranked = Vec::with_capacity(bids.len());
+
+                for bid in bids {
+                    let distance_km =
+                        self.distance_to(&service_request, profiles.get(&bid.user_id));
+
+                    let mut signals = self.signals(
+                        stats.get(&bid.user_id),
+                        reputations.get(&bid.user_id),
+                        distance_km,
+                    );
+
+                    signals.amount = Some(if bid.amount > 0.0 {
+                        lowest / bid.amount
+                    } else {
+                        0.0
+                    });
+
+                    ranked.push(TRankedBid {
+                        score: score(&self.config.weights, &signals),
+                        signals: Some(signals),
+                        bid: Some(bid),
+                    });
+                }
+
+                // the earliest bid first among equals
+                ranked.sort_by(|a, b| {
+                    b.score.total_cmp(&a.score).then_with(|| {
+                        let created_at = |r: &TRankedBid| {
+                            r.bid
+                                .as_ref()
+                                .map(|b| b.created_at.clone())
+                                .unwrap_or_default()
+                        };
+                        created_at(a).cmp(&created_at(b))
+                    })
+                });
+
+                Ok(Response::new(rank_bids::Response { bids: ranked }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn recommend_providers(
+        &self,
+        request: Request<recommend_providers::Request>,
+    ) -> Result<Response<recommend_providers::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let service_request = self.request(user, &payload.request_id).await?;
+
+                let limit = match payload.limit {
+                    0 => self.config.recommendations,
+                    limit => limit.min(self.config.recommendations),
+                } as usize;
+
+                // whoever bid already, even if they withdrew, has seen the request
+                let mut excluded: HashSet<String> = self
+                    .repository
+                    .get_bids(&Query::eq("request_id", &service_request.id))
+                    .await?
+                    .into_iter()
+                    .map(|b| b.user_id)
+                    .collect();
+
+                excluded.insert(service_request.requestor.clone());
+
+                let stats: HashMap<String, ProviderStats> = self
+                    .repository
+                    .get_provider_stats(&Query {
+                        order: vec![("completed".to_string(), true), ("bids".to_string(), true)],
+                        limit: Some(CANDIDATES),
+                        ..Default::default()
+                    })
+                    .await?
+                    .into_iter()
+                    .filter(|s| !excluded.contains(&s.user_id))
+                    .map(|s| (s.user_id.clone(), s))
+                    .collect();
+
+                // members offering skills the request needs are considered even without a track
+                // record
+                let categories = self.related_categories(&service_request).await?;
+                let skilled = self.skilled(&categories).await?;
+
+                let candidates: Vec<String> = stats
+                    .keys()
+                    .chain(skilled.iter().filter(|id| !excluded.contains(*id)))
+                    .cloned()
+                    .collect::<HashSet<_>>()
+                    .into_iter()
+                    .collect();
+
+                if candidates.is_empty() {
+                    return Ok(Response::new(recommend_providers::Response {
+                        providers: Vec::new(),
+                    }));
+                }
+
+                let eligible = self.eligible(&candidates).await?;
+                let profiles = self.profiles(&candidates).await?;
+                let reputations = self.reputations(&candidates).await?;
+                let past_keywords = self.past_keywords(&candidates, &service_request.id).await?;
+
+                let wanted = keywords(&service_request);
+
+                let mut providers = Vec::new();
+
+                for user_id in candidates.iter().filter(|id| eligible.contains(*id)) {
+                    let profile = profiles.get(user_id);
+                    let distance_km = self.distance_to(&service_request, profile);
+
+                    let relevance = (!wanted.is_empty()).then(|| {
+                        let matched = past_keywords
+                            .get(user_id)
+                            .map_or(0, |words| wanted.intersection(words).count());
+
+                        matched as f64 / wanted.len() as f64
+                    });
+
+                    let skill = (!categories.is_empty()).then(|| skill(profile, &categories));
+
+                    // a provider matches if they did similar requests before, offer the skills
+                    // it needs or live nearby
+                    let relevant = relevance.is_some_and(|r| r > 0.0);
+                    let skilled = skill.is_some_and(|s| s > 0.0);
+                    let nearby = distance_km.is_some_and(|km| km <= self.config.max_distance_km);
+
+                    if !relevant && !skilled && !nearby {
+                        continue;
+                    }
+
+                    let mut signals =
+                        self.signals(stats.get(user_id), reputations.get(user_id), distance_km);
+
+                    signals.relevance = relevance;
+                    signals.skill = skill;
+
+                    providers.push(TRecommendation {
+                        user: Some(profile.cloned().unwrap_or_else(|| TUserProfile {
+                            user_id: user_id.clone(),
+                            ..Default::default()
+                        })),
+                        score: score(&self.config.weights, &signals),
+                        signals: Some(signals),
+                    });
+                }
+
+                providers.sort_by(|a, b| b.score.total_cmp(&a.score));
+                providers.truncate(limit);
+
+                Ok(Response::new(recommend_providers::Response { providers }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+}
@@ -0,0 +1,245 @@
+// Service reading the reputation of users, aggregated from the ratings they received
+
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use tonic::{Request, Response};
+
+use crate::error::{self, Error};
+use crate::proto::timebank::reputation::reputation_server::Reputation;
+use crate::proto::timebank::reputation::{
+    get_reputation, list_reputations, ReputationRole, TReputation, TReputationScore,
+};
+use crate::proto::timebank::servicerating::TServiceRating;
+use crate::repository::{Query, Repository, ReputationTally};
+use crate::services::{collection::service_rating::RATING_SCALE, query, Result};
+
+pub use crate::proto::timebank::reputation::reputation_server::ReputationServer;
+
+/// Mean every score starts from, the middle of the scale.
+pub const PRIOR_MEAN: f64 = 3.0;
+
+/// How many ratings the prior counts as, the more ratings a user has the less it matters.
+pub const PRIOR_WEIGHT: f64 = 5.0;
+
+// period `trend` compares with the rest of the history
+const TREND_DAYS: i64 = 30;
+
+const QUERY_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &["user_id", "role", "count", "score"],
+    order: &["count", "score"],
+};
+
+/// Bayesian average of `count` ratings adding up to `sum`.
+pub fn bayesian_score(count: u64, sum: i64) -> f64 {
+    (PRIOR_WEIGHT * PRIOR_MEAN + sum as f64) / (PRIOR_WEIGHT + count as f64)
+}
+
+/// Where `value` is counted in the histogram, values outside the scale in the closest bucket.
+fn bucket(value: i32) -> usize {
+    (value.clamp(*RATING_SCALE.start(), *RATING_SCALE.end()) - RATING_SCALE.start()) as usize
+}
+
+impl ReputationTally {
+    pub fn adjust(&mut self, added: Option<i32>, removed: Option<i32>) {
+        self.histogram.resize(RATING_SCALE.count(), 0);
+
+        if let Some(value) = added {
+            self.count += 1;
+            self.sum += value as i64;
+            self.histogram[bucket(value)] += 1;
+        }
+
+        if let Some(value) = removed {
+            self.count = self.count.saturating_sub(1);
+            self.sum -= value as i64;
+
+            let bucket = &mut self.histogram[bucket(value)];
+            *bucket = bucket.saturating_sub(1);
+        }
+
+        self.score = bayesian_score(self.count, self.sum);
+    }
+
+    pub fn mean(&self) -> f64 {
+        match self.count {
+            0 => 0.0,
+            count => self.sum as f64 / count as f64,
+        }
+    }
+
+    /// The score between 0 and 1, for ranking.
+    pub fn normalized_score(&self) -> f64 {
+        let (min, max) = (*RATING_SCALE.start() as f64, *RATING_SCALE.end() as f64);
+        ((self.score - min) / (max - min)).clamp(0.0, 1.0)
+    }
+
+    fn to_score(&self) -> TReputationScore {
+        TReputationScore {
+            user_id: self.user_id.clone(),
+            role: self.role,
+            count: self.count,
+            mean: self.mean(),
+            score: self.score,
+            histogram: self.histogram.clone(),
+            updated_at: self.updated_at.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Counts the change of a rating in the reputation of the user it rates. `added` and `removed`
+/// are the values it has after and had before the change.
+pub async fn record(
+    repository: &dyn Repository,
+    rating: &TServiceRating,
+    added: Option<i32>,
+    removed: Option<i32>,
+) -> error::Result<()> {
+    // ratings only count once revealed, and not while moderators hide them
+    if !rating.revealed || rating.hidden || added == removed {
+        return Ok(());
+    }
+
+    repository
+        .adjust_reputation(&rating.rated_user_id, rating.role(), added, removed)
+        .await?;
+
+    Ok(())
+}
+
+// mean of the ratings since `since` minus the mean of those before
+fn trend<'a>(ratings: impl Iterator<Item = &'a TServiceRating>, since: DateTime<Utc>) -> f64 {
+    let (mut recent, mut before) = ((0.0, 0), (0.0, 0));
+
+    for rating in ratings {
+        let created_at = match DateTime::parse_from_rfc3339(&rating.created_at) {
+            Ok(created_at) => created_at.with_timezone(&Utc),
+            Err(_) => continue,
+        };
+
+        let period = if created_at >= since {
+            &mut recent
+        } else {
+            &mut before
+        };
+
+        period.0 += rating.value as f64;
+        period.1 += 1;
+    }
+
+    match (recent, before) {
+        ((_, 0), _) | (_, (_, 0)) => 0.0,
+        ((recent, r), (before, b)) => recent / r as f64 - before / b as f64,
+    }
+}
+
+pub struct ReputationService {
+    repository: Arc<dyn Repository>,
+}
+
+impl ReputationService {
+    pub fn new(repository: Arc<dyn Repository>) -> Self {
+        Self { repository }
+    }
+}
+
+#[tonic::async_trait]
+impl Reputation for ReputationService {
+    async fn get_reputation(
+        &self,
+        request: Request<get_reputation::Request>,
+    ) -> Result<Response<get_reputation::Response>> {
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) if !payload.user_id.is_empty() => {
+                let tallies = self
+                    .repository
+                    .get_reputations(&Query::eq("user_id", &payload.user_id))
+                    .await?;
+
+                let tally = |role: ReputationRole| {
+                    tallies
+                        .iter()
+                        .find(|t| t.role == role as i32)
+                        .cloned()
+                        .unwrap_or_else(|| {
+                            let mut tally = ReputationTally {
+                                user_id: payload.user_id.clone(),
+                                role: role as i32,
+                                ..Default::default()
+                            };
+                            tally.adjust(None, None);
+                            tally
+                        })
+                };
+
+                let (provider, requestor) = (
+                    tally(ReputationRole::Provider),
+                    tally(ReputationRole::Requestor),
+                );
+
+                let mut overall = ReputationTally {
+                    user_id: payload.user_id.clone(),
+                    count: provider.count + requestor.count,
+                    sum: provider.sum + requestor.sum,
+                    histogram: provider
+                        .histogram
+                        .iter()
+                        .zip(&requestor.histogram)
+                        .map(|(a, b)| a + b)
+                        .collect(),
+                    updated_at: provider
+                        .updated_at
+                        .clone()
+                        .max(requestor.updated_at.clone()),
+                    ..Default::default()
+                };
+                overall.adjust(None, None);
+
+                let since = Utc::now() - Duration::days(TREND_DAYS);
+                let received = self.repository.get_user_ratings(&payload.user_id).await?;
+
+                let scored = |tally: &ReputationTally, role: Option<ReputationRole>| {
+                    let ratings = received
+                        .iter()
+                        .filter(|r| role.is_none_or(|role| r.role() == role));
+
+                    TReputationScore {
+                        trend: trend(ratings, since),
+                        ..tally.to_score()
+                    }
+                };
+
+                Ok(Response::new(get_reputation::Response {
+                    reputation: Some(TReputation {
+                        user_id: payload.user_id,
+                        overall: Some(scored(&overall, None)),
+                        provider: Some(scored(&provider, Some(ReputationRole::Provider))),
+                        requestor: Some(scored(&requestor, Some(ReputationRole::Requestor))),
+                    }),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn list_reputations(
+        &self,
+        request: Request<list_reputations::Request>,
+    ) -> Result<Response<list_reputations::Response>> {
+        let payload = request.into_inner().payload.unwrap_or_default();
+
+        let page = query::parse(payload.query, &QUERY_COLUMNS)?;
+        let (tallies, next_page_token) =
+            page.split(self.repository.get_reputations(&page.query).await?);
+
+        Ok(Response::new(list_reputations::Response {
+            reputations: tallies.iter().map(ReputationTally::to_score).collect(),
+            next_page_token,
+        }))
+    }
+}
@@ -0,0 +1,397 @@
+// Service for the categories services are offered in and the skills members offer
+
+use std::collections::HashSet;
+use std::slice;
+use std::sync::Arc;
+
+use tonic::{Request, Response};
+
+use crate::error::{self, Error};
+use crate::middleware::audit;
+use crate::policy::{self, Action};
+use crate::proto::timebank::taxonomy::taxonomy_server::Taxonomy;
+use crate::proto::timebank::taxonomy::{
+    create_category, delete_category, list_categories, list_skills, set_skills, update_category,
+    Proficiency, TCategory,
+};
+use crate::repository::{Filter, Query, Repository};
+use crate::services::{field_mask, query, util::helper, Result};
+
+pub use crate::proto::timebank::taxonomy::taxonomy_server::TaxonomyServer;
+
+// fields admins are allowed to change through `update_category`
+const MUTABLE_FIELDS: &[&str] = &["name", "description", "parent_id"];
+
+const CATEGORY_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &["id", "parent_id", "name", "created_at"],
+    order: &["name", "created_at"],
+};
+
+const SKILL_COLUMNS: query::Columns = query::Columns {
+    key: "id",
+    filter: &["user_id", "category_id", "proficiency", "created_at"],
+    order: &["proficiency", "created_at"],
+};
+
+/// The categories and every one under them.
+pub fn descendants(categories: &[TCategory], ids: &[String]) -> HashSet<String> {
+    let mut found: HashSet<String> = ids.iter().cloned().collect();
+    let mut pending = ids.to_vec();
+
+    while let Some(id) = pending.pop() {
+        for category in categories.iter().filter(|c| c.parent_id == id) {
+            if found.insert(category.id.clone()) {
+                pending.push(category.id.clone());
+            }
+        }
+    }
+
+    found
+}
+
+/// The category and every one above it.
+pub fn ancestors(categories: &[TCategory], id: &str) -> HashSet<String> {
+    let mut found = HashSet::new();
+    let mut next = Some(id.to_string());
+
+    while let Some(id) = next.filter(|id| !id.is_empty() && found.insert(id.clone())) {
+        next = categories
+            .iter()
+            .find(|c| c.id == id)
+            .map(|c| c.parent_id.clone());
+    }
+
+    found
+}
+
+/// The whole taxonomy, which is small enough to be walked in memory.
+pub async fn categories(repository: &dyn Repository) -> error::Result<Vec<TCategory>> {
+    repository.get_categories(&Query::default()).await
+}
+
+/// Fails with `Error::UnknownCategory` unless every id is that of a category.
+pub async fn ensure_categories(repository: &dyn Repository, ids: &[String]) -> error::Result<()> {
+    if ids.is_empty() {
+        return Ok(());
+    }
+
+    let known: HashSet<String> = repository
+        .get_categories(&Query {
+            filters: vec![("id".to_string(), Filter::In(ids.to_vec()))],
+            ..Default::default()
+        })
+        .await?
+        .into_iter()
+        .map(|c| c.id)
+        .collect();
+
+    match ids.iter().find(|id| !known.contains(*id)) {
+        Some(id) => Err(Error::UnknownCategory(id.clone())),
+        None => Ok(()),
+    }
+}
+
+/// Widens the `eq` and `in` filters on `column` to the categories under the ones named, built
+/// into the filter the column needs with `filter`.
+pub async fn expand(
+    repository: &dyn Repository,
+    query: &mut Query,
+    column: &str,
+    filter: fn(Vec<String>) -> Filter,
+) -> error::Result<()> {
+    if !query.filters.iter().any(|(name, _)| name == column) {
+        return Ok(());
+    }
+
+    let categories = categories(repository).await?;
+
+    for (name, condition) in query.filters.iter_mut().filter(|(name, _)| name == column) {
+        let ids = match condition {
+            Filter::Eq(id) => vec![id.clone()],
+            Filter::In(ids) => ids.clone(),
+            _ => return Err(Error::InvalidColumn(name.clone())),
+        };
+
+        *condition = filter(descendants(&categories, &ids).into_iter().collect());
+    }
+
+    Ok(())
+}
+
+pub struct TaxonomyService {
+    repository: Arc<dyn Repository>,
+}
+
+impl TaxonomyService {
+    pub fn new(repository: Arc<dyn Repository>) -> Self {
+        Self { repository }
+    }
+
+    async fn category(&self, category_id: &str) -> error::Result<TCategory> {
+        self.repository
+            .get_categories(&Query::eq("id", category_id))
+            .await?
+            .into_iter()
+            .next()
+            .ok_or(Error::NotFound)
+    }
+
+    // whether anything still refers to the category
+    async fn in_use(&self, category_id: &str) -> error::Result<bool> {
+        let first = |column: &str, filter: Filter| Query {
+            filters: vec![(column.to_string(), filter)],
+            limit: Some(1),
+            ..Default::default()
+        };
+
+        let id = category_id.to_string();
+
+        Ok(!self
+            .repository
+            .get_categories(&first("parent_id", Filter::Eq(id.clone())))
+            .await?
+            .is_empty()
+            || !self
+                .repository
+                .get_requests(&first("category_ids", Filter::Overlaps(vec![id.clone()])))
+                .await?
+                .is_empty()
+            || !self
+                .repository
+                .get_skills(&first("category_id", Filter::Eq(id)))
+                .await?
+                .is_empty())
+    }
+}
+
+#[tonic::async_trait]
+impl Taxonomy for TaxonomyService {
+    async fn create_category(
+        &self,
+        request: Request<create_category::Request>,
+    ) -> Result<Response<create_category::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) if !payload.name.trim().is_empty() => {
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(&actor, Action::EditTaxonomy)?;
+
+                if !payload.parent_id.is_empty() {
+                    ensure_categories(
+                        self.repository.as_ref(),
+                        slice::from_ref(&payload.parent_id),
+                    )
+                    .await?;
+                }
+
+                let category = self
+                    .repository
+                    .create_category(TCategory {
+                        parent_id: payload.parent_id,
+                        name: payload.name.trim().to_string(),
+                        description: payload.description,
+                        ..Default::default()
+                    })
+                    .await?;
+
+                audit::target("category", &category.id);
+                audit::after(&category);
+
+                Ok(Response::new(create_category::Response {
+                    category: Some(category),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn update_category(
+        &self,
+        request: Request<update_category::Request>,
+    ) -> Result<Response<update_category::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let update_category::Payload {
+                    category_id,
+                    category,
+                    update_mask,
+                } = payload;
+
+                let patch =
+                    field_mask::patch(category.as_ref(), update_mask.as_ref(), MUTABLE_FIELDS)?;
+
+                let current = self.category(&category_id).await?;
+
+                audit::target("category", &category_id);
+                audit::before(&current);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(&actor, Action::EditTaxonomy)?;
+
+                if patch
+                    .get("name")
+                    .is_some_and(|name| name.as_str().is_none_or(|name| name.trim().is_empty()))
+                {
+                    return Err(Error::InvalidPayload.into());
+                }
+
+                if let Some(parent_id) = patch.get("parent_id").and_then(|id| id.as_str()) {
+                    if !parent_id.is_empty() {
+                        let categories = categories(self.repository.as_ref()).await?;
+
+                        if descendants(&categories, slice::from_ref(&current.id))
+                            .contains(parent_id)
+                        {
+                            return Err(Error::CategoryCycle.into());
+                        }
+
+                        ensure_categories(self.repository.as_ref(), &[parent_id.to_string()])
+                            .await?;
+                    }
+                }
+
+                let category = self
+                    .repository
+                    .update_category(&category_id, field_mask::apply(&current, patch)?)
+                    .await?;
+
+                if let Some(category) = &category {
+                    audit::after(category);
+                }
+
+                Ok(Response::new(update_category::Response { category }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn delete_category(
+        &self,
+        request: Request<delete_category::Request>,
+    ) -> Result<Response<delete_category::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let category = self.category(&payload.category_id).await?;
+
+                audit::target("category", &category.id);
+                audit::before(&category);
+
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(&actor, Action::EditTaxonomy)?;
+
+                if self.in_use(&category.id).await? {
+                    return Err(Error::CategoryInUse.into());
+                }
+
+                self.repository.delete_category(&category.id).await?;
+
+                Ok(Response::new(delete_category::Response {}))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn list_categories(
+        &self,
+        request: Request<list_categories::Request>,
+    ) -> Result<Response<list_categories::Response>> {
+        let payload = request.into_inner().payload.unwrap_or_default();
+
+        let unordered = payload.query.as_ref().is_none_or(|q| q.order_by.is_empty());
+        let mut page = query::parse(payload.query, &CATEGORY_COLUMNS)?;
+
+        // alphabetical rather than the newest first of `parse`
+        if unordered {
+            page.query.order.insert(0, ("name".to_string(), false));
+        }
+
+        let (categories, next_page_token) =
+            page.split(self.repository.get_categories(&page.query).await?);
+
+        Ok(Response::new(list_categories::Response {
+            categories,
+            next_page_token,
+        }))
+    }
+
+    async fn set_skills(
+        &self,
+        request: Request<set_skills::Request>,
+    ) -> Result<Response<set_skills::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+
+                let mut seen = HashSet::new();
+
+                for skill in &payload.skills {
+                    if skill.proficiency() == Proficiency::Unspecified
+                        || !seen.insert(skill.category_id.clone())
+                    {
+                        return Err(Error::InvalidPayload.into());
+                    }
+                }
+
+                let category_ids: Vec<String> = seen.into_iter().collect();
+                ensure_categories(self.repository.as_ref(), &category_ids).await?;
+
+                audit::target("user", &actor.id);
+                audit::before(
+                    &self
+                        .repository
+                        .get_skills(&Query::eq("user_id", &actor.id))
+                        .await?,
+                );
+
+                let skills = self
+                    .repository
+                    .set_skills(&actor.id, payload.skills)
+                    .await?;
+
+                audit::after(&skills);
+
+                Ok(Response::new(set_skills::Response { skills }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn list_skills(
+        &self,
+        request: Request<list_skills::Request>,
+    ) -> Result<Response<list_skills::Response>> {
+        let payload = request.into_inner().payload.unwrap_or_default();
+
+        let mut page = query::parse(payload.query, &SKILL_COLUMNS)?;
+        expand(
+            self.repository.as_ref(),
+            &mut page.query,
+            "category_id",
+            Filter::In,
+        )
+        .await?;
+
+        let (skills, next_page_token) = page.split(self.repository.get_skills(&page.query).await?);
+
+        Ok(Response::new(list_skills::Response {
+            skills,
+            next_page_token,
+        }))
+    }
+}
@@ -0,0 +1,158 @@
+// Fixtures for testing the services against `MemoryRepository`
+
+use std::sync::Arc;
+
+use serde_json::{json, Map};
+use tonic::Request;
+
+use crate::events::EventBus;
+use crate::middleware::auth::AuthenticatedUser;
+use crate::proto::timebank::reputation::ReputationRole;
+use crate::proto::timebank::role::Role;
+use crate::proto::timebank::servicerequest::service_request_server::ServiceRequest;
+use crate::proto::timebank::servicerequest::{
+    self, complete_service, select_bid, t_service_request::RequestData, TServiceRequest,
+};
+use crate::proto::timebank::servicerequestbid::service_request_bid_server::ServiceRequestBid;
+use crate::proto::timebank::servicerequestbid::{self, TServiceRequestBid};
+use crate::proto::timebank::verification::{TVerification, VerificationStatus};
+use crate::repository::{memory::MemoryRepository, Query, Repository, ReputationTally};
+use crate::services::collection::{
+    service_request::ServiceRequestService, service_request_bid::ServiceRequestBidService,
+};
+
+pub const REQUESTOR: &str = "requestor";
+pub const PROVIDER: &str = "provider";
+
+pub fn repository() -> Arc<dyn Repository> {
+    Arc::new(MemoryRepository::default())
+}
+
+/// The request as `AuthInterceptor` hands it over once it authenticated `user_id`.
+pub fn request<T>(user_id: &str, message: T) -> Request<T> {
+    let mut request = Request::new(message);
+
+    request.extensions_mut().insert(AuthenticatedUser {
+        id: user_id.to_string(),
+        session_id: None,
+    });
+
+    request
+}
+
+/// A verified user with the role, and the opening balance every profile starts with.
+pub async fn user(repository: &dyn Repository, user_id: &str, role: Role) {
+    let columns = Map::from_iter([
+        ("username".to_string(), json!(user_id)),
+        ("role".to_string(), json!(role as i32)),
+    ]);
+
+    repository.update_profile(user_id, columns).await.unwrap();
+
+    repository
+        .save_verification(TVerification {
+            user_id: user_id.to_string(),
+            status: VerificationStatus::Approved as i32,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+}
+
+/// An open request of `REQUESTOR` with a bid of `amount` from `PROVIDER`.
+pub async fn bid_on_request(
+    repository: &Arc<dyn Repository>,
+    amount: f64,
+) -> (TServiceRequest, TServiceRequestBid) {
+    let requests = ServiceRequestService::new(repository.clone(), EventBus::new());
+    let bids = ServiceRequestBidService::new(repository.clone(), EventBus::new());
+
+    user(repository.as_ref(), REQUESTOR, Role::Member).await;
+    user(repository.as_ref(), PROVIDER, Role::Member).await;
+
+    let service_request = requests
+        .create(request(
+            REQUESTOR,
+            servicerequest::create::Request {
+                payload: Some(servicerequest::create::Payload {
+                    request_data: Some(RequestData {
+                        title: "Fix the fence".to_string(),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                }),
+            },
+        ))
+        .await
+        .unwrap()
+        .into_inner()
+        .request
+        .unwrap();
+
+    let bid = bids
+        .create(request(
+            PROVIDER,
+            servicerequestbid::create::Request {
+                payload: Some(servicerequestbid::create::Payload {
+                    request_id: service_request.id.clone(),
+                    amount,
+                    ..Default::default()
+                }),
+            },
+        ))
+        .await
+        .unwrap()
+        .into_inner()
+        .bid
+        .unwrap();
+
+    (service_request, bid)
+}
+
+/// A request of `REQUESTOR` that `PROVIDER` completed for `amount`.
+pub async fn completed_request(repository: &Arc<dyn Repository>, amount: f64) -> TServiceRequest {
+    let requests = ServiceRequestService::new(repository.clone(), EventBus::new());
+    let (service_request, bid) = bid_on_request(repository, amount).await;
+
+    requests
+        .select_bid(request(
+            REQUESTOR,
+            select_bid::Request {
+                payload: Some(select_bid::Payload {
+                    request_id: service_request.id.clone(),
+                    bid_id: bid.id,
+                }),
+            },
+        ))
+        .await
+        .unwrap();
+
+    requests
+        .complete_service(request(
+            REQUESTOR,
+            complete_service::Request {
+                payload: Some(complete_service::Payload {
+                    request_id: service_request.id.clone(),
+                    ..Default::default()
+                }),
+            },
+        ))
+        .await
+        .unwrap();
+
+    service_request
+}
+
+/// Tally of the ratings `user_id` received in the role, if any were counted.
+pub async fn tally(
+    repository: &dyn Repository,
+    user_id: &str,
+    role: ReputationRole,
+) -> Option<ReputationTally> {
+    repository
+        .get_reputations(&Query::eq("user_id", user_id))
+        .await
+        .unwrap()
+        .into_iter()
+        .find(|t| t.role == role as i32)
+}
@@ -0,0 +1,256 @@
+// Service for identity verification (KYC), members need an approved verification before they
+// can exchange hours
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use chrono::Utc;
+use tonic::{Request, Response};
+
+use crate::blob::BlobStore;
+use crate::error::{self, Error};
+use crate::middleware::audit;
+use crate::policy::{self, Action};
+use crate::proto::timebank::verification::verification_server::Verification;
+use crate::proto::timebank::verification::{
+    get_document, get_status, list_submissions, submit, Document, DocumentKind, TDocument,
+    TVerification, VerificationStatus,
+};
+use crate::repository::{Filter, Query, Repository};
+use crate::services::{query, util::helper, Result};
+
+pub use crate::proto::timebank::verification::verification_server::VerificationServer;
+
+const MAX_DOCUMENT_SIZE: usize = 5 * 1024 * 1024;
+
+const CONTENT_TYPES: &[&str] = &["image/jpeg", "image/png", "application/pdf"];
+
+/// Fails with `Error::NotVerified` unless the user's verification has been approved.
+pub async fn ensure_verified(repository: &dyn Repository, user_id: &str) -> error::Result<()> {
+    let status = repository
+        .get_verification(user_id)
+        .await?
+        .map(|v| v.status());
+
+    match status {
+        Some(VerificationStatus::Approved) => Ok(()),
+        _ => Err(Error::NotVerified),
+    }
+}
+
+fn validate(documents: &[Document]) -> error::Result<()> {
+    let mut kinds = HashSet::new();
+
+    for document in documents {
+        let kind = document.kind();
+
+        if kind == DocumentKind::Unspecified {
+            return Err(Error::InvalidDocument("KIND IS MISSING".into()));
+        }
+
+        if !kinds.insert(kind) {
+            return Err(Error::InvalidDocument(format!(
+                "{kind:?} SUBMITTED MORE THAN ONCE"
+            )));
+        }
+
+        if !CONTENT_TYPES.contains(&document.content_type.as_str()) {
+            return Err(Error::InvalidDocument(format!(
+                "CONTENT TYPE {:?} IS NOT SUPPORTED",
+                document.content_type
+            )));
+        }
+
+        if document.content.is_empty() || document.content.len() > MAX_DOCUMENT_SIZE {
+            return Err(Error::InvalidDocument(format!(
+                "{kind:?} MUST BE BETWEEN 1 BYTE AND {MAX_DOCUMENT_SIZE} BYTES"
+            )));
+        }
+    }
+
+    // the identity document is the one thing a reviewer can't do without
+    if !kinds.contains(&DocumentKind::IdFront) {
+        return Err(Error::InvalidDocument("ID FRONT IS MISSING".into()));
+    }
+
+    Ok(())
+}
+
+pub struct VerificationService {
+    repository: Arc<dyn Repository>,
+    blobs: Arc<dyn BlobStore>,
+}
+
+impl VerificationService {
+    pub fn new(repository: Arc<dyn Repository>, blobs: Arc<dyn BlobStore>) -> Self {
+        Self { repository, blobs }
+    }
+}
+
+#[tonic::async_trait]
+impl Verification for VerificationService {
+    async fn submit(
+        &self,
+        request: Request<submit::Request>,
+    ) -> Result<Response<submit::Response>> {
+        let user_id = helper::authenticated_user(&request)?.id;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                validate(&payload.documents)?;
+
+                audit::target("verification", &user_id);
+
+                let previous = self.repository.get_verification(&user_id).await?;
+
+                if let Some(previous) = &previous {
+                    audit::before(previous);
+                }
+
+                if matches!(&previous, Some(v) if v.status() == VerificationStatus::Approved) {
+                    return Err(Error::AlreadyVerified.into());
+                }
+
+                let mut documents = Vec::with_capacity(payload.documents.len());
+
+                for document in payload.documents {
+                    // a new key per submission so a failed one can't clobber the previous files
+                    let key = format!("verification/{user_id}/{}", uuid::Uuid::new_v4());
+
+                    documents.push(TDocument {
+                        kind: document.kind,
+                        content_type: document.content_type,
+                        size: document.content.len() as u64,
+                        key: key.clone(),
+                    });
+
+                    self.blobs.put(&key, document.content).await?;
+                }
+
+                let verification = self
+                    .repository
+                    .save_verification(TVerification {
+                        user_id,
+                        status: VerificationStatus::Pending as i32,
+                        documents,
+                        submitted_at: Utc::now().to_rfc3339(),
+                        ..Default::default()
+                    })
+                    .await?;
+
+                audit::after(&verification);
+
+                // the replaced submission's files aren't referenced anymore
+                for document in previous.into_iter().flat_map(|v| v.documents) {
+                    if let Err(e) = self.blobs.delete(&document.key).await {
+                        log::error!("UNABLE TO DELETE DOCUMENT {}: {e}", document.key);
+                    }
+                }
+
+                Ok(Response::new(submit::Response {
+                    verification: Some(verification),
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    async fn get_status(
+        &self,
+        request: Request<get_status::Request>,
+    ) -> Result<Response<get_status::Response>> {
+        let user_id = helper::authenticated_user(&request)?.id;
+
+        let verification =
+            self.repository
+                .get_verification(&user_id)
+                .await?
+                .unwrap_or(TVerification {
+                    user_id,
+                    ..Default::default()
+                });
+
+        Ok(Response::new(get_status::Response {
+            verification: Some(verification),
+        }))
+    }
+
+    async fn list_submissions(
+        &self,
+        request: Request<list_submissions::Request>,
+    ) -> Result<Response<list_submissions::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let actor = helper::actor(self.repository.as_ref(), user).await?;
+        policy::authorize(&actor, Action::ListVerifications)?;
+
+        let payload = request.into_inner().payload.unwrap_or_default();
+
+        let status = match payload.status() {
+            VerificationStatus::Unspecified => VerificationStatus::Pending,
+            status => status,
+        };
+
+        // oldest submissions first, they have been waiting the longest
+        let page = query::page(
+            Query {
+                filters: vec![(
+                    "status".to_string(),
+                    Filter::Eq((status as i32).to_string()),
+                )],
+                order: vec![
+                    ("submitted_at".to_string(), false),
+                    ("user_id".to_string(), false),
+                ],
+                ..Default::default()
+            },
+            payload.page_size,
+            &payload.page_token,
+        )?;
+
+        let (verifications, next_page_token) =
+            page.split(self.repository.get_verifications(&page.query).await?);
+
+        Ok(Response::new(list_submissions::Response {
+            verifications,
+            next_page_token,
+        }))
+    }
+
+    async fn get_document(
+        &self,
+        request: Request<get_document::Request>,
+    ) -> Result<Response<get_document::Response>> {
+        let user = helper::authenticated_user(&request)?;
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(payload) => {
+                let actor = helper::actor(self.repository.as_ref(), user).await?;
+                policy::authorize(
+                    &actor,
+                    Action::ViewVerification {
+                        user_id: &payload.user_id,
+                    },
+                )?;
+
+                let document = self
+                    .repository
+                    .get_verification(&payload.user_id)
+                    .await?
+                    .into_iter()
+                    .flat_map(|v| v.documents)
+                    .find(|d| d.kind == payload.kind)
+                    .ok_or(Error::NotFound)?;
+
+                Ok(Response::new(get_document::Response {
+                    content: self.blobs.get(&document.key).await?,
+                    content_type: document.content_type,
+                }))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+}
@@ -0,0 +1,139 @@
+// Service streaming the events published on the `EventBus`
+
+use std::collections::HashSet;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use serde_json::Value;
+use tokio_stream::wrappers::BroadcastStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::error::Error;
+use crate::events::{Event, EventBus};
+use crate::proto::timebank::servicerequest::TServiceRequest;
+use crate::proto::timebank::watch::watch_server::Watch;
+use crate::proto::timebank::watch::{watch_bids, watch_requests};
+use crate::repository::Repository;
+use crate::services::{taxonomy, Result};
+
+pub use crate::proto::timebank::watch::watch_server::WatchServer;
+
+type EventStream<T> = Pin<Box<dyn Stream<Item = std::result::Result<T, Status>> + Send>>;
+
+// requests in none of the categories are filtered out, `None` matches everything
+fn matches_categories(request: &TServiceRequest, categories: &Option<HashSet<String>>) -> bool {
+    match categories {
+        Some(categories) => request
+            .category_ids
+            .iter()
+            .any(|id| categories.contains(id)),
+        None => true,
+    }
+}
+
+// empty filters match everything, otherwise the `request_data` field must be equal
+fn matches_field(request: &TServiceRequest, field: &str, expected: &str) -> bool {
+    if expected.is_empty() {
+        return true;
+    }
+
+    let request_data = serde_json::to_value(&request.request_data).unwrap_or_default();
+
+    matches!(&request_data[field], Value::String(value) if value == expected)
+}
+
+pub struct WatchService {
+    repository: Arc<dyn Repository>,
+    events: EventBus,
+}
+
+impl WatchService {
+    pub fn new(repository: Arc<dyn Repository>, events: EventBus) -> Self {
+        Self { repository, events }
+    }
+}
+
+#[tonic::async_trait]
+impl Watch for WatchService {
+    type WatchRequestsStream = EventStream<watch_requests::Response>;
+
+    async fn watch_requests(
+        &self,
+        request: Request<watch_requests::Request>,
+    ) -> Result<Response<Self::WatchRequestsStream>> {
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(watch_requests::Payload { category, area }) => {
+                // the categories under it are looked up once, those added while the stream is
+                // open aren't matched
+                let categories = if category.is_empty() {
+                    None
+                } else {
+                    let all = taxonomy::categories(self.repository.as_ref()).await?;
+                    Some(taxonomy::descendants(&all, &[category]))
+                };
+
+                // lagging behind only drops the missed events, the stream keeps going
+                let stream =
+                    BroadcastStream::new(self.events.subscribe()).filter_map(move |event| {
+                        match event {
+                            Ok(Event::Request(event)) => {
+                                let request = event.request.as_ref()?;
+
+                                if matches_categories(request, &categories)
+                                    && matches_field(request, "area", &area)
+                                {
+                                    Some(Ok(watch_requests::Response { event: Some(event) }))
+                                } else {
+                                    None
+                                }
+                            }
+
+                            _ => None,
+                        }
+                    });
+
+                Ok(Response::new(Box::pin(stream)))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+
+    type WatchBidsStream = EventStream<watch_bids::Response>;
+
+    async fn watch_bids(
+        &self,
+        request: Request<watch_bids::Request>,
+    ) -> Result<Response<Self::WatchBidsStream>> {
+        let payload = request.into_inner().payload;
+
+        match payload {
+            Some(watch_bids::Payload { request_id }) => {
+                self.repository
+                    .get_request(&request_id)
+                    .await?
+                    .ok_or(Error::NotFound)?;
+
+                let stream =
+                    BroadcastStream::new(self.events.subscribe()).filter_map(move |event| {
+                        match event {
+                            Ok(Event::Bid(event))
+                                if matches!(&event.bid, Some(bid) if bid.request_id == request_id) =>
+                            {
+                                Some(Ok(watch_bids::Response { event: Some(event) }))
+                            }
+
+                            _ => None,
+                        }
+                    });
+
+                Ok(Response::new(Box::pin(stream)))
+            }
+
+            _ => Err(Error::InvalidPayload.into()),
+        }
+    }
+}
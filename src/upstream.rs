@@ -0,0 +1,270 @@
+///
+/// Calls to Supabase, shared by the auth service and the database repository.
+///
+/// Every call gets the time left of the gRPC deadline (capped by `timeouts.request_ms`),
+/// idempotent reads are retried with exponential backoff on 5xx and connection errors,
+/// and a circuit breaker fails calls fast while Supabase keeps failing.
+///
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use reqwest::header::{HeaderMap, HeaderValue, AUTHORIZATION};
+use tokio::time::Instant;
+
+use crate::config::{CircuitBreakerConfig, Config, RetryConfig};
+use crate::error::{Error, Result};
+use crate::middleware::deadline;
+
+#[derive(Debug)]
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A call is probing whether Supabase recovered, the others keep failing fast.
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    /// Lets a call through, unless the circuit is open or another call is probing it.
+    pub fn attempt(&self) -> Option<Attempt<'_>> {
+        let mut state = self.state.lock().unwrap();
+
+        let probe = match *state {
+            State::Closed { .. } => false,
+            State::Open { until } if Instant::now() >= until => {
+                *state = State::HalfOpen;
+                true
+            }
+            State::Open { .. } | State::HalfOpen => return None,
+        };
+
+        Some(Attempt {
+            breaker: self,
+            probe,
+            outcome: None,
+        })
+    }
+
+    fn record_success(&self) {
+        *self.state.lock().unwrap() = State::Closed { failures: 0 };
+    }
+
+    fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            // the probe failed
+            State::HalfOpen => self.config.failure_threshold,
+            State::Open { .. } => return,
+        };
+
+        *state = if failures >= self.config.failure_threshold {
+            State::Open {
+                until: Instant::now() + self.config.open(),
+            }
+        } else {
+            State::Closed { failures }
+        };
+    }
+
+    // the probe ended without telling whether Supabase recovered, the next call probes again
+    fn release_probe(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if let State::HalfOpen = *state {
+            *state = State::Open {
+                until: Instant::now(),
+            };
+        }
+    }
+}
+
+/// A call let through by the `CircuitBreaker`, its outcome is recorded when it is dropped.
+/// Calls dropped without an outcome, eg because they were cancelled or failed before reaching
+/// Supabase, count neither as a success nor as a failure.
+pub struct Attempt<'a> {
+    breaker: &'a CircuitBreaker,
+    probe: bool,
+    outcome: Option<bool>,
+}
+
+impl Attempt<'_> {
+    pub fn succeeded(mut self) {
+        self.outcome = Some(true);
+    }
+
+    pub fn failed(mut self) {
+        self.outcome = Some(false);
+    }
+}
+
+impl Drop for Attempt<'_> {
+    fn drop(&mut self) {
+        match self.outcome {
+            Some(true) => self.breaker.record_success(),
+            Some(false) => self.breaker.record_failure(),
+            None if self.probe => self.breaker.release_probe(),
+            None => {}
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Upstream {
+    client: reqwest::Client,
+    retry: RetryConfig,
+    breaker: Arc<CircuitBreaker>,
+    request_timeout: Duration,
+}
+
+impl Upstream {
+    pub fn new(config: &Config) -> Self {
+        let api_key = &config.supabase.api_key;
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "apiKey",
+            HeaderValue::from_str(api_key).expect("INVALID SUPABASE API KEY!"),
+        );
+        headers.insert(
+            AUTHORIZATION,
+            HeaderValue::from_str(&format!("Bearer {api_key}")).expect("INVALID SUPABASE API KEY!"),
+        );
+
+        // the per call timeout is applied in `call`, as it depends on the gRPC deadline
+        let client = reqwest::Client::builder()
+            .default_headers(headers)
+            .connect_timeout(config.timeouts.connect())
+            .build()
+            .expect("UNABLE TO BUILD HTTP CLIENT");
+
+        Self {
+            client,
+            retry: config.retry.clone(),
+            breaker: Arc::new(CircuitBreaker::new(config.circuit_breaker.clone())),
+            request_timeout: config.timeouts.request(),
+        }
+    }
+
+    /// The pooled client, with the Supabase api key attached to every request.
+    pub fn client(&self) -> &reqwest::Client {
+        &self.client
+    }
+
+    /// Sends a read, retried when it fails with a 5xx or connection error.
+    pub async fn read<F, Fut>(&self, send: F) -> Result<reqwest::Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = reqwest::Result<reqwest::Response>>,
+    {
+        self.call(self.retry.max_retries, send).await
+    }
+
+    /// Sends a write, which is never retried as it may have been applied already.
+    pub async fn write<F, Fut>(&self, send: F) -> Result<reqwest::Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = reqwest::Result<reqwest::Response>>,
+    {
+        self.call(0, send).await
+    }
+
+    async fn call<F, Fut>(&self, max_retries: u32, send: F) -> Result<reqwest::Response>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = reqwest::Result<reqwest::Response>>,
+    {
+        let timeout = Instant::now() + self.request_timeout;
+        let deadline = deadline::current().map_or(timeout, |d| d.min(timeout));
+
+        let mut attempt = 0;
+
+        loop {
+            let call = self.breaker.attempt().ok_or_else(|| {
+                Error::Unavailable("SUPABASE IS FAILING, RETRY LATER".to_string())
+            })?;
+
+            let result = match tokio::time::timeout_at(deadline, send()).await {
+                Ok(Ok(res)) if !res.status().is_server_error() => {
+                    call.succeeded();
+                    return Ok(res);
+                }
+                // handed back as is once out of retries, for the caller to map the error body
+                Ok(Ok(res)) => Ok(res),
+                Ok(Err(e)) if e.is_connect() || e.is_timeout() => Err(e.into()),
+                // eg the request could not be built, Supabase itself is fine
+                Ok(Err(e)) => return Err(e.into()),
+                // the caller's deadline ran out before `timeouts.request_ms` did, which says
+                // nothing about Supabase
+                Err(_) if deadline < timeout => return Err(Error::DeadlineExceeded),
+                Err(_) => Err(Error::DeadlineExceeded),
+            };
+
+            call.failed();
+
+            let retry_at = Instant::now() + self.retry.backoff(attempt);
+
+            if attempt >= max_retries || retry_at >= deadline {
+                return result;
+            }
+
+            tokio::time::sleep_until(retry_at).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // opens on the first failure and lets a probe through right away
+    fn breaker() -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig {
+            failure_threshold: 1,
+            open_ms: 0,
+        })
+    }
+
+    #[test]
+    fn only_one_call_probes_the_circuit() {
+        let breaker = breaker();
+        breaker.attempt().unwrap().failed();
+
+        let probe = breaker.attempt().unwrap();
+        assert!(breaker.attempt().is_none());
+
+        probe.succeeded();
+        assert!(breaker.attempt().is_some());
+    }
+
+    #[test]
+    fn a_probe_dropped_without_an_outcome_lets_another_call_probe() {
+        let breaker = breaker();
+        breaker.attempt().unwrap().failed();
+
+        drop(breaker.attempt().unwrap());
+
+        let probe = breaker.attempt();
+        assert!(probe.is_some());
+        assert!(breaker.attempt().is_none());
+    }
+}
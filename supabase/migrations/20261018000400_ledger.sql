@@ -0,0 +1,214 @@
+-- Double-entry ledger of time credits. Balances are only ever derived from the
+-- entries, `service_request_complete_service` no longer moves credits between
+-- profiles and is dropped, the server records completion as a status and pays
+-- the provider through the ledger.
+
+create table ledger_transaction (
+    id uuid primary key default gen_random_uuid(),
+    -- text rather than a reference, the history outlives deleted requests
+    request_id text not null default '',
+    memo text not null default '',
+    created_at timestamptz not null default now()
+);
+
+create table ledger_entry (
+    id bigint generated always as identity primary key,
+    transaction_id uuid not null references ledger_transaction (id),
+    -- a user id, `escrow:<request id>` or `system:opening`
+    account text not null,
+    -- positive for a credit, negative for a debit
+    amount double precision not null
+);
+
+-- one row per account, locked by the transactions debiting it so concurrent debits can't
+-- together overdraw it
+create table ledger_account (
+    account text primary key
+);
+
+create index ledger_entry_account_idx on ledger_entry (account);
+create index ledger_entry_transaction_id_idx on ledger_entry (transaction_id);
+create index ledger_transaction_created_at_idx on ledger_transaction (created_at);
+
+-- transactions with their entries, as the server reads them
+create view ledger_transaction_with_entries as
+select
+    t.id,
+    t.request_id,
+    t.memo,
+    coalesce(
+        (
+            select json_agg(json_build_object('account', e.account, 'amount', e.amount) order by e.id)
+            from ledger_entry e
+            where e.transaction_id = t.id
+        ),
+        '[]'::json
+    ) as entries,
+    t.created_at
+from ledger_transaction t;
+
+-- the transaction and its entries are written in the one statement the function runs in, so
+-- either all of them are recorded or none. Accounts can't be overdrawn, except the `system:`
+-- ones credits are issued from.
+create function ledger_record_transaction(_request_id text, _memo text, _entries jsonb)
+returns setof ledger_transaction_with_entries
+language plpgsql
+as $$
+declare
+    _transaction_id uuid;
+begin
+    if jsonb_array_length(_entries) < 2
+        or abs((select sum((e ->> 'amount')::double precision) from jsonb_array_elements(_entries) e)) > 1e-9
+    then
+        raise exception 'TRANSACTION ENTRIES DO NOT BALANCE' using errcode = 'check_violation';
+    end if;
+
+    insert into ledger_account (account)
+    select distinct e ->> 'account' from jsonb_array_elements(_entries) e
+    on conflict do nothing;
+
+    -- in a fixed order, so two transactions debiting the same accounts can't deadlock
+    perform 1
+    from ledger_account a
+    where a.account in (
+        select e ->> 'account'
+        from jsonb_array_elements(_entries) e
+        where (e ->> 'amount')::double precision < 0
+    )
+    order by a.account
+    for update;
+
+    insert into ledger_transaction (request_id, memo)
+    values (coalesce(_request_id, ''), coalesce(_memo, ''))
+    returning id into _transaction_id;
+
+    insert into ledger_entry (transaction_id, account, amount)
+    select _transaction_id, e ->> 'account', (e ->> 'amount')::double precision
+    from jsonb_array_elements(_entries) e;
+
+    if exists (
+        select 1
+        from ledger_entry e
+        where e.transaction_id = _transaction_id
+            and e.amount < 0
+            and e.account not like 'system:%'
+            and ledger_get_balance(e.account) < -1e-9
+    ) then
+        raise exception 'INSUFFICIENT BALANCE';
+    end if;
+
+    return query
+    select * from ledger_transaction_with_entries t where t.id = _transaction_id;
+end;
+$$;
+
+create function ledger_get_balance(_account text, _until timestamptz default null)
+returns double precision
+language sql
+stable
+as $$
+    select coalesce(sum(e.amount), 0)
+    from ledger_entry e
+    join ledger_transaction t on t.id = e.transaction_id
+    where e.account = _account
+        and (_until is null or t.created_at < _until);
+$$;
+
+-- what is held in the escrow accounts the account has transactions with, for a requestor the
+-- escrow of their own requests
+create function ledger_get_escrowed(_account text)
+returns double precision
+language sql
+stable
+as $$
+    select coalesce(sum(e.amount), 0)
+    from ledger_entry e
+    where e.account in (
+        select escrow.account
+        from ledger_entry own
+        join ledger_entry escrow on escrow.transaction_id = own.transaction_id
+        where own.account = _account
+            and escrow.account like 'escrow:%'
+    );
+$$;
+
+-- oldest first, `_to` is exclusive
+create function ledger_list_transactions(
+    _account text,
+    _from timestamptz default null,
+    _to timestamptz default null,
+    _counterparty text default null,
+    _offset integer default 0,
+    _limit integer default null
+)
+returns setof ledger_transaction_with_entries
+language sql
+stable
+as $$
+    select t.*
+    from ledger_transaction_with_entries t
+    where exists (select 1 from ledger_entry e where e.transaction_id = t.id and e.account = _account)
+        and (_from is null or t.created_at >= _from)
+        and (_to is null or t.created_at < _to)
+        and (
+            _counterparty is null
+            or exists (select 1 from ledger_entry e where e.transaction_id = t.id and e.account = _counterparty)
+        )
+    order by t.created_at, t.id
+    offset _offset
+    limit _limit;
+$$;
+
+-- every member starts with the same number of hours, `ledger::OPENING_BALANCE` on the server
+create function ledger_grant_opening_balance(_user_id uuid, _amount double precision default 10)
+returns void
+language sql
+as $$
+    select ledger_record_transaction(
+        null,
+        'OPENING BALANCE',
+        jsonb_build_array(
+            jsonb_build_object('account', 'system:opening', 'amount', -_amount),
+            jsonb_build_object('account', _user_id::text, 'amount', _amount)
+        )
+    );
+$$;
+
+create function ledger_grant_opening_balance_on_signup()
+returns trigger
+language plpgsql
+as $$
+begin
+    perform ledger_grant_opening_balance(new.user_id);
+    return new;
+end;
+$$;
+
+create trigger user_profile_opening_balance
+after insert on user_profile
+for each row execute function ledger_grant_opening_balance_on_signup();
+
+-- members that signed up before the ledger existed open with the hours they held, what
+-- `service_request_complete_service` moved into and out of `user_profile.balance`
+do $$
+begin
+    if exists (
+        select 1
+        from information_schema.columns
+        where table_schema = 'public' and table_name = 'user_profile' and column_name = 'balance'
+    ) then
+        perform ledger_grant_opening_balance(user_id, coalesce(balance, 0)) from user_profile;
+        alter table user_profile drop column balance;
+    else
+        perform ledger_grant_opening_balance(user_id) from user_profile;
+    end if;
+end;
+$$;
+
+drop function service_request_complete_service;
+
+-- entries are never changed, corrections are new transactions
+revoke update, delete on ledger_transaction, ledger_entry from anon, authenticated;
+
+-- accounts are only added by `ledger_record_transaction`
+revoke insert, update, delete on ledger_account from anon, authenticated;
@@ -0,0 +1,156 @@
+-- Lifecycle of service requests. Every status change is kept as a transition,
+-- `service_request.status` is the latest one so requests can be filtered on it.
+
+-- `timebank.servicerequeststatus.RequestStatus`, requests from before are open
+alter table service_request add column status integer not null default 1;
+
+-- the bid picked by `service_request_select_bid`
+alter table service_request
+    add column selected_bid uuid references service_request_bid (id) on delete restrict;
+
+create table service_request_status (
+    id uuid primary key default gen_random_uuid(),
+    request_id uuid not null references service_request (id) on delete cascade,
+    from_status integer not null,
+    to_status integer not null,
+    -- the user that made the change
+    user_id uuid not null,
+    created_at timestamptz not null default now()
+);
+
+create index service_request_status_request_id_idx on service_request_status (request_id, created_at);
+
+-- only records the transition if the request's latest status is still `_from`, so of two
+-- concurrent changes one fails rather than both taking effect. Whether `_to` may follow `_from`
+-- is left to the server.
+create function service_request_record_transition(
+    _request_id uuid,
+    _from integer,
+    _to integer,
+    _user_id uuid
+)
+returns setof service_request_status
+language plpgsql
+as $$
+declare
+    _current integer;
+begin
+    perform 1 from service_request where id = _request_id for update;
+
+    if not found then
+        raise exception 'SERVICE REQUEST NOT FOUND' using errcode = 'no_data_found';
+    end if;
+
+    select to_status into _current
+    from service_request_status
+    where request_id = _request_id
+    order by created_at desc, id desc
+    limit 1;
+
+    -- requests without any history are either being created or predate status tracking
+    if (_current is null and _from not in (0, 1)) or _current <> _from then
+        raise exception 'SERVICE REQUEST STATUS HAS CHANGED';
+    end if;
+
+    update service_request set status = _to where id = _request_id;
+
+    return query
+    insert into service_request_status (request_id, from_status, to_status, user_id)
+    values (_request_id, _from, _to, _user_id)
+    returning *;
+end;
+$$;
+
+-- the previous definition had no notion of a selected bid nor of escrow. The bid is selected, the
+-- request assigned and the bid amount escrowed together, either all of them take effect or none.
+-- Recording the transition locks the request, so of two concurrent selections only one gets
+-- past it.
+drop function service_request_select_bid;
+
+create function service_request_select_bid(
+    _request_id uuid,
+    _bid_id uuid,
+    _from integer,
+    _to integer,
+    _user_id uuid,
+    _memo text,
+    _entries jsonb
+)
+returns setof service_request
+language plpgsql
+as $$
+begin
+    perform 1 from service_request_bid where id = _bid_id and request_id = _request_id;
+
+    if not found then
+        raise exception 'BID NOT FOUND' using errcode = 'no_data_found';
+    end if;
+
+    perform service_request_record_transition(_request_id, _from, _to, _user_id);
+
+    update service_request
+    set selected_bid = _bid_id
+    where id = _request_id and selected_bid is null;
+
+    if not found then
+        raise exception 'A BID HAS ALREADY BEEN SELECTED';
+    end if;
+
+    perform ledger_record_transaction(_request_id::text, _memo, _entries);
+
+    return query
+    select * from service_request where id = _request_id;
+end;
+$$;
+
+-- records the transition and moves whatever the request's escrow holds to `_account` together, so
+-- the status never changes without the credits following
+create function service_request_release_escrow(
+    _request_id uuid,
+    _from integer,
+    _to integer,
+    _user_id uuid,
+    _account text,
+    _memo text
+)
+returns setof service_request_status
+language plpgsql
+as $$
+declare
+    _transition service_request_status;
+    _escrow text := 'escrow:' || _request_id;
+    _held double precision;
+begin
+    select * into _transition
+    from service_request_record_transition(_request_id, _from, _to, _user_id);
+
+    _held := ledger_get_balance(_escrow);
+
+    if _held > 0 then
+        perform ledger_record_transaction(
+            _request_id::text,
+            _memo,
+            jsonb_build_array(
+                jsonb_build_object('account', _escrow, 'amount', -_held),
+                jsonb_build_object('account', _account, 'amount', _held)
+            )
+        );
+    end if;
+
+    return next _transition;
+end;
+$$;
+
+create function service_request_get_selected_bid(_request_id uuid)
+returns setof service_request_bid
+language sql
+stable
+as $$
+    select b.*
+    from service_request r
+    join service_request_bid b on b.id = r.selected_bid
+    where r.id = _request_id;
+$$;
+
+-- the history is only written through `service_request_record_transition`
+revoke insert, update, delete on service_request_status from anon, authenticated;
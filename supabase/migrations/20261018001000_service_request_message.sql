@@ -0,0 +1,17 @@
+-- Messages between the requestor of a service request and its selected
+-- provider. Who may write to a request is checked by the server.
+
+create table service_request_message (
+    id uuid primary key default gen_random_uuid(),
+    request_id uuid not null references service_request (id) on delete cascade,
+    sender uuid not null references user_profile (user_id),
+    body text not null check (length(body) > 0),
+    read boolean not null default false,
+    created_at timestamptz not null default now()
+);
+
+create index service_request_message_request_id_idx on service_request_message (request_id, created_at);
+
+-- messages are never edited, only marked as read
+revoke update, delete on service_request_message from anon, authenticated;
+grant update (read) on service_request_message to authenticated;
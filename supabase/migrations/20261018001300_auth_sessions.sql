@@ -0,0 +1,50 @@
+-- GoTrue keeps sessions in the `auth` schema, which PostgREST doesn't expose.
+-- These functions reach into it on behalf of the server, so only the service
+-- role may call them.
+
+create function auth_list_sessions(_user_id uuid)
+returns table (
+    id text,
+    created_at timestamptz,
+    updated_at timestamptz,
+    user_agent text,
+    ip text,
+    -- filled in by the server, which knows the session of the caller
+    current boolean
+)
+language sql
+stable
+security definer
+set search_path = ''
+as $$
+    select
+        s.id::text,
+        s.created_at,
+        coalesce(s.updated_at, s.created_at),
+        coalesce(s.user_agent, ''),
+        coalesce(host(s.ip), ''),
+        false
+    from auth.sessions s
+    where s.user_id = _user_id
+    -- most recently used first
+    order by coalesce(s.updated_at, s.created_at) desc;
+$$;
+
+-- the refresh tokens of the session go with it, so it can't be refreshed anymore
+create function auth_revoke_session(_user_id uuid, _session_id uuid)
+returns void
+language plpgsql
+security definer
+set search_path = ''
+as $$
+begin
+    delete from auth.sessions where id = _session_id and user_id = _user_id;
+
+    if not found then
+        raise exception 'SESSION NOT FOUND' using errcode = 'no_data_found';
+    end if;
+end;
+$$;
+
+revoke execute on function auth_list_sessions, auth_revoke_session from public, anon, authenticated;
+grant execute on function auth_list_sessions, auth_revoke_session to service_role;
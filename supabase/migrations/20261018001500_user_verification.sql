@@ -0,0 +1,24 @@
+-- Identity verification of members, one row per member that submitted their
+-- documents. The documents themselves are kept in the blob store, `documents`
+-- only describes them.
+--
+-- The server writes whole `TVerification`s, where the reviewer is an empty
+-- string until the documents are reviewed.
+
+create table user_verification (
+    user_id uuid primary key references user_profile (user_id) on delete cascade,
+    -- `timebank.verification.VerificationStatus`
+    status integer not null,
+    -- `timebank.verification.TDocument`s
+    documents jsonb not null default '[]',
+    submitted_at timestamptz not null default now(),
+    -- null until reviewed
+    reviewed_at timestamptz,
+    reviewer text not null default '',
+    reason text not null default ''
+);
+
+create index user_verification_status_idx on user_verification (status, submitted_at);
+
+-- documents are private, they're only read through the server
+revoke all on user_verification from anon, authenticated;
@@ -0,0 +1,8 @@
+-- The role of every member, `timebank.role.Role`. Everyone starts as a member,
+-- only admins change it, through the server.
+
+alter table user_profile add column role integer not null default 1;
+
+-- a column revoke doesn't take away a table wide grant, and members mustn't be
+-- able to promote themselves, so profiles are only updated through the server
+revoke update on user_profile from anon, authenticated;
@@ -0,0 +1,55 @@
+-- Suspensions and bans, at most one per member, a new sanction replaces the
+-- previous one and lifting it deletes the row.
+
+create table user_sanction (
+    user_id uuid primary key references user_profile (user_id) on delete cascade,
+    -- `timebank.admin.SanctionKind`
+    kind integer not null,
+    reason text not null,
+    -- suspensions only, bans don't end
+    until timestamptz,
+    issued_by uuid not null references user_profile (user_id),
+    created_at timestamptz not null default now()
+);
+
+-- sanctions are only issued by admins, through the server
+revoke all on user_sanction from anon, authenticated;
+
+-- `timebank.admin.TStats`, in one round trip
+create function admin_stats()
+returns json
+language sql
+stable
+as $$
+    select json_build_object(
+        'users', (select count(*) from user_profile),
+        'suspended_users', (
+            select count(*)
+            from user_sanction
+            where kind = 1 and until > now()
+        ),
+        'banned_users', (select count(*) from user_sanction where kind = 2),
+        'requests', (select count(*) from service_request),
+        'requests_by_status', (
+            select coalesce(json_agg(json_build_object('status', status, 'count', count)), '[]')
+            from (
+                select status, count(*) as count
+                from service_request
+                group by status
+                order by status
+            ) s
+        ),
+        'bids', (select count(*) from service_request_bid),
+        -- completed requests are paid their selected bid
+        'hours_exchanged', (
+            select coalesce(sum(b.amount), 0)
+            from service_request r
+            join service_request_bid b on b.id = r.selected_bid
+            where r.status = 5
+        ),
+        'ratings', (select count(*) from service_rating),
+        'average_rating', (select coalesce(avg(value), 0) from service_rating)
+    );
+$$;
+
+revoke execute on function admin_stats from anon, authenticated;
@@ -0,0 +1,26 @@
+-- Log of the calls that changed something, written by the server after each
+-- successful call. Entries are never changed nor removed.
+
+create table audit_event (
+    id uuid primary key default gen_random_uuid(),
+    -- empty for calls made without signing in
+    user_id text not null default '',
+    -- full rpc name, eg `timebank.admin.Admin/SuspendUser`
+    method text not null,
+    -- kind of the target, eg `service_request`
+    entity text not null default '',
+    target_id text not null default '',
+    -- json snapshots, empty when there was nothing
+    before text not null default '',
+    after text not null default '',
+    reason text not null default '',
+    created_at timestamptz not null default now()
+);
+
+create index audit_event_created_at_idx on audit_event (created_at);
+create index audit_event_target_idx on audit_event (entity, target_id, created_at);
+create index audit_event_user_id_idx on audit_event (user_id, created_at);
+
+-- members don't see the log, and not even the server can rewrite it
+revoke all on audit_event from anon, authenticated;
+revoke update, delete, truncate on audit_event from service_role;
@@ -0,0 +1,57 @@
+-- Bids can be updated and withdrawn, and the requestor can answer them with
+-- counter-offers. Withdrawn bids are kept, with their status.
+
+-- `timebank.servicerequestbid.BidStatus`, bids from before are active
+alter table service_request_bid add column status integer not null default 1;
+-- RFC 3339, empty until the bid is first changed
+alter table service_request_bid add column updated_at text not null default '';
+
+-- one active bid per user and request, a withdrawn one doesn't stop them bidding again
+create unique index service_request_bid_active_idx
+    on service_request_bid (request_id, user_id)
+    where status = 1;
+
+create table bid_counter_offer (
+    id uuid primary key default gen_random_uuid(),
+    bid_id uuid not null references service_request_bid (id) on delete cascade,
+    -- the requestor that made the offer
+    user_id uuid not null references user_profile (user_id),
+    amount double precision not null check (amount > 0),
+    message text not null default '',
+    -- `timebank.servicerequestbid.CounterOfferStatus`
+    status integer not null,
+    created_at timestamptz not null default now()
+);
+
+create index bid_counter_offer_bid_id_idx on bid_counter_offer (bid_id, created_at);
+
+-- at most one pending counter-offer per bid, the server supersedes the previous one first
+create unique index bid_counter_offer_pending_idx
+    on bid_counter_offer (bid_id)
+    where status = 1;
+
+-- negotiations are private to the requestor and the bidder, read through the server
+revoke all on bid_counter_offer from anon, authenticated;
+
+-- the previous definition didn't know about withdrawn bids
+drop function bid_create;
+
+create function bid_create(_user_id uuid, _request_id uuid, _amount double precision)
+returns setof service_request_bid
+language plpgsql
+as $$
+begin
+    perform 1
+    from service_request_bid
+    where request_id = _request_id and user_id = _user_id and status = 1;
+
+    if found then
+        raise exception 'USER HAS ALREADY BID ON THE REQUEST' using errcode = 'unique_violation';
+    end if;
+
+    return query
+    insert into service_request_bid (request_id, user_id, amount)
+    values (_request_id, _user_id, _amount)
+    returning *;
+end;
+$$;
@@ -0,0 +1,22 @@
+-- Where members are, and their track record as providers, for ranking bids
+-- and recommending providers.
+
+-- `timebank.location.TLocation`, null until the member sets it
+alter table user_profile add column location jsonb;
+
+-- one row per user that ever bid, see `ProviderStats`
+create view provider_stats as
+select
+    b.user_id,
+    count(*) as bids,
+    count(*) filter (where r.selected_bid = b.id) as selected,
+    -- completed, cancelled, disputed or expired
+    count(*) filter (where r.selected_bid = b.id and r.status in (5, 6, 7, 8)) as concluded,
+    count(*) filter (where r.selected_bid = b.id and r.status = 5) as completed,
+    avg(greatest(extract(epoch from b.created_at - r.created_at), 0))::double precision
+        as mean_response_seconds
+from service_request_bid b
+join service_request r on r.id = b.request_id
+group by b.user_id;
+
+revoke all on provider_stats from anon, authenticated;
@@ -0,0 +1,83 @@
+-- Running totals of the ratings each member received, one row per member and
+-- role, kept up to date as ratings are revealed, changed or hidden.
+
+create table user_reputation (
+    id uuid primary key default gen_random_uuid(),
+    user_id uuid not null references user_profile (user_id) on delete cascade,
+    -- `timebank.reputation.ReputationRole`
+    role integer not null,
+    count bigint not null default 0,
+    sum bigint not null default 0,
+    -- ratings of each value, the first is the lowest of the scale
+    histogram bigint[] not null default '{}',
+    score double precision not null default 0,
+    updated_at timestamptz not null default now(),
+    unique (user_id, role)
+);
+
+-- scores are public, but only ever written through `reputation_adjust`
+revoke insert, update, delete on user_reputation from anon, authenticated;
+
+-- counts `_added` in and `_removed` out of the tally, either may be null, and
+-- recomputes its score as (prior_weight * prior_mean + sum) / (prior_weight + count).
+-- The row is locked while doing so, concurrent adjustments are applied one after the other.
+create function reputation_adjust(
+    _user_id uuid,
+    _role integer,
+    _added integer,
+    _removed integer,
+    _scale_min integer,
+    _scale_max integer,
+    _prior_mean double precision,
+    _prior_weight double precision
+)
+returns setof user_reputation
+language plpgsql
+as $$
+declare
+    _tally user_reputation;
+    _buckets integer := _scale_max - _scale_min + 1;
+    -- buckets of the values, those outside the scale in the closest one
+    _added_bucket integer := least(greatest(_added, _scale_min), _scale_max) - _scale_min + 1;
+    _removed_bucket integer := least(greatest(_removed, _scale_min), _scale_max) - _scale_min + 1;
+begin
+    insert into user_reputation (user_id, role)
+    values (_user_id, _role)
+    on conflict (user_id, role) do nothing;
+
+    select * into _tally
+    from user_reputation
+    where user_id = _user_id and role = _role
+    for update;
+
+    -- pads or truncates the histogram to the scale
+    _tally.histogram := array(
+        select coalesce(_tally.histogram[i], 0)
+        from generate_series(1, _buckets) i
+    );
+
+    if _added is not null then
+        _tally.count := _tally.count + 1;
+        _tally.sum := _tally.sum + _added;
+        _tally.histogram[_added_bucket] := _tally.histogram[_added_bucket] + 1;
+    end if;
+
+    if _removed is not null then
+        _tally.count := greatest(_tally.count - 1, 0);
+        _tally.sum := _tally.sum - _removed;
+        _tally.histogram[_removed_bucket] := greatest(_tally.histogram[_removed_bucket] - 1, 0);
+    end if;
+
+    return query
+    update user_reputation
+    set count = _tally.count,
+        sum = _tally.sum,
+        histogram = _tally.histogram,
+        score = (_prior_weight * _prior_mean + _tally.sum) / (_prior_weight + _tally.count),
+        updated_at = now()
+    where id = _tally.id
+    returning *;
+end;
+$$;
+
+revoke execute on function reputation_adjust from anon, authenticated;
@@ -0,0 +1,84 @@
+-- The requestor and the provider of a completed request rate each other, once
+-- each. A rating stays hidden until the other side rates back, or until the
+-- server reveals it once the reveal window runs out.
+
+alter table service_rating
+    add column rated_user_id uuid references user_profile (user_id) on delete cascade,
+    -- `timebank.reputation.ReputationRole` of the rated user, ratings from before rate providers
+    add column role integer not null default 1,
+    add column revealed boolean not null default false,
+    -- RFC 3339, empty until revealed
+    add column revealed_at text not null default '';
+
+-- ratings from before were the requestor's rating of the provider, and public. A rating of a
+-- request without a selected bid can't be attributed and fails the `not null` below.
+update service_rating r
+set rated_user_id = b.user_id,
+    revealed = true,
+    revealed_at = to_json(r.created_at) #>> '{}'
+from service_request q
+join service_request_bid b on b.id = q.selected_bid
+where q.id = r.request_id;
+
+alter table service_rating
+    alter column rated_user_id set not null,
+    alter column role drop default;
+
+create unique index service_rating_request_author_idx on service_rating (request_id, user_id);
+create index service_rating_rated_user_idx on service_rating (rated_user_id, role);
+
+-- count the revealed ratings in the reputation of the providers, with the scale (1 to 5) and
+-- prior (3 over 5 ratings) the server used when this was written
+select reputation_adjust(rated_user_id, role, value, null, 1, 5, 3.0, 5.0)
+from service_rating
+where revealed
+order by created_at;
+
+-- the previous definition had the requestor rate the bidder
+drop function rating_create;
+
+create function rating_create(
+    _user_id uuid,
+    _rated_user_id uuid,
+    _role integer,
+    _value integer,
+    _comment text,
+    _request_id uuid
+)
+returns setof service_rating
+language plpgsql
+as $$
+declare
+    _request service_request;
+    _provider uuid;
+begin
+    select * into _request from service_request where id = _request_id for share;
+
+    if not found then
+        raise exception 'SERVICE REQUEST NOT FOUND' using errcode = 'no_data_found';
+    end if;
+
+    select user_id into _provider from service_request_bid where id = _request.selected_bid;
+
+    if _provider is null
+        or not ((_user_id = _request.requestor and _rated_user_id = _provider)
+            or (_user_id = _provider and _rated_user_id = _request.requestor)) then
+        raise exception 'ONLY THE REQUESTOR AND THE PROVIDER CAN RATE THE SERVICE'
+            using errcode = 'insufficient_privilege';
+    end if;
+
+    -- `timebank.servicerequeststatus.RequestStatus.REQUEST_STATUS_COMPLETED`
+    if _request.status <> 5 then
+        raise exception 'SERVICE NOT COMPLETED';
+    end if;
+
+    -- a second rating by the same author fails on `service_rating_request_author_idx`
+    return query
+    insert into service_rating (request_id, user_id, rated_user_id, role, value, comment)
+    values (_request_id, _user_id, _rated_user_id, _role, _value, _comment)
+    returning *;
+end;
+$$;
+
+-- ratings are revealed, and reputations counted, by the server
+revoke insert, update on service_rating from anon, authenticated;
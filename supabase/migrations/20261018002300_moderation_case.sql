@@ -0,0 +1,73 @@
+-- Reports and appeals of ratings, worked through by moderators. A rating a
+-- moderator hides is left out of everything, reputations included.
+
+alter table service_rating add column hidden boolean not null default false;
+
+create table moderation_case (
+    id uuid primary key default gen_random_uuid(),
+    rating_id uuid not null references service_rating (id) on delete cascade,
+    -- `timebank.moderation.CaseKind`
+    kind integer not null,
+    -- who opened the case
+    user_id uuid not null references user_profile (user_id) on delete cascade,
+    reason text not null,
+    -- `timebank.moderation.CaseStatus`
+    status integer not null,
+    -- empty until resolved, the resolution time is RFC 3339
+    resolved_by text not null default '',
+    resolution text not null default '',
+    resolved_at text not null default '',
+    created_at timestamptz not null default now()
+);
+
+create index moderation_case_queue_idx on moderation_case (status, created_at);
+create index moderation_case_rating_id_idx on moderation_case (rating_id, status);
+
+-- one pending case per rating, kind and user
+create unique index moderation_case_pending_idx
+    on moderation_case (rating_id, kind, user_id)
+    where status = 1;
+
+-- cases are opened and resolved through the server
+revoke all on moderation_case from anon, authenticated;
+
+-- hidden ratings don't count in the stats either
+create or replace function admin_stats()
+returns json
+language sql
+stable
+as $$
+    select json_build_object(
+        'users', (select count(*) from user_profile),
+        'suspended_users', (
+            select count(*)
+            from user_sanction
+            where kind = 1 and until > now()
+        ),
+        'banned_users', (select count(*) from user_sanction where kind = 2),
+        'requests', (select count(*) from service_request),
+        'requests_by_status', (
+            select coalesce(json_agg(json_build_object('status', status, 'count', count)), '[]')
+            from (
+                select status, count(*) as count
+                from service_request
+                group by status
+                order by status
+            ) s
+        ),
+        'bids', (select count(*) from service_request_bid),
+        -- completed requests are paid their selected bid
+        'hours_exchanged', (
+            select coalesce(sum(b.amount), 0)
+            from service_request r
+            join service_request_bid b on b.id = r.selected_bid
+            where r.status = 5
+        ),
+        -- only the ratings others can see
+        'ratings', (select count(*) from service_rating where revealed and not hidden),
+        'average_rating', (
+            select coalesce(avg(value), 0) from service_rating where revealed and not hidden
+        )
+    );
+$$;
+
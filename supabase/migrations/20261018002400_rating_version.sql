@@ -0,0 +1,19 @@
+-- Values ratings had before each edit, so admins can see what was changed.
+-- Versions are only ever added.
+
+create table rating_version (
+    id uuid primary key default gen_random_uuid(),
+    rating_id uuid not null references service_rating (id) on delete cascade,
+    value integer not null,
+    comment text not null default '',
+    -- who made the edit that replaced these values
+    edited_by uuid not null references user_profile (user_id),
+    -- when the edit was made
+    created_at timestamptz not null default now()
+);
+
+create index rating_version_rating_id_idx on rating_version (rating_id, created_at);
+
+-- members don't see the history of ratings, and not even the server rewrites it
+revoke all on rating_version from anon, authenticated;
+revoke update, delete, truncate on rating_version from service_role;
@@ -0,0 +1,68 @@
+-- Categories services are offered and requested in, the skills members offer
+-- and the categories requests are tagged with. Categories nest through
+-- `parent_id`, which is empty for top level ones.
+
+create table category (
+    id uuid primary key default gen_random_uuid(),
+    -- text rather than a reference so top level categories have an empty parent
+    parent_id text not null default '',
+    name text not null check (btrim(name) <> ''),
+    description text not null default '',
+    created_at timestamptz not null default now()
+);
+
+create index category_parent_id_idx on category (parent_id);
+
+create table user_skill (
+    id uuid primary key default gen_random_uuid(),
+    user_id uuid not null references user_profile (user_id) on delete cascade,
+    -- categories in use can't be deleted
+    category_id uuid not null references category (id) on delete restrict,
+    -- `timebank.taxonomy.Proficiency`
+    proficiency integer not null check (proficiency > 0),
+    created_at timestamptz not null default now(),
+    unique (user_id, category_id)
+);
+
+create index user_skill_category_id_idx on user_skill (category_id);
+
+alter table service_request add column category_ids text[] not null default '{}';
+
+create index service_request_category_ids_idx on service_request using gin (category_ids);
+
+-- the taxonomy is public, but only admins edit it and members set their skills, through the server
+revoke insert, update, delete on category, user_skill from anon, authenticated;
+
+-- replaces the skills of the user with `_skills`, `{ category_id, proficiency }` objects
+create function user_skill_set(_user_id uuid, _skills jsonb)
+returns setof user_skill
+language plpgsql
+as $$
+begin
+    delete from user_skill where user_id = _user_id;
+
+    return query
+    insert into user_skill (user_id, category_id, proficiency)
+    select _user_id, (s ->> 'category_id')::uuid, (s ->> 'proficiency')::integer
+    from jsonb_array_elements(_skills) s
+    returning *;
+end;
+$$;
+
+revoke execute on function user_skill_set from anon, authenticated;
+
+-- the previous definition didn't tag requests with categories
+drop function service_request_create;
+
+create function service_request_create(
+    _requestor uuid,
+    _request jsonb,
+    _category_ids text[] default '{}'
+)
+returns setof service_request
+language sql
+as $$
+    insert into service_request (requestor, request_data, category_ids)
+    values (_requestor, _request, coalesce(_category_ids, '{}'))
+    returning *;
+$$;